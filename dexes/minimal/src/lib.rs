@@ -44,6 +44,7 @@ fn input() -> Vec<u8> {
     read(sys::input)
 }
 
+/// Swaps any asset for any other asset 1:1.
 #[unsafe(no_mangle)]
 fn swap() {
     let input = input();
@@ -52,13 +53,10 @@ fn swap() {
         SwapRequestAmount::ExactIn(amount) => amount,
         SwapRequestAmount::ExactOut(amount) => amount,
     };
-    assert_eq!(
-        request.asset_in, request.asset_out,
-        "Asset in and asset out must be the same, since this dex is a no-op"
-    );
     let response = SwapResponse {
         amount_in: amount,
         amount_out: amount,
+        refunds: vec![],
     };
     let response = borsh::to_vec(&response).expect("Failed to serialize response");
     return_value(&response);
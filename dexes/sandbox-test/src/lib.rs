@@ -0,0 +1,675 @@
+//! A dex that exercises sandbox-specific host functions and
+//! engine features in integration tests.
+#![no_std]
+#![deny(clippy::arithmetic_side_effects)]
+
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+use intear_dex_types::{
+    AssetId, DexCallRequest, DexCallResponse, SwapRequest, SwapRequestAmount, SwapResponse,
+};
+use near_sdk::json_types::U128;
+
+#[global_allocator]
+static ALLOCATOR: talc::Talck<talc::locking::AssumeUnlockable, talc::ClaimOnOom> = {
+    static mut MEMORY: [u8; 0x1000] = [0; 0x1000]; // 4KB
+    let span = talc::Span::from_array(core::ptr::addr_of!(MEMORY).cast_mut());
+    talc::Talc::new(unsafe { talc::ClaimOnOom::new(span) }).lock()
+};
+
+mod sys {
+    unsafe extern "C" {
+        pub fn value_return(value_len: u64, value_ptr: u64);
+        pub fn input(register_id: u64);
+        pub fn register_len(register_id: u64) -> u64;
+        pub fn read_register(register_id: u64, ptr: u64);
+        pub fn write_register(register_id: u64, data_len: u64, data_ptr: u64);
+        pub fn random_seed(register_id: u64);
+        pub fn read_config(register_id: u64);
+        pub fn fuel_remaining() -> u64;
+        pub fn dex_id(register_id: u64);
+        pub fn current_account_id(register_id: u64);
+        pub fn account_balance(balance_ptr: u64);
+        pub fn signer_account_id(register_id: u64);
+        pub fn signer_account_pk(register_id: u64);
+        pub fn debug_kv(key_len: u64, key_ptr: u64, value_len: u64, value_ptr: u64);
+        pub fn on_pool_created();
+        pub fn soft_fail(len: u64, ptr: u64);
+        pub fn set_reserves(pool_id: u64, reserves_len: u64, reserves_ptr: u64);
+        pub fn get_reserves(pool_id: u64, register_id: u64) -> u64;
+        pub fn promise_return(promise_id: u64);
+        pub fn log_utf8(len: u64, ptr: u64);
+        pub fn log_utf16(len: u64, ptr: u64);
+        pub fn panic_utf8(len: u64, ptr: u64) -> !;
+        pub fn abort(msg_ptr: u32, filename_ptr: u32, line: u32, col: u32) -> !;
+        pub fn storage_write(
+            key_len: u64,
+            key_ptr: u64,
+            value_len: u64,
+            value_ptr: u64,
+            register_id: u64,
+        ) -> u64;
+        pub fn storage_read(key_len: u64, key_ptr: u64, register_id: u64) -> u64;
+        pub fn storage_remove(key_len: u64, key_ptr: u64, register_id: u64) -> u64;
+        pub fn storage_usage() -> u64;
+        pub fn ed25519_verify(
+            signature_len: u64,
+            signature_ptr: u64,
+            message_len: u64,
+            message_ptr: u64,
+            public_key_len: u64,
+            public_key_ptr: u64,
+        ) -> u64;
+    }
+}
+
+fn return_value(value: impl AsRef<[u8]>) {
+    let value = value.as_ref();
+    unsafe {
+        sys::value_return(value.len() as u64, value.as_ptr() as u64);
+    }
+}
+
+const ATOMIC_REGISTER_ID: u64 = u64::MAX;
+
+fn read_register(register_id: u64) -> Vec<u8> {
+    let len = unsafe { sys::register_len(register_id) };
+    let mut buf = vec![0; len as usize];
+    unsafe {
+        sys::read_register(register_id, buf.as_mut_ptr() as u64);
+    }
+    buf
+}
+
+fn read(load: unsafe extern "C" fn(u64)) -> Vec<u8> {
+    unsafe { load(ATOMIC_REGISTER_ID) };
+    read_register(ATOMIC_REGISTER_ID)
+}
+
+fn input() -> Vec<u8> {
+    read(sys::input)
+}
+
+/// Does some work until the remaining fuel drops below the
+/// reserve passed as borsh-serialized `u64`. Returns borsh
+/// `(iterations, fuel_remaining)`.
+#[unsafe(no_mangle)]
+fn work_until_fuel_low() {
+    let reserve: u64 = borsh::from_slice(&input()).expect("Invalid reserve");
+    let mut iterations: u64 = 0;
+    let mut accumulator: u64 = 0;
+    while unsafe { sys::fuel_remaining() } > reserve {
+        accumulator = accumulator.wrapping_mul(31).wrapping_add(iterations);
+        iterations = iterations.checked_add(1).expect("Too many iterations");
+    }
+    core::hint::black_box(accumulator);
+    let response = borsh::to_vec(&(iterations, unsafe { sys::fuel_remaining() }))
+        .expect("Failed to serialize response");
+    return_value(&response);
+}
+
+/// View that fills orders of an imaginary order book, one unit of
+/// the borsh `u64` amount per order, until the amount is filled or
+/// `fuel_remaining` reports that it's time to wrap up. Returns the
+/// filled amount as borsh `u64`.
+#[unsafe(no_mangle)]
+fn fill_orders() {
+    let amount: u64 = borsh::from_slice(&input()).expect("Invalid amount");
+    let mut filled: u64 = 0;
+    let mut price: u64 = 1;
+    while filled < amount && unsafe { sys::fuel_remaining() } > 0 {
+        price = price.wrapping_mul(31).wrapping_add(filled);
+        filled = filled.checked_add(1).expect("Too many orders");
+    }
+    core::hint::black_box(price);
+    return_value(borsh::to_vec(&filled).expect("Failed to serialize response"));
+}
+
+/// Swaps any asset for any other asset 1:1, and additionally
+/// releases the refunds passed in the message as borsh
+/// `Vec<(AssetId, U128)>`. If the message is `soft_fail`,
+/// increments the borsh `u32` under key `failed_attempts` and
+/// soft-fails the swap instead. If the message is
+/// `write_then_panic`, writes key `written` and panics. If the
+/// message is `write_then_mismatch`, writes key `written`, checks
+/// `storage_usage`, and returns an `amount_in` that doesn't match
+/// the request. If the message is `constant_product`, swaps
+/// `ExactIn` through the constant product pool with the reserves
+/// of pool 0 and updates them. If the message is `record_assets`, writes the borsh `(asset_in,
+/// asset_out)` of the request under key `last_assets` and to
+/// register 0, logs `Recorded assets`, and swaps without refunds.
+/// If the message is `fill_register` or `write_too_much`, writes
+/// 2048 bytes to register 0 or 600 bytes under key `written`
+/// before swapping without refunds. If the message is
+/// `create_pool`, reports a new pool before swapping without
+/// refunds. If the message is `event`, logs an `EVENT_JSON:` event
+/// before swapping without refunds, and `event_then_panic` panics
+/// after the event instead.
+#[unsafe(no_mangle)]
+fn swap() {
+    let request: SwapRequest = borsh::from_slice(&input()).expect("Invalid request");
+    if request.message.0 == b"write_then_panic" {
+        let (key, value) = (b"written", b"value");
+        let message = "Panicked after writing";
+        unsafe {
+            sys::storage_write(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                value.len() as u64,
+                value.as_ptr() as u64,
+                ATOMIC_REGISTER_ID,
+            );
+            sys::panic_utf8(message.len() as u64, message.as_ptr() as u64)
+        }
+    }
+    if request.message.0 == b"write_then_mismatch" {
+        let (key, value) = (b"written", b"value");
+        unsafe {
+            sys::storage_write(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                value.len() as u64,
+                value.as_ptr() as u64,
+                ATOMIC_REGISTER_ID,
+            );
+            core::hint::black_box(sys::storage_usage());
+        }
+        let amount = match request.amount {
+            SwapRequestAmount::ExactIn(amount) => amount,
+            SwapRequestAmount::ExactOut(amount) => amount,
+        };
+        let response = SwapResponse {
+            amount_in: U128(amount.0.checked_add(1).expect("Amount too large")),
+            amount_out: amount,
+            refunds: vec![],
+        };
+        let response = borsh::to_vec(&response).expect("Failed to serialize response");
+        return_value(&response);
+        return;
+    }
+    if request.message.0 == b"constant_product" {
+        let pool_id = 0;
+        let reserves: Vec<(AssetId, U128)> = match unsafe {
+            sys::get_reserves(pool_id, ATOMIC_REGISTER_ID)
+        } {
+            1 => borsh::from_slice(&read_register(ATOMIC_REGISTER_ID)).expect("Invalid reserves"),
+            _ => Vec::new(),
+        };
+        let reserve = |asset_id: &AssetId| {
+            reserves
+                .iter()
+                .find(|(reserve_asset_id, _)| reserve_asset_id == asset_id)
+                .map(|(_, reserve)| reserve.0)
+                .expect("Asset is not in the pool")
+        };
+        let (reserve_in, reserve_out) = (reserve(&request.asset_in), reserve(&request.asset_out));
+        let SwapRequestAmount::ExactIn(amount_in) = request.amount else {
+            let message = "Only ExactIn is supported";
+            unsafe { sys::panic_utf8(message.len() as u64, message.as_ptr() as u64) }
+        };
+        let new_reserve_in = reserve_in
+            .checked_add(amount_in.0)
+            .expect("Reserve overflow");
+        let amount_out = reserve_out
+            .checked_mul(amount_in.0)
+            .and_then(|product| product.checked_div(new_reserve_in))
+            .expect("Amount out overflow");
+        let new_reserves = borsh::to_vec(&vec![
+            (request.asset_in.clone(), U128(new_reserve_in)),
+            (
+                request.asset_out.clone(),
+                U128(
+                    reserve_out
+                        .checked_sub(amount_out)
+                        .expect("Reserve underflow"),
+                ),
+            ),
+        ])
+        .expect("Failed to serialize reserves");
+        unsafe {
+            sys::set_reserves(
+                pool_id,
+                new_reserves.len() as u64,
+                new_reserves.as_ptr() as u64,
+            )
+        };
+        let response = SwapResponse {
+            amount_in,
+            amount_out: U128(amount_out),
+            refunds: vec![],
+        };
+        let response = borsh::to_vec(&response).expect("Failed to serialize response");
+        return_value(&response);
+        return;
+    }
+    if request.message.0 == b"soft_fail" {
+        let key = b"failed_attempts";
+        let attempts: u32 = match unsafe {
+            sys::storage_read(key.len() as u64, key.as_ptr() as u64, ATOMIC_REGISTER_ID)
+        } {
+            1 => borsh::from_slice(&read_register(ATOMIC_REGISTER_ID)).expect("Invalid attempts"),
+            _ => 0,
+        };
+        let attempts = borsh::to_vec(&attempts.checked_add(1).expect("Too many attempts"))
+            .expect("Failed to serialize attempts");
+        let message = "No liquidity";
+        unsafe {
+            sys::storage_write(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                attempts.len() as u64,
+                attempts.as_ptr() as u64,
+                ATOMIC_REGISTER_ID,
+            );
+            sys::soft_fail(message.len() as u64, message.as_ptr() as u64);
+        }
+        return;
+    }
+    if request.message.0 == b"fill_register" {
+        unsafe { sys::write_register(0, 2048, 0) };
+    }
+    if request.message.0 == b"write_too_much" {
+        let (key, value) = (b"written", [0u8; 600]);
+        unsafe {
+            sys::storage_write(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                value.len() as u64,
+                value.as_ptr() as u64,
+                ATOMIC_REGISTER_ID,
+            )
+        };
+    }
+    if request.message.0 == b"event" || request.message.0 == b"event_then_panic" {
+        let event = br#"EVENT_JSON:{"pool":0}"#;
+        unsafe { sys::log_utf8(event.len() as u64, event.as_ptr() as u64) };
+        if request.message.0 == b"event_then_panic" {
+            let message = "Panicked after the event";
+            unsafe { sys::panic_utf8(message.len() as u64, message.as_ptr() as u64) }
+        }
+    }
+    if request.message.0 == b"create_pool" {
+        unsafe { sys::on_pool_created() };
+    }
+    if request.message.0 == b"record_assets" {
+        let key = b"last_assets";
+        let assets = borsh::to_vec(&(&request.asset_in, &request.asset_out))
+            .expect("Failed to serialize assets");
+        unsafe {
+            sys::storage_write(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                assets.len() as u64,
+                assets.as_ptr() as u64,
+                ATOMIC_REGISTER_ID,
+            );
+            sys::write_register(0, assets.len() as u64, assets.as_ptr() as u64);
+        }
+        let message = "Recorded assets";
+        unsafe { sys::log_utf8(message.len() as u64, message.as_ptr() as u64) };
+    }
+    let refunds: Vec<(AssetId, U128)> = match &request.message.0[..] {
+        b"" | b"record_assets" | b"fill_register" | b"write_too_much" | b"create_pool"
+        | b"event" => {
+            vec![]
+        }
+        message => borsh::from_slice(message).expect("Invalid refunds"),
+    };
+    let amount = match request.amount {
+        SwapRequestAmount::ExactIn(amount) => amount,
+        SwapRequestAmount::ExactOut(amount) => amount,
+    };
+    let response = SwapResponse {
+        amount_in: amount,
+        amount_out: amount,
+        refunds,
+    };
+    let response = borsh::to_vec(&response).expect("Failed to serialize response");
+    return_value(&response);
+}
+
+/// Returns the JSON id of the dex as reported by the engine.
+#[unsafe(no_mangle)]
+fn own_dex_id() {
+    return_value(read(sys::dex_id));
+}
+
+/// Returns the account id the dex runs under as reported by the
+/// engine.
+#[unsafe(no_mangle)]
+fn own_account_id() {
+    return_value(read(sys::current_account_id));
+}
+
+/// Returns the NEAR balance of the dex as reported by the engine,
+/// as borsh `u128`.
+#[unsafe(no_mangle)]
+fn own_near_balance() {
+    let mut balance = [0; 16];
+    unsafe { sys::account_balance(balance.as_mut_ptr() as u64) };
+    let balance = u128::from_le_bytes(balance);
+    return_value(borsh::to_vec(&balance).expect("Failed to serialize response"));
+}
+
+/// Returns the random seed as reported by the engine.
+#[unsafe(no_mangle)]
+fn own_random_seed() {
+    return_value(read(sys::random_seed));
+}
+
+/// Returns the configuration of the dex as reported by the engine.
+#[unsafe(no_mangle)]
+fn own_config() {
+    return_value(read(sys::read_config));
+}
+
+/// Tries to return the result of a promise instead of a value.
+#[unsafe(no_mangle)]
+fn return_via_promise() {
+    unsafe { sys::promise_return(0) };
+}
+
+/// Tries to return a value in two parts.
+#[unsafe(no_mangle)]
+fn return_twice() {
+    return_value(b"first");
+    return_value(b"second");
+}
+
+#[unsafe(no_mangle)]
+fn log_hello() {
+    let message = "hello";
+    unsafe { sys::log_utf8(message.len() as u64, message.as_ptr() as u64) };
+}
+
+#[unsafe(no_mangle)]
+fn log_invalid_utf8() {
+    let message = b"invalid \xff utf-8";
+    unsafe { sys::log_utf8(message.len() as u64, message.as_ptr() as u64) };
+}
+
+/// Logs `sized ✓` with an explicit length and `terminated ✓` as a
+/// null-terminated string, `lone ` followed by an unpaired
+/// surrogate, and an `EVENT_JSON:` event, all as UTF-16.
+#[unsafe(no_mangle)]
+fn log_utf16_messages() {
+    let sized: Vec<u16> = "sized ✓".encode_utf16().collect();
+    let terminated: Vec<u16> = "terminated ✓\0".encode_utf16().collect();
+    let lone: Vec<u16> = "lone ".encode_utf16().chain([0xD800]).collect();
+    let event: Vec<u16> = r#"EVENT_JSON:{"utf16":true}"#.encode_utf16().collect();
+    for message in [&sized, &lone, &event] {
+        unsafe {
+            sys::log_utf16(
+                (message.len() as u64)
+                    .checked_mul(2)
+                    .expect("Message is too long"),
+                message.as_ptr() as u64,
+            )
+        };
+    }
+    unsafe { sys::log_utf16(u64::MAX, terminated.as_ptr() as u64) };
+}
+
+#[unsafe(no_mangle)]
+fn log_debug_pairs() {
+    for (key, value) in [("pool", "0"), ("reserve", "100")] {
+        unsafe {
+            sys::debug_kv(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                value.len() as u64,
+                value.as_ptr() as u64,
+            )
+        };
+    }
+}
+
+/// Dex call that returns the borsh `(Vec<u8>, Vec<u8>)` of the
+/// signer account id and public key as reported by the engine.
+#[unsafe(no_mangle)]
+fn own_signer() {
+    let response = DexCallResponse {
+        response: borsh::to_vec(&(read(sys::signer_account_id), read(sys::signer_account_pk)))
+            .expect("Failed to serialize response"),
+        ..Default::default()
+    };
+    return_value(borsh::to_vec(&response).expect("Failed to serialize response"));
+}
+
+/// Dex call that writes borsh `(key, value)` from the args
+/// to the dex storage.
+#[unsafe(no_mangle)]
+fn write_kv() {
+    let request: DexCallRequest = borsh::from_slice(&input()).expect("Invalid request");
+    let (key, value): (Vec<u8>, Vec<u8>) = borsh::from_slice(&request.args).expect("Invalid args");
+    unsafe {
+        sys::storage_write(
+            key.len() as u64,
+            key.as_ptr() as u64,
+            value.len() as u64,
+            value.as_ptr() as u64,
+            ATOMIC_REGISTER_ID,
+        )
+    };
+}
+
+/// Dex call that writes borsh `(key, first, second)` from the args
+/// to the same key twice. Returns borsh `(Option<Vec<u8>>,
+/// Option<Vec<u8>>)` with the old values reported by each write.
+#[unsafe(no_mangle)]
+fn write_kv_twice() {
+    let request: DexCallRequest = borsh::from_slice(&input()).expect("Invalid request");
+    let (key, first, second): (Vec<u8>, Vec<u8>, Vec<u8>) =
+        borsh::from_slice(&request.args).expect("Invalid args");
+    let old_values = [first, second].map(|value| {
+        match unsafe {
+            sys::storage_write(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                value.len() as u64,
+                value.as_ptr() as u64,
+                ATOMIC_REGISTER_ID,
+            )
+        } {
+            1 => Some(read_register(ATOMIC_REGISTER_ID)),
+            _ => None,
+        }
+    });
+    let [first_old_value, second_old_value] = old_values;
+    let response = DexCallResponse {
+        response: borsh::to_vec(&(first_old_value, second_old_value))
+            .expect("Failed to serialize response"),
+        ..Default::default()
+    };
+    return_value(borsh::to_vec(&response).expect("Failed to serialize response"));
+}
+
+/// View that reads the value of the key passed as raw args.
+/// Returns borsh `Option<Vec<u8>>`.
+#[unsafe(no_mangle)]
+fn read_kv() {
+    let key = input();
+    let value = match unsafe {
+        sys::storage_read(key.len() as u64, key.as_ptr() as u64, ATOMIC_REGISTER_ID)
+    } {
+        1 => Some(read_register(ATOMIC_REGISTER_ID)),
+        _ => None,
+    };
+    let response = borsh::to_vec(&value).expect("Failed to serialize response");
+    return_value(&response);
+}
+
+/// Migration that moves the value of key `old` to key `new`.
+/// Panics if there's nothing to migrate.
+#[unsafe(no_mangle)]
+fn migrate() {
+    let (old_key, new_key) = (b"old", b"new");
+    let value = match unsafe {
+        sys::storage_remove(
+            old_key.len() as u64,
+            old_key.as_ptr() as u64,
+            ATOMIC_REGISTER_ID,
+        )
+    } {
+        1 => read_register(ATOMIC_REGISTER_ID),
+        _ => {
+            let message = "Nothing to migrate";
+            unsafe { sys::panic_utf8(message.len() as u64, message.as_ptr() as u64) }
+        }
+    };
+    unsafe {
+        sys::storage_write(
+            new_key.len() as u64,
+            new_key.as_ptr() as u64,
+            value.len() as u64,
+            value.as_ptr() as u64,
+            ATOMIC_REGISTER_ID,
+        )
+    };
+}
+
+/// Dex call that creates an empty pool and reports it to the
+/// engine. Pools are stored under the borsh `u32` index of the
+/// pool.
+#[unsafe(no_mangle)]
+fn create_pool() {
+    unsafe { sys::on_pool_created() };
+    let count_key = b"pool_count";
+    let count: u32 = match unsafe {
+        sys::storage_read(
+            count_key.len() as u64,
+            count_key.as_ptr() as u64,
+            ATOMIC_REGISTER_ID,
+        )
+    } {
+        1 => borsh::from_slice(&read_register(ATOMIC_REGISTER_ID)).expect("Invalid pool count"),
+        _ => 0,
+    };
+    let pool_key = borsh::to_vec(&count).expect("Failed to serialize pool key");
+    let new_count = borsh::to_vec(&count.checked_add(1).expect("Too many pools"))
+        .expect("Failed to serialize pool count");
+    for (key, value) in [(&pool_key[..], &[][..]), (&count_key[..], &new_count[..])] {
+        unsafe {
+            sys::storage_write(
+                key.len() as u64,
+                key.as_ptr() as u64,
+                value.len() as u64,
+                value.as_ptr() as u64,
+                ATOMIC_REGISTER_ID,
+            )
+        };
+    }
+}
+
+/// Dex call that sets the reserves of a pool from borsh
+/// `(u64, Vec<(AssetId, U128)>)` args.
+#[unsafe(no_mangle)]
+fn set_pool_reserves() {
+    let request: DexCallRequest = borsh::from_slice(&input()).expect("Invalid request");
+    let (pool_id, reserves): (u64, Vec<(AssetId, U128)>) =
+        borsh::from_slice(&request.args).expect("Invalid args");
+    let reserves = borsh::to_vec(&reserves).expect("Failed to serialize reserves");
+    unsafe { sys::set_reserves(pool_id, reserves.len() as u64, reserves.as_ptr() as u64) };
+}
+
+/// View that reads the reserves of the pool passed as borsh `u64`.
+/// Returns borsh `Option<Vec<(AssetId, U128)>>`.
+#[unsafe(no_mangle)]
+fn pool_reserves() {
+    let pool_id: u64 = borsh::from_slice(&input()).expect("Invalid pool id");
+    let reserves: Option<Vec<(AssetId, U128)>> = match unsafe {
+        sys::get_reserves(pool_id, ATOMIC_REGISTER_ID)
+    } {
+        1 => Some(borsh::from_slice(&read_register(ATOMIC_REGISTER_ID)).expect("Invalid reserves")),
+        _ => None,
+    };
+    return_value(borsh::to_vec(&reserves).expect("Failed to serialize response"));
+}
+
+/// View that grows the memory by the number of pages passed as
+/// borsh `u32`.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+fn grow_memory() {
+    let pages: u32 = borsh::from_slice(&input()).expect("Invalid pages");
+    core::arch::wasm32::memory_grow(0, pages as usize);
+}
+
+/// View that recurses to the depth passed as borsh `u32`. Returns
+/// the depth reached as borsh `u32`.
+#[unsafe(no_mangle)]
+fn recurse() {
+    fn depth(remaining: u32) -> u32 {
+        match remaining.checked_sub(1) {
+            // Not a tail call, so that it isn't turned into a loop
+            Some(remaining) => core::hint::black_box(depth(core::hint::black_box(remaining)))
+                .checked_add(1)
+                .expect("Too deep"),
+            None => 0,
+        }
+    }
+    let remaining: u32 = borsh::from_slice(&input()).expect("Invalid depth");
+    return_value(borsh::to_vec(&depth(remaining)).expect("Failed to serialize response"));
+}
+
+/// View that fills a register with the number of bytes passed as
+/// borsh `u64`, taken from the start of the memory.
+#[unsafe(no_mangle)]
+fn fill_register() {
+    let len: u64 = borsh::from_slice(&input()).expect("Invalid length");
+    unsafe { sys::write_register(0, len, 0) };
+}
+
+/// View that verifies an ed25519 signature from borsh
+/// `(Vec<u8>, Vec<u8>, Vec<u8>)` args of the signature, message and
+/// public key. Returns the result of the host function as borsh
+/// `u64`.
+#[unsafe(no_mangle)]
+fn verify_ed25519() {
+    let (signature, message, public_key): (Vec<u8>, Vec<u8>, Vec<u8>) =
+        borsh::from_slice(&input()).expect("Invalid args");
+    let result = unsafe {
+        sys::ed25519_verify(
+            signature.len() as u64,
+            signature.as_ptr() as u64,
+            message.len() as u64,
+            message.as_ptr() as u64,
+            public_key.len() as u64,
+            public_key.as_ptr() as u64,
+        )
+    };
+    return_value(borsh::to_vec(&result).expect("Failed to serialize response"));
+}
+
+/// Encodes the string the way AssemblyScript lays it out in
+/// memory: the byte length as `u32`, followed by UTF-16. Strings
+/// are passed by the pointer to the UTF-16 part.
+fn assemblyscript_string(s: &str) -> Vec<u8> {
+    let utf16: Vec<u8> = s.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let mut buf = (utf16.len() as u32).to_le_bytes().to_vec();
+    buf.extend(utf16);
+    buf
+}
+
+/// View that aborts like a failed AssemblyScript assertion, with
+/// message `Assertion failed` in `assembly/index.ts` at 3:5.
+#[unsafe(no_mangle)]
+fn abort_like_assemblyscript() {
+    let message = assemblyscript_string("Assertion failed");
+    let filename = assemblyscript_string("assembly/index.ts");
+    unsafe {
+        sys::abort(
+            message.as_ptr().wrapping_add(4) as u32,
+            filename.as_ptr().wrapping_add(4) as u32,
+            3,
+            5,
+        )
+    }
+}
+
+/// View that aborts like `abort()` without arguments in
+/// AssemblyScript, with a null message and filename.
+#[unsafe(no_mangle)]
+fn abort_with_null_message() {
+    unsafe { sys::abort(0, 0, 0, 0) }
+}
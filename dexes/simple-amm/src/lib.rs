@@ -21,6 +21,9 @@ static ALLOCATOR: talc::Talck<talc::locking::AssumeUnlockable, talc::ClaimOnOom>
 
 type PoolId = u64;
 
+/// Fixed-point scale of prices returned by `get_pool_price`.
+const PRICE_SCALE: u128 = 10u128.pow(24);
+
 /// The simplest possible x*y=k pool, with just one
 /// liquidity provider. Demonstrates the basic functionality
 /// of swaps, adding / withdrawing liquidity, storage
@@ -90,6 +93,7 @@ impl Dex for SimpleAmmDex {
                 SwapResponse {
                     amount_in: exact_amount_in,
                     amount_out: U128(amount_out),
+                    refunds: vec![],
                 }
             }
             SwapRequestAmount::ExactOut(exact_amount_out) => {
@@ -120,6 +124,7 @@ impl Dex for SimpleAmmDex {
                 SwapResponse {
                     amount_in: U128(amount_in),
                     amount_out: U128(exact_amount_out.0),
+                    refunds: vec![],
                 }
             }
         }
@@ -361,6 +366,42 @@ impl SimpleAmmDex {
     pub fn get_pool(&self, #[serializer(borsh)] pool_id: PoolId) -> Option<&SimplePool> {
         self.pools.get(&pool_id)
     }
+
+    /// Spot price of `base` denominated in `quote`, multiplied by
+    /// `PRICE_SCALE`. Returns `None` if the pool doesn't exist,
+    /// doesn't consist of these two assets, or is empty.
+    #[result_serializer(borsh)]
+    pub fn get_pool_price(
+        &self,
+        #[serializer(borsh)] pool_id: PoolId,
+        #[serializer(borsh)] base: AssetId,
+        #[serializer(borsh)] quote: AssetId,
+    ) -> Option<U128> {
+        let pool = self.pools.get(&pool_id)?;
+        let (base_balance, quote_balance) =
+            if pool.assets.0.asset_id == base && pool.assets.1.asset_id == quote {
+                (pool.assets.0.balance.0, pool.assets.1.balance.0)
+            } else if pool.assets.1.asset_id == base && pool.assets.0.asset_id == quote {
+                (pool.assets.1.balance.0, pool.assets.0.balance.0)
+            } else {
+                return None;
+            };
+        if base_balance == 0 || quote_balance == 0 {
+            return None;
+        }
+        // base_balance was checked to be positive
+        #[allow(clippy::arithmetic_side_effects)]
+        let price = (U256::from(quote_balance) * U256::from(PRICE_SCALE)
+            / U256::from(base_balance))
+        .to_le_bytes();
+        let (low, high) = price.split_at(16);
+        if high.iter().any(|byte| *byte != 0) {
+            return None;
+        }
+        Some(U128(u128::from_le_bytes(
+            low.try_into().expect("Split at 16 bytes"),
+        )))
+    }
 }
 
 #[near(serializers=[borsh])]
@@ -29,6 +29,27 @@ pub struct SwapRequest {
     pub amount: SwapRequestAmount,
 }
 
+impl SwapRequest {
+    /// Checks the invariants that hold for any dex: the amount
+    /// is not zero, and the assets are different. Dex-specific
+    /// checks, such as validity of pool id, are up to the dex.
+    pub fn validate(&self) -> Result<(), String> {
+        let amount = match self.amount {
+            SwapRequestAmount::ExactIn(amount) | SwapRequestAmount::ExactOut(amount) => amount,
+        };
+        if amount.0 == 0 {
+            return Err("Amount must be greater than 0".to_string());
+        }
+        if self.asset_in == self.asset_out {
+            return Err(format!(
+                "Asset in and asset out must be different, got {} for both",
+                self.asset_in
+            ));
+        }
+        Ok(())
+    }
+}
+
 /// The swap operation was successful, release `amount_out`
 /// to the user and take `amount_in` from the user.
 ///
@@ -36,10 +57,34 @@ pub struct SwapRequest {
 /// assets to the user, the dex must panic.
 #[derive(Clone)]
 #[cfg_attr(debug_assertions, derive(Debug))]
-#[near(serializers=[borsh])]
+#[cfg_attr(not(feature = "json"), near(serializers=[borsh]))]
+#[cfg_attr(feature = "json", near(serializers=[borsh, json]))]
 pub struct SwapResponse {
     pub amount_in: U128,
     pub amount_out: U128,
+    /// Additional assets to release to the user from the dex
+    /// balance, for dexes that don't consume all of the input
+    /// or pay out in more than one asset. Usually empty.
+    pub refunds: Vec<(AssetId, U128)>,
+}
+
+impl SwapResponse {
+    /// Deserializes the borsh response returned by the `swap` export
+    /// of a dex. Dexes built before `refunds` was added return only
+    /// `amount_in` and `amount_out`, which is read as a response
+    /// without refunds.
+    pub fn from_dex_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        const LEGACY_RESPONSE_LEN: usize = 32;
+        if bytes.len() == LEGACY_RESPONSE_LEN {
+            let (amount_in, amount_out) = near_sdk::borsh::from_slice(bytes)?;
+            return Ok(Self {
+                amount_in,
+                amount_out,
+                refunds: vec![],
+            });
+        }
+        near_sdk::borsh::from_slice(bytes)
+    }
 }
 
 #[derive(Clone)]
@@ -77,6 +122,16 @@ pub enum AssetWithdrawalType {
     WithdrawUnderlyingAsset(AccountId),
 }
 
+/// The canonical byte encoding of an asset id is borsh: one
+/// byte for the variant (in declaration order), followed by the
+/// length-prefixed fields. It's used as-is in composite storage
+/// keys, such as `(AccountId, AssetId)` balance keys.
+///
+/// `Ord` sorts by variant first and then by fields as strings,
+/// which doesn't match the byte order of the encoding, since
+/// strings are prefixed with their length. Balances are stored
+/// in lookup maps, so nothing depends on storage key order, and
+/// clients should sort by `Ord` rather than by the encoding.
 #[derive(PartialEq, Eq, Hash, Clone, PartialOrd, Ord, Debug)]
 #[near(serializers=[borsh])]
 pub enum AssetId {
@@ -86,6 +141,18 @@ pub enum AssetId {
     Nep171(AccountId, String),
 }
 
+impl AssetId {
+    /// The contract of the token, or `None` for near.
+    pub fn token_contract(&self) -> Option<&AccountId> {
+        match self {
+            Self::Near => None,
+            Self::Nep141(contract_id)
+            | Self::Nep245(contract_id, _)
+            | Self::Nep171(contract_id, _) => Some(contract_id),
+        }
+    }
+}
+
 impl Display for AssetId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -110,6 +177,9 @@ impl FromStr for AssetId {
                 }
                 Some(("nep245", rest)) => {
                     if let Some((contract_id, token_id)) = rest.split_once(':') {
+                        if token_id.is_empty() {
+                            return Err(format!("Invalid asset id: {s}, token id is empty"));
+                        }
                         Ok(Self::Nep245(
                             contract_id
                                 .parse()
@@ -122,6 +192,9 @@ impl FromStr for AssetId {
                 }
                 Some(("nep171", rest)) => {
                     if let Some((contract_id, token_id)) = rest.split_once(':') {
+                        if token_id.is_empty() {
+                            return Err(format!("Invalid asset id: {s}, token id is empty"));
+                        }
                         Ok(Self::Nep171(
                             contract_id
                                 .parse()
@@ -240,6 +313,141 @@ impl near_sdk::schemars::JsonSchema for DexId {
     }
 }
 
+/// Denominator of fees in basis points.
+pub const BPS_DENOMINATOR: u16 = 10_000;
+
+/// An amount split into fees and the rest, see `split_fees`.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+pub struct FeeSplit {
+    /// What's left of the gross amount after all fees.
+    pub net: U128,
+    /// The fees, in the same order as they were requested.
+    pub fees: Vec<U128>,
+}
+
+/// Takes fees in basis points from the gross amount, for example
+/// dex, protocol, and affiliate fees of a swap output.
+///
+/// Each fee is a share of the gross amount, not of what's left
+/// after the previous fees, so the order of fees doesn't matter.
+/// Fees don't overflow for any amount and are rounded down, so the rounding dust goes to the
+/// trader and not to the fee recipients. `net` plus the fees is
+/// always exactly `gross`. Fails if the fees add up to more than
+/// 100%.
+pub fn split_fees(gross: U128, fees_bps: &[u16]) -> Result<FeeSplit, String> {
+    let total_bps = fees_bps
+        .iter()
+        .fold(0u32, |total, bps| total.saturating_add(u32::from(*bps)));
+    if total_bps > u32::from(BPS_DENOMINATOR) {
+        return Err(format!(
+            "Fees add up to {total_bps} bps, more than {BPS_DENOMINATOR}"
+        ));
+    }
+    let mut net = gross.0;
+    let fees = fees_bps
+        .iter()
+        .map(|bps| {
+            let bps = u128::from(*bps);
+            let denominator = u128::from(BPS_DENOMINATOR);
+            // Split so that nothing overflows: the first term is at
+            // most gross, the second is less than 10000 * 10000, and
+            // together they are gross * bps / 10000 rounded down
+            #[allow(clippy::arithmetic_side_effects)]
+            let fee = gross.0 / denominator * bps + gross.0 % denominator * bps / denominator;
+            // Fees are rounded down, so their sum is at most the
+            // sum of exact shares, which is at most gross
+            net = net.checked_sub(fee).expect("Fees exceed the gross amount");
+            U128(fee)
+        })
+        .collect();
+    Ok(FeeSplit {
+        net: U128(net),
+        fees,
+    })
+}
+
+/// Price impact of a swap in basis points: how much worse the price
+/// of the swap is than the spot price `reserve_out / reserve_in` of
+/// the pool before the swap. Rounded up, so that a cap on the price
+/// impact can't be bypassed by rounding. Zero if the swap got the
+/// spot price or better. Fails if the reserves or the input are zero.
+pub fn price_impact_bps(
+    reserve_in: U128,
+    reserve_out: U128,
+    amount_in: U128,
+    amount_out: U128,
+) -> Result<u16, String> {
+    if reserve_in.0 == 0 || reserve_out.0 == 0 {
+        return Err("Reserves of the pool are empty".to_string());
+    }
+    if amount_in.0 == 0 {
+        return Err("Amount in is zero".to_string());
+    }
+    let spot_value = widening_product([amount_in.0, reserve_out.0, 1]);
+    let swap_value = widening_product([amount_out.0, reserve_in.0, 1]);
+    if swap_value.iter().rev().ge(spot_value.iter().rev()) {
+        return Ok(0);
+    }
+    // The impact is the smallest `bps` for which
+    // `(10000 - bps) * spot_value <= 10000 * swap_value`,
+    // and 10000 always satisfies it
+    let denominator = u128::from(BPS_DENOMINATOR);
+    let scaled_swap_value = widening_product([amount_out.0, reserve_in.0, denominator]);
+    let (mut low, mut high) = (1, BPS_DENOMINATOR);
+    while low < high {
+        #[allow(clippy::arithmetic_side_effects)]
+        let mid = low + (high - low) / 2;
+        #[allow(clippy::arithmetic_side_effects)]
+        let scaled_spot_value = widening_product([
+            amount_in.0,
+            reserve_out.0,
+            u128::from(BPS_DENOMINATOR - mid),
+        ]);
+        if scaled_spot_value
+            .iter()
+            .rev()
+            .le(scaled_swap_value.iter().rev())
+        {
+            high = mid;
+        } else {
+            #[allow(clippy::arithmetic_side_effects)]
+            {
+                low = mid + 1;
+            }
+        }
+    }
+    Ok(low)
+}
+
+/// Exact product of three u128 as little-endian 64-bit limbs.
+fn widening_product(factors: [u128; 3]) -> [u64; 6] {
+    let mut product = [1, 0, 0, 0, 0, 0];
+    for factor in factors {
+        let factor = [factor as u64, (factor >> 64) as u64];
+        let mut next = [0u64; 6];
+        for (i, limb) in product.iter().enumerate() {
+            let mut carry = 0u128;
+            for (j, factor_limb) in factor.iter().enumerate() {
+                let Some(next_limb) = next.get_mut(i.saturating_add(j)) else {
+                    break;
+                };
+                // u64 * u64 + u64 + u64 fits in u128
+                #[allow(clippy::arithmetic_side_effects)]
+                let sum =
+                    u128::from(*next_limb) + u128::from(*limb) * u128::from(*factor_limb) + carry;
+                *next_limb = sum as u64;
+                carry = sum >> 64;
+            }
+            if let Some(next_limb) = next.get_mut(i.saturating_add(factor.len())) {
+                *next_limb = carry as u64;
+            }
+        }
+        product = next;
+    }
+    product
+}
+
 pub trait Dex {
     fn swap(&mut self, request: SwapRequest) -> SwapResponse;
 }
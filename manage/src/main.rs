@@ -29,6 +29,7 @@ enum Commands {
 #[derive(Subcommand)]
 enum OtcAction {
     Deploy,
+    Upgrade,
     SetAuthorizedKey {
         account_id: AccountId,
         key: PublicKey,
@@ -86,7 +87,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match cli.command {
         Commands::Otc { action } => match action {
-            OtcAction::Deploy => {
+            OtcAction::Deploy | OtcAction::Upgrade => {
+                let method = match action {
+                    OtcAction::Upgrade => "upgrade_dex_code",
+                    _ => "deploy_dex_code",
+                };
                 println!("Compiling otc-dex");
                 assert!(
                     Command::new("cargo")
@@ -116,16 +121,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .unwrap()
                         .success()
                 );
-                println!("Deploying otc-dex");
+                println!("Deploying otc-dex with {method}");
                 let wasm =
                     std::fs::read("./target/wasm32-unknown-unknown/release/otc_dex.wasm").unwrap();
                 let wasm_base64 = BASE64_STANDARD.encode(&wasm);
-                let args = json!({
-                    "last_part_of_id": "otc",
-                    "code_base64": wasm_base64,
-                });
+                let args = match action {
+                    OtcAction::Upgrade => json!({
+                        "dex_id": {
+                            "deployer": config.deployer_id,
+                            "id": "otc",
+                        },
+                        "code_base64": wasm_base64,
+                    }),
+                    _ => json!({
+                        "last_part_of_id": "otc",
+                        "code_base64": wasm_base64,
+                    }),
+                };
                 let result = Contract(config.dex_contract_id.clone())
-                    .call_function("deploy_dex_code", args)
+                    .call_function(method, args)
                     .transaction()
                     .max_gas()
                     .deposit(NearToken::from_yoctonear(1))
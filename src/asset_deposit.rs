@@ -16,7 +16,8 @@ use crate::{
 impl DexEngine {
     #[payable]
     /// Deposit near to the dex engine contract's inner
-    /// balance for the user.
+    /// balance for the user. The whole attached deposit is
+    /// credited, in yoctoNEAR.
     pub fn deposit_near(&mut self, operations: Option<Vec<Operation>>) {
         let deposit = U128(near_sdk::env::attached_deposit().as_yoctonear());
         self.total_in_custody
@@ -59,6 +60,15 @@ impl DexEngine {
 
 #[near]
 impl FungibleTokenReceiver for DexEngine {
+    /// Deposits the tokens to the sender's balance. If `msg` is
+    /// a JSON list of operations, the operations are executed
+    /// with only the transferred tokens available, so the tokens
+    /// are already held by the engine before any dex code runs.
+    /// If any operation
+    /// fails, the whole transfer is refunded by the token
+    /// contract. This is the only way to pull input tokens
+    /// within a swap, since the engine can't initiate a transfer
+    /// on behalf of the trader.
     fn ft_on_transfer(
         &mut self,
         sender_id: AccountId,
@@ -114,6 +124,11 @@ impl FungibleTokenReceiver for DexEngine {
 
 #[near]
 impl NonFungibleTokenReceiver for DexEngine {
+    /// Deposits the token to the previous owner's balance, or runs
+    /// the operations in `msg`, like `ft_on_transfer`. Each token
+    /// is its own asset, `AssetId::Nep171(contract, token_id)`,
+    /// with a balance of 1 while the engine holds it, so dexes
+    /// trade NFTs with the same `U128` balances as other assets.
     fn nft_on_transfer(
         &mut self,
         sender_id: AccountId,
@@ -178,6 +193,10 @@ impl NonFungibleTokenReceiver for DexEngine {
 // There's no interface in near-contract-standards for nep245
 #[near]
 impl DexEngine {
+    /// Deposits the tokens to the previous owners' balances, or
+    /// runs the operations in `msg`, like `ft_on_transfer`. Each
+    /// token id is a separate asset, `AssetId::Nep245(contract,
+    /// token_id)`, with the transferred amount as its balance.
     pub fn mt_on_transfer(
         &mut self,
         sender_id: AccountId,
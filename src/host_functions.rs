@@ -1,7 +1,119 @@
 use wasmi::Caller;
 
-use crate::{CallType, IntearDexEvent, RunnerData};
-use near_sdk::NearToken;
+use crate::{CallType, IntearDexEvent, RunnerData, StorageKey};
+use intear_dex_types::{AssetId, DexId};
+use near_sdk::{NearToken, json_types::U128};
+
+impl RunnerData<'_> {
+    fn set_register(&mut self, register_id: u64, value: Vec<u8>) -> Result<(), wasmi::Error> {
+        let limit = self.limits.register_bytes;
+        let other_registers_len = self
+            .registers
+            .iter()
+            .filter(|(id, _)| **id != register_id)
+            .map(|(_, value)| value.len() as u64)
+            .fold(0u64, u64::saturating_add);
+        if other_registers_len.saturating_add(value.len() as u64) > limit {
+            return Err(wasmi::Error::new(format!(
+                "[{}] Dex exceeded the register limit of {limit} bytes",
+                self.dex_id
+            )));
+        }
+        self.registers.insert(register_id, value);
+        Ok(())
+    }
+
+    fn write_storage(
+        &mut self,
+        function: &str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, wasmi::Error> {
+        let dex_id = &self.dex_id;
+        let limit = self.limits.storage_write_bytes;
+        self.storage_bytes_written = self
+            .storage_bytes_written
+            .saturating_add(key.len() as u64)
+            .saturating_add(value.len() as u64);
+        if self.storage_bytes_written > limit {
+            return Err(wasmi::Error::new(format!(
+                "[{dex_id}] Dex exceeded the storage write limit of {limit} bytes"
+            )));
+        }
+        if self.call_type.is_view() {
+            return Err(wasmi::Error::new(format!(
+                "[{dex_id}] {function} is not allowed in view functions"
+            )));
+        }
+        #[cfg(feature = "testing")]
+        self.trace
+            .storage_writes
+            .push((key.clone().into(), Some(value.clone().into())));
+        let record_bytes = dex_storage_record_bytes(dex_id, &key, &value);
+        let value_len = value.len() as i64;
+        let old_value = self.call_type.storage_set(dex_id, key, Some(value));
+        let added_bytes = match &old_value {
+            Some(old_value) => value_len.saturating_sub(old_value.len() as i64),
+            None => record_bytes,
+        };
+        self.unflushed_storage_bytes = self.unflushed_storage_bytes.saturating_add(added_bytes);
+        Ok(old_value)
+    }
+
+    /// Logs the message prefixed with the dex id, or queues it as a
+    /// `DexEvent` if it's an `EVENT_JSON:` event, see
+    /// `pending_events`.
+    fn log(&mut self, message: &str) {
+        #[cfg(feature = "testing")]
+        self.trace.logs.push(message.to_string());
+        if let Some(event) = message.strip_prefix("EVENT_JSON:") {
+            if let Ok(event) = near_sdk::serde_json::from_str(event) {
+                let sequence = self.event_sequence;
+                self.event_sequence = sequence.checked_add(1).expect("Event sequence overflow");
+                self.pending_events.push(IntearDexEvent::DexEvent {
+                    dex_id: self.dex_id.clone(),
+                    event,
+                    sequence,
+                });
+                return;
+            }
+        }
+        near_sdk::env::log_str(&format!("[{}] {message}", self.dex_id));
+    }
+}
+
+/// Bytes of contract storage that a record of the dex storage takes,
+/// counted the way `env::storage_usage` counts it: the key of the
+/// lookup map, the borsh-encoded value, and the fixed overhead of a
+/// record.
+fn dex_storage_record_bytes(dex_id: &DexId, key: &[u8], value: &[u8]) -> i64 {
+    const RECORD_OVERHEAD_BYTES: usize = 40;
+    let prefix =
+        near_sdk::borsh::to_vec(&StorageKey::DexStorage).expect("Failed to serialize storage key");
+    let map_key = near_sdk::borsh::to_vec(&(dex_id, key)).expect("Failed to serialize storage key");
+    let value = near_sdk::borsh::to_vec(value).expect("Failed to serialize value");
+    prefix
+        .len()
+        .saturating_add(map_key.len())
+        .saturating_add(value.len())
+        .saturating_add(RECORD_OVERHEAD_BYTES) as i64
+}
+
+/// Prefix of the keys in the dex storage under which `set_reserves`
+/// stores the reserves of a pool. Starts with a zero byte, so it
+/// doesn't collide with human-readable keys of the dex.
+const RESERVES_STORAGE_KEY_PREFIX: &[u8] = b"\0reserves/";
+
+/// Key in the dex storage under which `set_reserves` stores the
+/// reserves of a pool.
+pub(crate) fn reserves_storage_key(pool_id: u64) -> Vec<u8> {
+    [RESERVES_STORAGE_KEY_PREFIX, &pool_id.to_le_bytes()].concat()
+}
+
+pub(crate) fn is_reserves_storage_key(key: &[u8]) -> bool {
+    key.strip_prefix(RESERVES_STORAGE_KEY_PREFIX)
+        .is_some_and(|pool_id| pool_id.len() == size_of::<u64>())
+}
 
 #[macro_export]
 macro_rules! declare_unimplemented_host_functions {
@@ -46,12 +158,8 @@ macro_rules! impl_unsupported_host_functions {
             // ####################
             // # Unsupported APIs #
             // ####################
-            pub fn current_account_id(register_id: u64);
             pub fn current_contract_code(register_id: u64) -> u64;
             pub fn refund_to_account_id(register_id: u64);
-            pub fn signer_account_id(register_id: u64);
-            pub fn signer_account_pk(register_id: u64);
-            pub fn account_balance(balance_ptr: u64);
             pub fn account_locked_balance(balance_ptr: u64);
             pub fn validator_stake(account_id_len: u64, account_id_ptr: u64, stake_ptr: u64);
             pub fn validator_total_stake(stake_ptr: u64);
@@ -195,10 +303,12 @@ macro_rules! impl_unsupported_host_functions {
             ) -> u32;
             pub fn promise_results_count() -> u64;
             pub fn promise_result(result_idx: u64, register_id: u64) -> u64;
-            pub fn promise_return(promise_id: u64);
             // ##########################
             // # Deprecated Storage API #
             // ##########################
+            // Dex storage lives in the engine's trie, which contracts
+            // can't enumerate, so dexes need to keep their own index of
+            // the keys they want to iterate over
             pub fn storage_iter_prefix(prefix_len: u64, prefix_ptr: u64) -> u64;
             pub fn storage_iter_range(start_len: u64, start_ptr: u64, end_len: u64, end_ptr: u64) -> u64;
             pub fn storage_iter_next(iterator_id: u64, key_register_id: u64, value_register_id: u64)
@@ -238,10 +348,15 @@ macro_rules! impl_supported_host_functions {
         $crate::impl_host_function!($var, write_register);
         $crate::impl_host_function!($var, input);
         $crate::impl_host_function!($var, attached_deposit);
+        $crate::impl_host_function!($var, account_balance);
         $crate::impl_host_function!($var, predecessor_account_id);
+        $crate::impl_host_function!($var, current_account_id);
+        $crate::impl_host_function!($var, signer_account_id);
+        $crate::impl_host_function!($var, signer_account_pk);
         $crate::impl_host_function!($var, value_return);
         $crate::impl_host_function!($var, panic);
         $crate::impl_host_function!($var, panic_utf8);
+        $crate::impl_host_function!($var, abort);
         $crate::impl_host_function!($var, storage_write);
         $crate::impl_host_function!($var, storage_read);
         $crate::impl_host_function!($var, storage_remove);
@@ -261,6 +376,19 @@ macro_rules! impl_supported_host_functions {
         $crate::impl_host_function!($var, ed25519_verify);
         $crate::impl_host_function!($var, log_utf8);
         $crate::impl_host_function!($var, log_utf16);
+        $crate::impl_host_function!($var, promise_return);
+
+        // #########################
+        // # Sandbox-specific APIs #
+        // #########################
+        $crate::impl_host_function!($var, fuel_remaining);
+        $crate::impl_host_function!($var, dex_id);
+        $crate::impl_host_function!($var, debug_kv);
+        $crate::impl_host_function!($var, on_pool_created);
+        $crate::impl_host_function!($var, soft_fail);
+        $crate::impl_host_function!($var, read_config);
+        $crate::impl_host_function!($var, set_reserves);
+        $crate::impl_host_function!($var, get_reserves);
     };
 }
 
@@ -294,7 +422,7 @@ pub fn write_register(
     register_id: u64,
     data_len: u64,
     data_ptr: u64,
-) {
+) -> Result<(), wasmi::Error> {
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -303,15 +431,17 @@ pub fn write_register(
     memory
         .read(&caller, data_ptr as usize, &mut buf)
         .expect("Failed to read data from guest memory");
-    caller.data_mut().registers.insert(register_id, buf);
+    caller.data_mut().set_register(register_id, buf)
 }
 
-pub fn input(mut caller: Caller<'_, RunnerData>, register_id: u64) {
+pub fn input(mut caller: Caller<'_, RunnerData>, register_id: u64) -> Result<(), wasmi::Error> {
     let request = caller.data().request.clone();
-    caller.data_mut().registers.insert(register_id, request);
+    caller.data_mut().set_register(register_id, request)
 }
 
-// 1 yocto if this is an authorized dex call, 0 otherwise
+// 1 yocto if this is an authorized dex call, 0 otherwise. Swaps
+// always see 0, since a deposit attached to a swap goes to the
+// trader's balance, not to the dex
 pub fn attached_deposit(mut caller: Caller<'_, RunnerData>, balance_ptr: u64) {
     let attached_deposit = match caller.data().call_type {
         CallType::Call {
@@ -333,15 +463,88 @@ pub fn attached_deposit(mut caller: Caller<'_, RunnerData>, balance_ptr: u64) {
         .expect("Failed to write data to guest memory");
 }
 
-pub fn predecessor_account_id(mut caller: Caller<'_, RunnerData>, register_id: u64) {
+/// The NEAR balance of the dex in the engine, rather than the
+/// balance of the engine account, which is shared by all dexes and
+/// users. It's the balance before the current call, so it doesn't
+/// include the amount being swapped in.
+pub fn account_balance(mut caller: Caller<'_, RunnerData>, balance_ptr: u64) {
+    let near_balance = caller.data().near_balance;
+    let memory = caller
+        .get_export("memory")
+        .and_then(|m| m.into_memory())
+        .expect("Failed to get memory");
+    memory
+        .write(
+            &mut caller,
+            balance_ptr as usize,
+            &near_balance.to_le_bytes(),
+        )
+        .expect("Failed to write data to guest memory");
+}
+
+pub fn predecessor_account_id(
+    mut caller: Caller<'_, RunnerData>,
+    register_id: u64,
+) -> Result<(), wasmi::Error> {
     let CallType::Call { predecessor_id, .. } = &caller.data().call_type else {
         panic!("predecessor_account_id is not allowed in view functions");
     };
     let buf = predecessor_id.to_string().into_bytes();
-    caller.data_mut().registers.insert(register_id, buf);
+    caller.data_mut().set_register(register_id, buf)
+}
+
+/// Writes the account id of the engine contract. All dexes run
+/// under the engine's account, so unlike in a natively deployed
+/// contract, this is not an account the dex owns, and it's the same
+/// for every dex. Use `dex_id` to tell dexes apart.
+pub fn current_account_id(
+    mut caller: Caller<'_, RunnerData>,
+    register_id: u64,
+) -> Result<(), wasmi::Error> {
+    let buf = near_sdk::env::current_account_id().to_string().into_bytes();
+    caller.data_mut().set_register(register_id, buf)
+}
+
+/// Writes the account id of the signer of the transaction. When
+/// the engine is called by another contract, this is not the
+/// predecessor.
+pub fn signer_account_id(
+    mut caller: Caller<'_, RunnerData>,
+    register_id: u64,
+) -> Result<(), wasmi::Error> {
+    if let CallType::View { .. } = caller.data().call_type {
+        panic!("signer_account_id is not allowed in view functions");
+    }
+    let buf = near_sdk::env::signer_account_id().to_string().into_bytes();
+    caller.data_mut().set_register(register_id, buf)
+}
+
+/// Writes the borsh public key that signed the transaction.
+pub fn signer_account_pk(
+    mut caller: Caller<'_, RunnerData>,
+    register_id: u64,
+) -> Result<(), wasmi::Error> {
+    if let CallType::View { .. } = caller.data().call_type {
+        panic!("signer_account_pk is not allowed in view functions");
+    }
+    let buf = near_sdk::env::signer_account_pk().into_bytes();
+    caller.data_mut().set_register(register_id, buf)
 }
 
-pub fn value_return(mut caller: Caller<'_, RunnerData>, value_len: u64, value_ptr: u64) {
+// Only one value can be returned. A second call is a bug in the
+// dex, such as trying to return a large response in chunks, so it
+// traps instead of silently replacing the first value
+pub fn value_return(
+    mut caller: Caller<'_, RunnerData>,
+    value_len: u64,
+    value_ptr: u64,
+) -> Result<(), wasmi::Error> {
+    if caller.data().response.is_some() {
+        let dex_id = caller.data().dex_id.clone();
+        return Err(wasmi::Error::new(format!(
+            "[{dex_id}] value_return can only be called once"
+        )));
+    }
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -351,14 +554,18 @@ pub fn value_return(mut caller: Caller<'_, RunnerData>, value_len: u64, value_pt
         .read(&caller, value_ptr as usize, &mut buf)
         .expect("Failed to get return value");
     caller.data_mut().response = Some(buf);
+    Ok(())
 }
 
-pub fn panic(caller: Caller<'_, RunnerData>) {
+// Dex panics trap instead of aborting the engine, so that
+// `try_swap` can recover from them. Everywhere else the trap
+// is turned into an engine panic by the caller
+pub fn panic(caller: Caller<'_, RunnerData>) -> Result<(), wasmi::Error> {
     let dex_id = caller.data().dex_id.clone();
-    panic!("[{dex_id}] Dex panicked");
+    Err(wasmi::Error::new(format!("[{dex_id}] Dex panicked")))
 }
 
-pub fn panic_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
+pub fn panic_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) -> Result<(), wasmi::Error> {
     let dex_id = caller.data().dex_id.clone();
     let memory = caller
         .get_export("memory")
@@ -369,7 +576,55 @@ pub fn panic_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
         .read(&caller, ptr as usize, &mut buf)
         .expect("Failed to read panic message");
     let message = String::from_utf8(buf).expect("Failed to parse panic message");
-    panic!("[{dex_id}] Dex panicked: {message}");
+    Err(wasmi::Error::new(format!(
+        "[{dex_id}] Dex panicked: {message}"
+    )))
+}
+
+/// Called by AssemblyScript on failed assertions. The message
+/// and the file name are AssemblyScript strings: UTF-16 with the
+/// length in bytes stored as a `u32` right before the pointer.
+/// Traps like `panic_utf8`, after logging the message.
+pub fn abort(
+    caller: Caller<'_, RunnerData>,
+    msg_ptr: u32,
+    filename_ptr: u32,
+    line: u32,
+    col: u32,
+) -> Result<(), wasmi::Error> {
+    let dex_id = caller.data().dex_id.clone();
+    let memory = caller
+        .get_export("memory")
+        .and_then(|m| m.into_memory())
+        .expect("Failed to get memory");
+    let read_string = |ptr: u32| -> Result<String, wasmi::Error> {
+        // AssemblyScript passes 0 for a null message or filename
+        if ptr == 0 {
+            return Ok("null".to_string());
+        }
+        let len_ptr = ptr.checked_sub(4).ok_or_else(|| {
+            wasmi::Error::new(format!("[{dex_id}] abort: invalid string pointer {ptr}"))
+        })?;
+        let mut len = [0; 4];
+        memory
+            .read(&caller, len_ptr as usize, &mut len)
+            .map_err(|err| wasmi::Error::new(format!("[{dex_id}] abort: {err}")))?;
+        let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+        memory
+            .read(&caller, ptr as usize, &mut buf)
+            .map_err(|err| wasmi::Error::new(format!("[{dex_id}] abort: {err}")))?;
+        let utf16: Vec<u16> = buf
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+        String::from_utf16(&utf16)
+            .map_err(|_| wasmi::Error::new(format!("[{dex_id}] abort: invalid UTF-16")))
+    };
+    let message = read_string(msg_ptr)?;
+    let filename = read_string(filename_ptr)?;
+    let message = format!("aborted: {message} at {filename}:{line}:{col}");
+    near_sdk::env::log_str(&format!("[{dex_id}] {message}"));
+    Err(wasmi::Error::new(format!("[{dex_id}] Dex {message}")))
 }
 
 pub fn storage_write(
@@ -379,8 +634,7 @@ pub fn storage_write(
     value_len: u64,
     value_ptr: u64,
     register_id: u64,
-) -> u64 {
-    let dex_id = caller.data().dex_id.clone();
+) -> Result<u64, wasmi::Error> {
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -394,16 +648,15 @@ pub fn storage_write(
         .read(&caller, value_ptr as usize, &mut value_buf)
         .expect("Failed to read value from guest memory");
 
-    let Some(dex_storage_mut) = caller.data_mut().call_type.dex_storage_mut() else {
-        panic!("storage_write is not allowed in view functions");
-    };
-    let old_value = dex_storage_mut.insert((dex_id, key_buf), value_buf);
+    let old_value = caller
+        .data_mut()
+        .write_storage("storage_write", key_buf, value_buf)?;
 
     if let Some(old_val) = old_value {
-        caller.data_mut().registers.insert(register_id, old_val);
-        1
+        caller.data_mut().set_register(register_id, old_val)?;
+        Ok(1)
     } else {
-        0
+        Ok(0)
     }
 }
 
@@ -412,7 +665,7 @@ pub fn storage_read(
     key_len: u64,
     key_ptr: u64,
     register_id: u64,
-) -> u64 {
+) -> Result<u64, wasmi::Error> {
     let dex_id = caller.data().dex_id.clone();
     let memory = caller
         .get_export("memory")
@@ -426,14 +679,13 @@ pub fn storage_read(
     if let Some(value) = caller
         .data()
         .call_type
-        .dex_storage()
-        .get(&(dex_id, key_buf))
+        .storage_get(&dex_id, &key_buf)
         .cloned()
     {
-        caller.data_mut().registers.insert(register_id, value);
-        1
+        caller.data_mut().set_register(register_id, value)?;
+        Ok(1)
     } else {
-        0
+        Ok(0)
     }
 }
 
@@ -442,7 +694,7 @@ pub fn storage_remove(
     key_len: u64,
     key_ptr: u64,
     register_id: u64,
-) -> u64 {
+) -> Result<u64, wasmi::Error> {
     let dex_id = caller.data().dex_id.clone();
     let memory = caller
         .get_export("memory")
@@ -453,14 +705,26 @@ pub fn storage_remove(
         .read(&caller, key_ptr as usize, &mut key_buf)
         .expect("Failed to read key from guest memory");
 
-    let Some(dex_storage_mut) = caller.data_mut().call_type.dex_storage_mut() else {
-        panic!("storage_write is not allowed in view functions");
-    };
-    if let Some(old_value) = dex_storage_mut.remove(&(dex_id, key_buf)) {
-        caller.data_mut().registers.insert(register_id, old_value);
-        1
+    if caller.data().call_type.is_view() {
+        return Err(wasmi::Error::new(format!(
+            "[{dex_id}] storage_remove is not allowed in view functions"
+        )));
+    }
+    #[cfg(feature = "testing")]
+    caller
+        .data_mut()
+        .trace
+        .storage_writes
+        .push((key_buf.clone().into(), None));
+    let data = caller.data_mut();
+    if let Some(old_value) = data.call_type.storage_set(&dex_id, key_buf.clone(), None) {
+        data.unflushed_storage_bytes = data
+            .unflushed_storage_bytes
+            .saturating_sub(dex_storage_record_bytes(&dex_id, &key_buf, &old_value));
+        data.set_register(register_id, old_value)?;
+        Ok(1)
     } else {
-        0
+        Ok(0)
     }
 }
 
@@ -478,8 +742,8 @@ pub fn storage_has_key(caller: Caller<'_, RunnerData>, key_len: u64, key_ptr: u6
     if caller
         .data()
         .call_type
-        .dex_storage()
-        .contains_key(&(dex_id, key_buf))
+        .storage_get(&dex_id, &key_buf)
+        .is_some()
     {
         1
     } else {
@@ -499,10 +763,14 @@ pub fn epoch_height(_caller: Caller<'_, RunnerData>) -> u64 {
     near_sdk::env::epoch_height()
 }
 
-pub fn storage_usage(mut caller: Caller<'_, RunnerData>) -> u64 {
-    if let Some(dex_storage_mut) = caller.data_mut().call_type.dex_storage_mut() {
-        dex_storage_mut.flush();
-    };
+/// Returns the bytes of storage used by the current dex, including
+/// the changes made so far in this call. This is scoped to the dex,
+/// not the whole engine account that `env::storage_usage` reports.
+///
+/// The writes of a swap stay in memory until the swap succeeds, and
+/// other calls flush the dex storage only after the dex returns, so
+/// the changes of this call are counted as they're made instead.
+pub fn storage_usage(caller: Caller<'_, RunnerData>) -> u64 {
     let storage_usage_now = near_sdk::env::storage_usage();
     let storage_usage_during_transaction = i64::try_from(storage_usage_now)
         .expect("Storage usage overflow")
@@ -518,6 +786,7 @@ pub fn storage_usage(mut caller: Caller<'_, RunnerData>) -> u64 {
     i64::try_from(data_used_before_transaction)
         .expect("Data used before transaction overflow")
         .checked_add(storage_usage_during_transaction)
+        .and_then(|usage| usage.checked_add(caller.data().unflushed_storage_bytes))
         .expect("Result of storage usage calculation is not within i64 range")
         .try_into()
         .expect("Result of storage usage calculation is not within u64 range")
@@ -531,12 +800,20 @@ pub fn used_gas(_caller: Caller<'_, RunnerData>) -> u64 {
     near_sdk::env::used_gas().as_gas()
 }
 
-pub fn random_seed(mut caller: Caller<'_, RunnerData>, register_id: u64) {
+/// The random seed of the block, the same for every dex and every
+/// call in the block, so validators get the same result. Block
+/// producers know it in advance, so dexes can use it for
+/// tie-breaking, but not for anything that must be unpredictable.
+pub fn random_seed(
+    mut caller: Caller<'_, RunnerData>,
+    register_id: u64,
+) -> Result<(), wasmi::Error> {
+    #[cfg(feature = "testing")]
+    if let Some(seed) = crate::random_seed_override() {
+        return caller.data_mut().set_register(register_id, seed);
+    }
     let seed = near_sdk::env::random_seed();
-    caller
-        .data_mut()
-        .registers
-        .insert(register_id, seed.to_vec());
+    caller.data_mut().set_register(register_id, seed.to_vec())
 }
 
 pub fn sha256(
@@ -544,7 +821,7 @@ pub fn sha256(
     value_len: u64,
     value_ptr: u64,
     register_id: u64,
-) {
+) -> Result<(), wasmi::Error> {
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -554,10 +831,7 @@ pub fn sha256(
         .read(&caller, value_ptr as usize, &mut value_buf)
         .expect("Failed to read value from guest memory");
     let hash = near_sdk::env::sha256_array(&value_buf);
-    caller
-        .data_mut()
-        .registers
-        .insert(register_id, hash.to_vec());
+    caller.data_mut().set_register(register_id, hash.to_vec())
 }
 
 pub fn keccak256(
@@ -565,7 +839,7 @@ pub fn keccak256(
     value_len: u64,
     value_ptr: u64,
     register_id: u64,
-) {
+) -> Result<(), wasmi::Error> {
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -575,10 +849,7 @@ pub fn keccak256(
         .read(&caller, value_ptr as usize, &mut value_buf)
         .expect("Failed to read value from guest memory");
     let hash = near_sdk::env::keccak256_array(&value_buf);
-    caller
-        .data_mut()
-        .registers
-        .insert(register_id, hash.to_vec());
+    caller.data_mut().set_register(register_id, hash.to_vec())
 }
 
 pub fn keccak512(
@@ -586,7 +857,7 @@ pub fn keccak512(
     value_len: u64,
     value_ptr: u64,
     register_id: u64,
-) {
+) -> Result<(), wasmi::Error> {
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -596,10 +867,7 @@ pub fn keccak512(
         .read(&caller, value_ptr as usize, &mut value_buf)
         .expect("Failed to read value from guest memory");
     let hash = near_sdk::env::keccak512_array(&value_buf);
-    caller
-        .data_mut()
-        .registers
-        .insert(register_id, hash.to_vec());
+    caller.data_mut().set_register(register_id, hash.to_vec())
 }
 
 pub fn ripemd160(
@@ -607,7 +875,7 @@ pub fn ripemd160(
     value_len: u64,
     value_ptr: u64,
     register_id: u64,
-) {
+) -> Result<(), wasmi::Error> {
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -617,10 +885,7 @@ pub fn ripemd160(
         .read(&caller, value_ptr as usize, &mut value_buf)
         .expect("Failed to read value from guest memory");
     let hash = near_sdk::env::ripemd160_array(&value_buf);
-    caller
-        .data_mut()
-        .registers
-        .insert(register_id, hash.to_vec());
+    caller.data_mut().set_register(register_id, hash.to_vec())
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -633,7 +898,7 @@ pub fn ecrecover(
     v: u64,
     malleability_flag: u64,
     register_id: u64,
-) -> u64 {
+) -> Result<u64, wasmi::Error> {
     if v >= 4 {
         panic!("Invalid recovery ID passed to ecrecover: {v}");
     }
@@ -663,11 +928,10 @@ pub fn ecrecover(
     if let Some(public_key) = maybe_public_key {
         caller
             .data_mut()
-            .registers
-            .insert(register_id, public_key.to_vec());
-        1
+            .set_register(register_id, public_key.to_vec())?;
+        Ok(1)
     } else {
-        0
+        Ok(0)
     }
 }
 
@@ -706,8 +970,10 @@ pub fn ed25519_verify(
     }
 }
 
-pub fn log_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
-    let dex_id = caller.data().dex_id.clone();
+/// Logs the message, see `RunnerData::log`. Invalid UTF-8 is
+/// replaced with U+FFFD instead of failing the dex, since a log
+/// is not worth failing a swap for.
+pub fn log_utf8(mut caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
@@ -721,41 +987,245 @@ pub fn log_utf8(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
             .expect("Failed to read log_utf8 buffer from guest memory");
         buf
     };
-    let message = String::from_utf8(msg_bytes).expect("log_utf8 received invalid UTF-8");
-    if let Some(event) = message.strip_prefix("EVENT_JSON:") {
-        if let Ok(event) = near_sdk::serde_json::from_str(event) {
-            IntearDexEvent::DexEvent {
-                dex_id: caller.data().dex_id.clone(),
-                event,
-            }
-            .emit();
-            return;
-        }
-    }
-
-    near_sdk::env::log_str(&format!("[{dex_id}] {message}"));
+    let message = String::from_utf8_lossy(&msg_bytes);
+    caller.data_mut().log(&message);
 }
 
-pub fn log_utf16(caller: Caller<'_, RunnerData>, len: u64, ptr: u64) {
+/// Same as `log_utf8`, for UTF-16 messages. Invalid UTF-16 is
+/// replaced with U+FFFD.
+pub fn log_utf16(
+    mut caller: Caller<'_, RunnerData>,
+    len: u64,
+    ptr: u64,
+) -> Result<(), wasmi::Error> {
     let dex_id = caller.data().dex_id.clone();
     let memory = caller
         .get_export("memory")
         .and_then(|m| m.into_memory())
         .expect("Failed to get memory");
+    let not_terminated = || {
+        wasmi::Error::new(format!(
+            "[{dex_id}] log_utf16: string is not null-terminated within guest memory"
+        ))
+    };
     let utf16: Vec<u16> = if len == u64::MAX {
-        panic!("log_utf16: unterminated log strings are not supported");
+        // Null-terminated, as AssemblyScript emits it
+        let mut utf16 = Vec::new();
+        let mut unit_ptr = ptr as usize;
+        loop {
+            let mut unit = [0; 2];
+            memory
+                .read(&caller, unit_ptr, &mut unit)
+                .map_err(|_| not_terminated())?;
+            let unit = u16::from_le_bytes(unit);
+            if unit == 0 {
+                break utf16;
+            }
+            utf16.push(unit);
+            unit_ptr = unit_ptr.checked_add(2).ok_or_else(not_terminated)?;
+        }
     } else {
         if len % 2 != 0 {
-            panic!("log_utf16 length must be even (u16 units)");
+            return Err(wasmi::Error::new(format!(
+                "[{dex_id}] log_utf16 length must be even (u16 units)"
+            )));
         }
         let mut buf = vec![0; len as usize];
         memory
             .read(&caller, ptr as usize, &mut buf)
-            .expect("Failed to read log_utf16 buffer from guest memory");
+            .map_err(|err| wasmi::Error::new(format!("[{dex_id}] log_utf16: {err}")))?;
         buf.chunks_exact(2)
             .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
             .collect()
     };
-    let message = String::from_utf16(&utf16).expect("log_utf16 received invalid UTF-16");
-    near_sdk::env::log_str(&format!("[{dex_id}] {message}"));
+    let message = String::from_utf16_lossy(&utf16);
+    caller.data_mut().log(&message);
+    Ok(())
+}
+
+/// Dexes can't create promises, so there's nothing to return.
+/// Fails with a clear message instead of a generic one.
+pub fn promise_return(
+    caller: Caller<'_, RunnerData>,
+    _promise_id: u64,
+) -> Result<(), wasmi::Error> {
+    let dex_id = caller.data().dex_id.clone();
+    Err(wasmi::Error::new(format!(
+        "[{dex_id}] promise_return is not supported, dexes must return the result with value_return"
+    )))
+}
+
+/// Not a NEAR host function. Returns the amount of fuel left for
+/// the current dex invocation, so that dexes doing variable-length
+/// work can stop before they run out of it. The fuel reserve of
+/// the dex is not included, so zero means the dex should wrap up.
+pub fn fuel_remaining(caller: Caller<'_, RunnerData>) -> u64 {
+    caller
+        .get_fuel()
+        .expect("Fuel metering is not enabled")
+        .saturating_sub(caller.data().fuel_reserve)
+}
+
+/// Not a NEAR host function. Writes the JSON-serialized id of the
+/// dex that is running, for code deployed under multiple ids.
+/// `current_account_id` is the engine, not the dex.
+pub fn dex_id(mut caller: Caller<'_, RunnerData>, register_id: u64) -> Result<(), wasmi::Error> {
+    let buf =
+        near_sdk::serde_json::to_vec(&caller.data().dex_id).expect("Failed to serialize dex id");
+    caller.data_mut().set_register(register_id, buf)
+}
+
+/// Not a NEAR host function. Writes the configuration the dex was
+/// deployed with, or nothing if it has none.
+pub fn read_config(
+    mut caller: Caller<'_, RunnerData>,
+    register_id: u64,
+) -> Result<(), wasmi::Error> {
+    let data = caller.data();
+    let config = data
+        .dex_configs
+        .get(&data.dex_id)
+        .cloned()
+        .unwrap_or_default();
+    caller.data_mut().set_register(register_id, config)
+}
+
+/// Not a NEAR host function. Logs `{"key": "value"}` JSON for
+/// tooling to parse, if debug logs are enabled for the dex in its
+/// metadata. Does nothing otherwise.
+pub fn debug_kv(
+    caller: Caller<'_, RunnerData>,
+    key_len: u64,
+    key_ptr: u64,
+    value_len: u64,
+    value_ptr: u64,
+) {
+    if !caller.data().debug {
+        return;
+    }
+    let dex_id = caller.data().dex_id.clone();
+    let memory = caller
+        .get_export("memory")
+        .and_then(|m| m.into_memory())
+        .expect("Failed to get memory");
+    let mut key_buf = vec![0; key_len as usize];
+    memory
+        .read(&caller, key_ptr as usize, &mut key_buf)
+        .expect("Failed to read key from guest memory");
+    let mut value_buf = vec![0; value_len as usize];
+    memory
+        .read(&caller, value_ptr as usize, &mut value_buf)
+        .expect("Failed to read value from guest memory");
+    let key = String::from_utf8(key_buf).expect("debug_kv received invalid UTF-8 key");
+    let value = String::from_utf8(value_buf).expect("debug_kv received invalid UTF-8 value");
+    let mut object = near_sdk::serde_json::Map::new();
+    object.insert(key, near_sdk::serde_json::Value::String(value));
+    near_sdk::env::log_str(&format!(
+        "[{dex_id}] {}",
+        near_sdk::serde_json::Value::Object(object)
+    ));
+}
+
+/// Not a NEAR host function. Must be called by the dex every time
+/// it creates a pool, so that the engine can enforce
+/// `max_pools_per_dex`. Traps if the dex already has the maximum
+/// number of pools.
+pub fn on_pool_created(mut caller: Caller<'_, RunnerData>) -> Result<(), wasmi::Error> {
+    let data = caller.data_mut();
+    let dex_id = &data.dex_id;
+    if let CallType::View { .. } = data.call_type {
+        return Err(wasmi::Error::new(format!(
+            "[{dex_id}] on_pool_created is not allowed in view functions"
+        )));
+    }
+    if let Some(max_pools) = data.max_pools {
+        if data.pool_count >= max_pools {
+            return Err(wasmi::Error::new(format!(
+                "[{dex_id}] Dex reached the limit of {max_pools} pools"
+            )));
+        }
+    }
+    data.pool_count = data.pool_count.checked_add(1).expect("Pool count overflow");
+    Ok(())
+}
+
+/// Not a NEAR host function. Fails the swap like `panic_utf8`, but
+/// if the dex owner enabled `allow_soft_fail`, storage changes made
+/// by the dex are kept. No assets are moved.
+pub fn soft_fail(
+    mut caller: Caller<'_, RunnerData>,
+    len: u64,
+    ptr: u64,
+) -> Result<(), wasmi::Error> {
+    let dex_id = caller.data().dex_id.clone();
+    if !matches!(caller.data().call_type, CallType::Trade { .. }) {
+        return Err(wasmi::Error::new(format!(
+            "[{dex_id}] soft_fail is only allowed in swaps"
+        )));
+    }
+    let memory = caller
+        .get_export("memory")
+        .and_then(|m| m.into_memory())
+        .expect("Failed to get memory");
+    let mut buf = vec![0; len as usize];
+    memory
+        .read(&caller, ptr as usize, &mut buf)
+        .expect("Failed to read soft fail message");
+    let message = String::from_utf8(buf).expect("Failed to parse soft fail message");
+    caller.data_mut().soft_fail = Some(message.clone());
+    Err(wasmi::Error::new(format!(
+        "[{dex_id}] Dex soft-failed: {message}"
+    )))
+}
+
+/// Not a NEAR host function. Sets the reserves of a pool to borsh
+/// `Vec<(AssetId, U128)>`, stored in the dex storage under a key
+/// shared by all dexes, so that the engine and tooling can read
+/// reserves of any dex the same way. Counts towards the storage
+/// write limit like `storage_write`.
+pub fn set_reserves(
+    mut caller: Caller<'_, RunnerData>,
+    pool_id: u64,
+    reserves_len: u64,
+    reserves_ptr: u64,
+) -> Result<(), wasmi::Error> {
+    let dex_id = caller.data().dex_id.clone();
+    let memory = caller
+        .get_export("memory")
+        .and_then(|m| m.into_memory())
+        .expect("Failed to get memory");
+    let mut reserves_buf = vec![0; reserves_len as usize];
+    memory
+        .read(&caller, reserves_ptr as usize, &mut reserves_buf)
+        .expect("Failed to read reserves from guest memory");
+    if let Err(err) = near_sdk::borsh::from_slice::<Vec<(AssetId, U128)>>(&reserves_buf) {
+        return Err(wasmi::Error::new(format!(
+            "[{dex_id}] Invalid reserves of pool {pool_id}: {err}"
+        )));
+    }
+    caller
+        .data_mut()
+        .write_storage("set_reserves", reserves_storage_key(pool_id), reserves_buf)?;
+    Ok(())
+}
+
+/// Not a NEAR host function. Writes the borsh reserves of a pool
+/// set with `set_reserves` to the register. Returns 1 if the pool
+/// has reserves, 0 otherwise.
+pub fn get_reserves(
+    mut caller: Caller<'_, RunnerData>,
+    pool_id: u64,
+    register_id: u64,
+) -> Result<u64, wasmi::Error> {
+    let data = caller.data();
+    if let Some(reserves) = data
+        .call_type
+        .storage_get(&data.dex_id, &reserves_storage_key(pool_id))
+        .cloned()
+    {
+        caller.data_mut().set_register(register_id, reserves)?;
+        Ok(1)
+    } else {
+        Ok(0)
+    }
 }
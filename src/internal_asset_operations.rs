@@ -165,4 +165,60 @@ impl DexEngine {
             }
         }
     }
+
+    /// Sets the balance of a dex to `new_balance`, and changes the
+    /// total in custody by the same amount, so that the balances
+    /// stay consistent with each other.
+    pub(crate) fn internal_reconcile_dex_balance(
+        &mut self,
+        dex_id: DexId,
+        asset_id: AssetId,
+        new_balance: U128,
+    ) {
+        let old_balance = self
+            .asset_balance_of(AccountOrDexId::Dex(dex_id.clone()), asset_id.clone())
+            .unwrap_or_else(|| panic!("Asset {asset_id} is not registered for dex {dex_id}"));
+        let custody = self
+            .total_in_custody
+            .get_mut(&asset_id)
+            .unwrap_or_else(|| panic!("Asset {asset_id} is not tracked in total in custody"));
+        if new_balance.0 >= old_balance.0 {
+            // new_balance was checked to be greater or equal
+            #[allow(clippy::arithmetic_side_effects)]
+            let delta = U128(new_balance.0 - old_balance.0);
+            custody.0 = custody
+                .0
+                .checked_add(delta.0)
+                .expect("Total in custody overflow");
+            self.internal_increase_assets(
+                AccountOrDexId::Dex(dex_id.clone()),
+                asset_id.clone(),
+                delta,
+            );
+        } else {
+            // new_balance was checked to be less
+            #[allow(clippy::arithmetic_side_effects)]
+            let delta = U128(old_balance.0 - new_balance.0);
+            custody.0 = custody
+                .0
+                .checked_sub(delta.0)
+                .expect("Total in custody underflow");
+            self.internal_decrease_assets(
+                AccountOrDexId::Dex(dex_id.clone()),
+                asset_id.clone(),
+                delta,
+            );
+        }
+        near_sdk::env::log_str(&format!(
+            "WARNING: balance of dex {dex_id} for {asset_id} was reconciled from {} to {}",
+            old_balance.0, new_balance.0
+        ));
+        IntearDexEvent::BalanceReconciled {
+            dex_id,
+            asset_id,
+            old_balance,
+            new_balance,
+        }
+        .emit();
+    }
 }
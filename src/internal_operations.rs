@@ -1,22 +1,23 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 
 use intear_dex_types::{
     AssetId, AssetWithdrawRequest, AssetWithdrawalType, DexCallRequest, DexCallResponse, DexId,
-    SwapRequest, SwapRequestAmount, SwapResponse, expect,
-};
-use near_contract_standards::{
-    fungible_token::core::ext_ft_core, non_fungible_token::core::ext_nft_core,
+    SwapRequest, SwapRequestAmount, SwapResponse, expect, price_impact_bps,
 };
 use near_sdk::{
-    AccountId, Gas, NearToken, Promise, PromiseError, PromiseOrValue,
+    AccountId, Gas, NearToken, PromiseError, PromiseOrValue,
     json_types::{Base58CryptoHash, Base64VecU8, U128},
     near,
 };
-use wasmi::{Engine, Func, Linker, Module, Store};
+use wasmi::{
+    Config, EnforcedLimits, Engine, ExternType, Func, Linker, Module, Store, TrapCode,
+    errors::{ErrorKind, MemoryError},
+};
 
 use crate::{
-    CallType, DexEngine, DexEngineExt, IntearDexEvent, RunnerData, impl_supported_host_functions,
-    impl_unsupported_host_functions, internal_asset_operations::AccountOrDexId,
+    CallType, DexContext, DexEngine, DexEngineExt, IntearDexEvent, LastSwap, ResourceLimits,
+    RunnerData, ScratchStorage, host_functions, impl_supported_host_functions,
+    impl_unsupported_host_functions, internal_asset_operations::AccountOrDexId, transfer,
 };
 
 #[derive(Clone)]
@@ -36,6 +37,15 @@ pub enum TradeAccount<'a> {
     },
 }
 
+/// Why `internal_try_swap_simple` didn't go through.
+pub enum SwapFailure {
+    /// The dex soft-failed the swap with this message. The changes
+    /// the dex made to its storage are kept.
+    SoftFailed(String),
+    /// The swap failed and left no changes behind.
+    Failed(String),
+}
+
 #[derive(Clone)]
 #[cfg_attr(debug_assertions, derive(Debug))]
 #[near(serializers=[json])]
@@ -46,10 +56,12 @@ pub enum Operation {
         asset_ids: Vec<AssetId>,
         r#for: Option<AccountOrDexId>,
     },
-    /// Deploy new code to your dex.
+    /// Deploy the code of a new dex. See `deploy_dex_code` for
+    /// `config`.
     DeployDexCode {
         last_part_of_id: String,
         code_base64: Base64VecU8,
+        config: Option<Base64VecU8>,
     },
     /// Withdraw assets from the dex engine contract's inner
     /// balance to the user. If amount is None, the entire
@@ -63,6 +75,10 @@ pub enum Operation {
         /// refunded to this address. It's required that either
         /// the user address or rescue address is registered.
         rescue_address: Option<AccountId>,
+        /// Gas for the transfer call instead of the default, for
+        /// tokens with expensive transfer methods. Not allowed
+        /// for near.
+        transfer_gas: Option<Gas>,
     },
     /// Swap assets between two assets on the selected dex.
     SwapSimple {
@@ -71,6 +87,12 @@ pub enum Operation {
         asset_in: AssetId,
         asset_out: AssetId,
         amount: SwapOperationAmount,
+        /// Fails the batch if the swap gives less, same as in
+        /// `swap_simple`.
+        min_amount_out: Option<U128>,
+        /// Fails the batch if the swap takes more, same as in
+        /// `swap_simple`.
+        max_amount_in: Option<U128>,
     },
     /// Call a method on a dex.
     DexCall {
@@ -93,21 +115,423 @@ pub enum Operation {
     },
 }
 
+/// Maximum number of dex invocations (swaps and dex calls)
+/// in a single batch of operations. Limits the length of
+/// routes, including cyclic ones, so that a batch is cut off
+/// early with a clear error instead of running out of gas
+/// somewhere in the middle.
+pub const MAX_DEX_INVOCATIONS_PER_BATCH: usize = 8;
+
+/// Maximum length of the referral of a swap, in bytes. Referrals
+/// are only logged, but still shouldn't bloat the logs.
+pub const MAX_REFERRAL_LEN: usize = 64;
+
+/// Default amount of fuel available to a single dex invocation,
+/// see `ResourceLimits::fuel`. Roughly
+/// corresponds to the number of executed wasm instructions. Kept
+/// low enough for a dex to run out of fuel before the transaction
+/// runs out of gas, so that such failures are reported clearly.
+pub const DEX_FUEL_LIMIT: u64 = 5_000_000;
+
+fn dex_wasm_engine(limits: &ResourceLimits) -> Engine {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    config
+        .set_max_recursion_depth(usize::try_from(limits.max_recursion_depth).unwrap_or(usize::MAX));
+    // Dex code is untrusted, so modules with unreasonable numbers
+    // of functions, globals, segments, etc. are rejected before
+    // they are compiled
+    config.enforced_limits(EnforcedLimits::strict());
+    Engine::new(&config)
+}
+
+/// Why dex code couldn't be loaded.
+pub enum ModuleError {
+    /// The code is not valid wasm. The dex developer has to fix
+    /// it, retrying won't help.
+    Invalid(wasmi::Error),
+    /// The code is valid wasm, but the engine couldn't compile
+    /// it, for example because it exceeds the engine limits.
+    Compilation(wasmi::Error),
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(err) => write!(f, "Invalid dex code: {err}"),
+            Self::Compilation(err) => write!(f, "Failed to compile dex code: {err}"),
+        }
+    }
+}
+
+/// Why the engine rejects a swap before invoking the dex, see
+/// `preflight`. `Display` gives the message the swap fails with.
+#[derive(Clone, PartialEq, Eq)]
+#[cfg_attr(debug_assertions, derive(Debug))]
+#[near(serializers=[json])]
+pub enum EngineError {
+    DexNotFound {
+        dex_id: DexId,
+    },
+    /// `allow_swap_deposits` is on and nothing is attached.
+    DepositRequired,
+    /// `allow_swap_deposits` is off and the deposit is not 1 yocto.
+    ExactlyOneYoctoRequired,
+    /// The asset is the wrapped NEAR token, which dexes see in
+    /// place of native NEAR.
+    WrappedNear {
+        asset_id: AssetId,
+    },
+    AssetNotSupported {
+        dex_id: DexId,
+        asset_id: AssetId,
+    },
+    SwapsPerBlockLimitReached {
+        dex_id: DexId,
+        max_swaps_per_block: u32,
+    },
+    BelowMinSwapAmount {
+        asset_id: AssetId,
+        min_swap_amount: U128,
+    },
+    /// The request failed `SwapRequest::validate`.
+    InvalidRequest {
+        reason: String,
+    },
+    AssetNotRegistered {
+        account_id: AccountId,
+        asset_id: AssetId,
+    },
+    InsufficientBalance {
+        account_id: AccountId,
+        asset_id: AssetId,
+        balance: U128,
+        amount: U128,
+    },
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DexNotFound { dex_id } => write!(f, "Dex {dex_id} not found"),
+            Self::DepositRequired => {
+                write!(f, "Requires attached deposit of at least 1 yoctoNEAR")
+            }
+            Self::ExactlyOneYoctoRequired => {
+                write!(f, "Requires attached deposit of exactly 1 yoctoNEAR")
+            }
+            Self::WrappedNear { asset_id } => write!(
+                f,
+                "{asset_id} is accounted as native NEAR by dexes, use near instead"
+            ),
+            Self::AssetNotSupported { dex_id, asset_id } => {
+                write!(f, "Asset {asset_id} is not supported by dex {dex_id}")
+            }
+            Self::SwapsPerBlockLimitReached {
+                dex_id,
+                max_swaps_per_block,
+            } => write!(
+                f,
+                "Dex {dex_id} reached the limit of {max_swaps_per_block} swaps per block"
+            ),
+            Self::BelowMinSwapAmount {
+                asset_id,
+                min_swap_amount,
+            } => write!(
+                f,
+                "Swap amount is below minimum of {} for {asset_id}",
+                min_swap_amount.0
+            ),
+            Self::InvalidRequest { reason } => write!(f, "Invalid swap request: {reason}"),
+            Self::AssetNotRegistered {
+                account_id,
+                asset_id,
+            } => write!(f, "Asset {asset_id} is not registered for {account_id}"),
+            Self::InsufficientBalance {
+                account_id,
+                asset_id,
+                balance,
+                amount,
+            } => write!(
+                f,
+                "Insufficient balance of {asset_id} for {account_id}: {} < {}",
+                balance.0, amount.0
+            ),
+        }
+    }
+}
+
+fn load_dex_module(engine: &Engine, code: &[u8]) -> Result<Module, ModuleError> {
+    Module::new(engine, code).map_err(|err| match err.kind() {
+        ErrorKind::Read(_) | ErrorKind::Wasm(_) => ModuleError::Invalid(err),
+        _ => ModuleError::Compilation(err),
+    })
+}
+
+/// Instantiates the dex module with the host functions, in a store
+/// with fuel and memory limited by `data.limits`, and calls its
+/// `export`. Returns the fuel used by the export, or the error
+/// prefixed with `context`. The store is returned either way, so
+/// that the caller can collect what the dex did before it failed.
+fn instantiate_dex<'a>(
+    engine: &Engine,
+    module: &Module,
+    data: RunnerData<'a>,
+    export: &str,
+    context: &str,
+) -> (Store<RunnerData<'a>>, Result<u64, String>) {
+    let dex_id = data.dex_id.clone();
+    let limits = data.limits;
+    let mut store = Store::new(engine, data);
+    store
+        .set_fuel(limits.fuel)
+        .expect("Fuel metering is not enabled");
+    store.limiter(|data| &mut data.store_limits);
+    let mut linker = Linker::new(engine);
+
+    impl_supported_host_functions!(linker);
+    impl_unsupported_host_functions!(linker);
+
+    let result = linker
+        .instantiate_and_start(&mut store, module)
+        .map_err(|err| dex_error(&dex_id, &limits, &err, "Failed to instantiate module"))
+        .and_then(|instance| {
+            instance
+                .get_func(&mut store, export)
+                .ok_or_else(|| format!("[{dex_id}] Dex is missing the {export} export"))
+        })
+        .and_then(|func: Func| {
+            let fuel_before = store.get_fuel().expect("Fuel metering is not enabled");
+            func.call(&mut store, &[], &mut [])
+                .map(|()| fuel_before.saturating_sub(store.get_fuel().unwrap_or(0)))
+                .map_err(|err| dex_error(&dex_id, &limits, &err, context))
+        });
+    (store, result)
+}
+
+/// Describes a failed dex invocation. If the dex exceeded one of
+/// the resource limits, the message names the resource, so that
+/// dex developers know what to optimize.
+fn dex_error(dex_id: &DexId, limits: &ResourceLimits, err: &wasmi::Error, context: &str) -> String {
+    match err.as_trap_code() {
+        Some(TrapCode::OutOfFuel) => {
+            return format!(
+                "[{dex_id}] Dex ran out of fuel, the limit is {}",
+                limits.fuel
+            );
+        }
+        Some(TrapCode::StackOverflow) => {
+            return format!(
+                "[{dex_id}] Dex stack overflow, the recursion limit is {}",
+                limits.max_recursion_depth
+            );
+        }
+        _ => (),
+    }
+    match err.kind() {
+        ErrorKind::Memory(MemoryError::ResourceLimiterDeniedAllocation) => format!(
+            "[{dex_id}] Dex exceeded the memory limit of {} bytes",
+            limits.memory_bytes
+        ),
+        _ => format!("{context}: {err:?}"),
+    }
+}
+
+/// Checks the `min_amount_out` and `max_amount_in` bounds the
+/// trader set on a swap.
+fn check_slippage(
+    response: &SwapResponse,
+    min_amount_out: Option<U128>,
+    max_amount_in: Option<U128>,
+) -> Result<(), String> {
+    if let Some(min_amount_out) = min_amount_out {
+        if response.amount_out < min_amount_out {
+            return Err(format!(
+                "Slippage exceeded: amount out {} is less than the minimum of {}",
+                response.amount_out.0, min_amount_out.0
+            ));
+        }
+    }
+    if let Some(max_amount_in) = max_amount_in {
+        if response.amount_in > max_amount_in {
+            return Err(format!(
+                "Slippage exceeded: amount in {} is more than the maximum of {}",
+                response.amount_in.0, max_amount_in.0
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the `max_price_impact_bps` the trader set on a swap
+/// against the spot price before the swap of every pool of
+/// `asset_in` and `asset_out` in `pools_before_swap`. The request
+/// has the assets as the dex sees them, same as the reserves.
+///
+/// The reserves are reported by the dex itself, so this is a safety
+/// net against bugs of the dex, not against a malicious dex. If the
+/// dex didn't report the reserves of such a pool, there's nothing
+/// to check against, and the swap goes through.
+fn check_price_impact(
+    pools_before_swap: &[Vec<(AssetId, U128)>],
+    dex_swap_request: &SwapRequest,
+    response: &SwapResponse,
+    max_price_impact_bps: u16,
+) -> Result<(), String> {
+    let SwapRequest {
+        asset_in,
+        asset_out,
+        ..
+    } = dex_swap_request;
+    let reserve_of = |reserves: &[(AssetId, U128)], asset_id: &AssetId| {
+        reserves
+            .iter()
+            .find(|(reserve_asset_id, _)| reserve_asset_id == asset_id)
+            .map(|(_, reserve)| *reserve)
+    };
+    // The dex may have updated several pools of the pair, and only
+    // the dex knows which one it swapped through, so every one of
+    // them has to be within the limit
+    for reserves in pools_before_swap {
+        let (Some(reserve_in), Some(reserve_out)) = (
+            reserve_of(reserves, asset_in),
+            reserve_of(reserves, asset_out),
+        ) else {
+            continue;
+        };
+        let price_impact_bps = price_impact_bps(
+            reserve_in,
+            reserve_out,
+            response.amount_in,
+            response.amount_out,
+        )
+        .map_err(|err| format!("Price impact can't be checked: {err}"))?;
+        if price_impact_bps > max_price_impact_bps {
+            return Err(format!(
+                "Price impact exceeded: {price_impact_bps} bps is more than the maximum of {max_price_impact_bps} bps"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every swap that takes the output of the previous
+/// swap is connected to it, so that a malformed route is rejected
+/// before anything is executed.
+fn validate_route(operations: &[Operation]) {
+    let mut last_asset_out = None;
+    for (index, operation) in operations.iter().enumerate() {
+        if let Operation::SwapSimple {
+            asset_in,
+            asset_out,
+            amount,
+            ..
+        } = operation
+        {
+            if let SwapOperationAmount::OutputOfLastIn = amount {
+                match last_asset_out {
+                    Some(last_asset_out) => expect!(
+                        last_asset_out == asset_in,
+                        "Route is disconnected at operation {index}: it takes {asset_in}, but the previous swap gives {last_asset_out}"
+                    ),
+                    None => panic!("Amount is required for first SwapSimple operation"),
+                }
+            }
+            last_asset_out = Some(asset_out);
+        }
+    }
+}
+
 impl DexEngine {
     pub(crate) fn internal_deploy_dex_code(
         &mut self,
         last_part_of_id: String,
         code_base64: Base64VecU8,
+        config: Option<Base64VecU8>,
         deployer: AccountId,
     ) {
-        let code_hash = near_sdk::env::sha256_array(&code_base64.0);
         let dex_id = DexId {
             deployer,
             id: last_part_of_id,
         };
+        expect!(
+            !self.dex_codes.contains_key(&dex_id),
+            "Dex {dex_id} is already deployed, use upgrade_dex_code or redeploy_and_migrate instead"
+        );
+        expect!(
+            !self.removed_dexes.contains_key(&dex_id),
+            "Dex {dex_id} was removed, its id can't be deployed again"
+        );
+        self.internal_set_dex_code(dex_id.clone(), code_base64, config);
+
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let deployed_dexes = self
+            .deployed_dexes
+            .entry(dex_id.deployer.clone())
+            .or_default();
+        if let Err(index) = deployed_dexes.binary_search(&dex_id.id) {
+            deployed_dexes.insert(index, dex_id.id.clone());
+        }
+        self.deployed_dexes.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+    }
+
+    pub(crate) fn internal_upgrade_dex_code(
+        &mut self,
+        dex_id: DexId,
+        code_base64: Base64VecU8,
+        config: Option<Base64VecU8>,
+    ) {
+        expect!(
+            self.dex_codes.contains_key(&dex_id),
+            "Dex {dex_id} is not deployed yet, use deploy_dex_code instead"
+        );
+        self.internal_set_dex_code(dex_id, code_base64, config)
+    }
+
+    fn internal_set_dex_code(
+        &mut self,
+        dex_id: DexId,
+        code_base64: Base64VecU8,
+        config: Option<Base64VecU8>,
+    ) {
+        let module = match load_dex_module(&dex_wasm_engine(&self.resource_limits), &code_base64.0)
+        {
+            Ok(module) => module,
+            Err(err) => panic!("{err}"),
+        };
+        match module.get_export("swap") {
+            Some(ExternType::Func(func_type)) => expect!(
+                func_type.params().is_empty() && func_type.results().is_empty(),
+                "The swap function of the dex code must take no parameters and return nothing, got {:?} -> {:?}",
+                func_type.params(),
+                func_type.results()
+            ),
+            _ => panic!("Dex code doesn't export a swap function"),
+        }
+        // Requests and responses are passed through the memory of
+        // the dex, so without it every call would fail inside a
+        // host function
+        expect!(
+            matches!(module.get_export("memory"), Some(ExternType::Memory(_))),
+            "Dex code doesn't export its memory as \"memory\""
+        );
+        drop(module);
+
+        let code_hash = near_sdk::env::sha256_array(&code_base64.0);
         let storage_usage_before = near_sdk::env::storage_usage();
         self.dex_codes.insert(dex_id.clone(), code_base64.0);
         self.dex_codes.flush();
+        if let Some(config) = config {
+            if config.0.is_empty() {
+                self.dex_configs.remove(&dex_id);
+            } else {
+                self.dex_configs.insert(dex_id.clone(), config.0);
+            }
+            self.dex_configs.flush();
+        }
         let storage_usage_after = near_sdk::env::storage_usage();
         self.dex_storage_balances
             .charge(&dex_id, storage_usage_before, storage_usage_after);
@@ -119,6 +543,368 @@ impl DexEngine {
         .emit();
     }
 
+    /// Deploys new code for an existing dex, and calls its `migrate`
+    /// export, if there is one, to convert the dex storage to the
+    /// new layout. If the migration fails, the whole upgrade is
+    /// reverted, including the code.
+    /// `owner` is the predecessor the `migrate` export sees.
+    pub(crate) fn internal_redeploy_and_migrate(
+        &mut self,
+        dex_id: DexId,
+        code_base64: Base64VecU8,
+        owner: AccountId,
+    ) {
+        self.internal_upgrade_dex_code(dex_id.clone(), code_base64, None);
+
+        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
+        let engine = dex_wasm_engine(&self.resource_limits);
+        let module = match load_dex_module(&engine, code) {
+            Ok(module) => module,
+            Err(err) => panic!("{err}"),
+        };
+        if !matches!(module.get_export("migrate"), Some(ExternType::Func(_))) {
+            return;
+        }
+
+        let context = self.internal_dex_context(&dex_id);
+        let storage_usage_before = context.storage_usage_before;
+        let (mut store, result) = instantiate_dex(
+            &engine,
+            &module,
+            RunnerData::new(
+                dex_id.clone(),
+                vec![],
+                CallType::Call {
+                    dex_storage_mut: &mut self.dex_storage,
+                    predecessor_id: owner,
+                    is_authorized: true,
+                },
+                &self.dex_storage_balances,
+                &self.dex_configs,
+                context,
+            ),
+            "migrate",
+            &format!("[{dex_id}] Migration failed"),
+        );
+        if let Err(err) = result {
+            panic!("{err}");
+        }
+        let pool_count = store.data().pool_count;
+        let event_sequence = store.data().event_sequence;
+        let pending_events = std::mem::take(&mut store.data_mut().pending_events);
+        drop(store);
+
+        self.internal_set_dex_pool_count(&dex_id, pool_count);
+        self.internal_set_dex_event_sequence(&dex_id, event_sequence);
+        self.dex_storage.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+        for event in pending_events {
+            event.emit();
+        }
+    }
+
+    /// Compiles the code of the dex.
+    fn internal_load_dex(&self, dex_id: &DexId) -> Result<(Engine, Module), String> {
+        let code = self
+            .dex_codes
+            .get(dex_id)
+            .ok_or_else(|| format!("Dex {dex_id} not found"))?;
+        let engine = dex_wasm_engine(&self.resource_limits);
+        let module = load_dex_module(&engine, code).map_err(|err| err.to_string())?;
+        Ok((engine, module))
+    }
+
+    /// What an invocation of the dex needs to know about it. Must
+    /// be called right before the invocation, since it records the
+    /// storage usage the dex is charged from.
+    fn internal_dex_context(&self, dex_id: &DexId) -> DexContext {
+        DexContext {
+            storage_usage_before: near_sdk::env::storage_usage(),
+            debug: self.internal_dex_debug(dex_id),
+            fuel_reserve: self.internal_dex_fuel_reserve(dex_id),
+            pool_count: self.internal_dex_pool_count(dex_id),
+            near_balance: self.internal_dex_near_balance(dex_id),
+            event_sequence: self.internal_dex_event_sequence(dex_id),
+            max_pools: self.max_pools_per_dex,
+            limits: self.resource_limits,
+        }
+    }
+
+    pub(crate) fn internal_is_dex_deployed(&self, dex_id: &DexId) -> bool {
+        self.deployed_dexes
+            .get(&dex_id.deployer)
+            .is_some_and(|ids| ids.binary_search(&dex_id.id).is_ok())
+    }
+
+    /// The account that controls the dex, or `None` if the dex
+    /// isn't deployed.
+    pub(crate) fn internal_dex_owner(&self, dex_id: &DexId) -> Option<AccountId> {
+        if !self.internal_is_dex_deployed(dex_id) {
+            return None;
+        }
+        Some(
+            self.dex_metadata
+                .get(dex_id)
+                .and_then(|metadata| metadata.owner.clone())
+                .unwrap_or_else(|| dex_id.deployer.clone()),
+        )
+    }
+
+    pub(crate) fn assert_dex_owner(&self, dex_id: &DexId, account_id: &AccountId) {
+        let owner = self.internal_dex_owner(dex_id);
+        expect!(
+            owner.is_some() || self.removed_dexes.contains_key(dex_id),
+            "Dex {dex_id} is not deployed yet, use deploy_dex_code instead"
+        );
+        expect!(owner.is_some(), "Dex {dex_id} is not deployed");
+        expect!(
+            owner.as_ref() == Some(account_id),
+            "Only the owner of dex {dex_id} can do this"
+        );
+    }
+
+    /// Like `assert_dex_owner`, but also lets the last owner of a
+    /// removed dex through, so that it can withdraw what's left.
+    pub(crate) fn assert_dex_or_removed_dex_owner(&self, dex_id: &DexId, account_id: &AccountId) {
+        let owner = self
+            .internal_dex_owner(dex_id)
+            .or_else(|| self.removed_dexes.get(dex_id).cloned());
+        expect!(owner.is_some(), "Dex {dex_id} is not deployed");
+        expect!(
+            owner.as_ref() == Some(account_id),
+            "Only the owner of dex {dex_id} can do this"
+        );
+    }
+
+    pub(crate) fn internal_dex_debug(&self, dex_id: &DexId) -> bool {
+        self.dex_metadata
+            .get(dex_id)
+            .is_some_and(|metadata| metadata.debug)
+    }
+
+    pub(crate) fn internal_dex_fuel_reserve(&self, dex_id: &DexId) -> u64 {
+        self.dex_metadata
+            .get(dex_id)
+            .map_or(0, |metadata| metadata.fuel_reserve)
+    }
+
+    pub(crate) fn internal_dex_pool_count(&self, dex_id: &DexId) -> u32 {
+        self.dex_pool_counts.get(dex_id).copied().unwrap_or(0)
+    }
+
+    /// NEAR balance of the dex in the engine, reported to the dex
+    /// by `account_balance`.
+    pub(crate) fn internal_dex_near_balance(&self, dex_id: &DexId) -> u128 {
+        self.dex_balances
+            .get(&(dex_id.clone(), AssetId::Near))
+            .map_or(0, |balance| balance.0)
+    }
+
+    /// Stores the pool count reported by the dex while it ran.
+    /// Called before the dex storage is charged, so that the
+    /// counter is paid for by the dex.
+    fn internal_set_dex_pool_count(&mut self, dex_id: &DexId, pool_count: u32) {
+        if self.internal_dex_pool_count(dex_id) != pool_count {
+            self.dex_pool_counts.insert(dex_id.clone(), pool_count);
+            self.dex_pool_counts.flush();
+        }
+    }
+
+    pub(crate) fn internal_dex_event_sequence(&self, dex_id: &DexId) -> u64 {
+        self.dex_event_sequences.get(dex_id).copied().unwrap_or(0)
+    }
+
+    /// Stores the sequence number of the next event of the dex.
+    /// Like the pool count, called before the dex storage is
+    /// charged.
+    fn internal_set_dex_event_sequence(&mut self, dex_id: &DexId, event_sequence: u64) {
+        if self.internal_dex_event_sequence(dex_id) != event_sequence {
+            self.dex_event_sequences
+                .insert(dex_id.clone(), event_sequence);
+            self.dex_event_sequences.flush();
+        }
+    }
+
+    pub(crate) fn internal_set_dex_debug(&mut self, dex_id: DexId, debug: bool) {
+        let storage_usage_before = near_sdk::env::storage_usage();
+        self.dex_metadata.entry(dex_id.clone()).or_default().debug = debug;
+        self.dex_metadata.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+    }
+
+    pub(crate) fn internal_set_dex_fuel_reserve(&mut self, dex_id: DexId, fuel_reserve: u64) {
+        let storage_usage_before = near_sdk::env::storage_usage();
+        self.dex_metadata
+            .entry(dex_id.clone())
+            .or_default()
+            .fuel_reserve = fuel_reserve;
+        self.dex_metadata.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+    }
+
+    pub(crate) fn internal_set_dex_allow_soft_fail(&mut self, dex_id: DexId, allow: bool) {
+        let storage_usage_before = near_sdk::env::storage_usage();
+        self.dex_metadata
+            .entry(dex_id.clone())
+            .or_default()
+            .allow_soft_fail = allow;
+        self.dex_metadata.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+    }
+
+    pub(crate) fn internal_transfer_dex_ownership(&mut self, dex_id: DexId, new_owner: AccountId) {
+        let old_owner = self
+            .internal_dex_owner(&dex_id)
+            .expect("Dex is not deployed");
+        let storage_usage_before = near_sdk::env::storage_usage();
+        self.dex_metadata.entry(dex_id.clone()).or_default().owner = Some(new_owner.clone());
+        self.dex_metadata.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+
+        IntearDexEvent::DexOwnershipTransferred {
+            dex_id,
+            old_owner,
+            new_owner,
+        }
+        .emit();
+    }
+
+    /// Removes the code of a dex and the data the dex paid storage
+    /// for. Fails if the dex still holds any asset, so that no
+    /// liquidity is stranded. The dex storage can't be enumerated,
+    /// so it's kept, along with the registrations of the assets,
+    /// which were paid for by whoever registered them. The owner is
+    /// recorded in `removed_dexes` instead, so that it keeps
+    /// control over the leftovers, and a new dex under the same id
+    /// doesn't inherit the old storage.
+    pub(crate) fn internal_remove_dex(&mut self, dex_id: DexId) {
+        expect!(
+            self.dex_codes.contains_key(&dex_id),
+            "Dex {dex_id} is not deployed"
+        );
+        for asset_id in self.dex_assets.get(&dex_id).into_iter().flatten() {
+            let balance = self
+                .dex_balances
+                .get(&(dex_id.clone(), asset_id.clone()))
+                .copied()
+                .unwrap_or(U128(0));
+            expect!(
+                balance.0 == 0,
+                "Dex {dex_id} still holds {} of {asset_id}, it has to be withdrawn first",
+                balance.0
+            );
+        }
+
+        let storage_usage_before = near_sdk::env::storage_usage();
+        let owner = self
+            .internal_dex_owner(&dex_id)
+            .expect("Dex is not deployed");
+        self.removed_dexes.insert(dex_id.clone(), owner);
+        self.removed_dexes.flush();
+        self.dex_codes.remove(&dex_id);
+        self.dex_codes.flush();
+        self.dex_configs.remove(&dex_id);
+        self.dex_configs.flush();
+        self.dex_metadata.remove(&dex_id);
+        self.dex_metadata.flush();
+        self.dex_swaps_in_block.remove(&dex_id);
+        self.dex_swaps_in_block.flush();
+        self.last_swaps.remove(&dex_id);
+        self.last_swaps.flush();
+        self.dex_pool_counts.remove(&dex_id);
+        self.dex_pool_counts.flush();
+        self.dex_event_sequences.remove(&dex_id);
+        self.dex_event_sequences.flush();
+        if let Some(deployed_dexes) = self.deployed_dexes.get_mut(&dex_id.deployer) {
+            if let Ok(index) = deployed_dexes.binary_search(&dex_id.id) {
+                deployed_dexes.remove(index);
+            }
+            if deployed_dexes.is_empty() {
+                self.deployed_dexes.remove(&dex_id.deployer);
+            }
+        }
+        self.deployed_dexes.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+
+        IntearDexEvent::DexRemoved { dex_id }.emit();
+    }
+
+    pub(crate) fn internal_set_dex_supported_assets(
+        &mut self,
+        dex_id: DexId,
+        supported_assets: Vec<AssetId>,
+    ) {
+        let storage_usage_before = near_sdk::env::storage_usage();
+        self.dex_metadata
+            .entry(dex_id.clone())
+            .or_default()
+            .supported_assets = supported_assets;
+        self.dex_metadata.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+    }
+
+    pub(crate) fn internal_set_dex_max_swaps_per_block(
+        &mut self,
+        dex_id: DexId,
+        max_swaps_per_block: Option<u32>,
+    ) {
+        let storage_usage_before = near_sdk::env::storage_usage();
+        self.dex_metadata
+            .entry(dex_id.clone())
+            .or_default()
+            .max_swaps_per_block = max_swaps_per_block;
+        self.dex_metadata.flush();
+        // The counter is created here, so that the dex pays for it
+        // upfront and swaps only overwrite it with the same size
+        if max_swaps_per_block.is_some() {
+            self.dex_swaps_in_block
+                .entry(dex_id.clone())
+                .or_insert((0, 0));
+        } else {
+            self.dex_swaps_in_block.remove(&dex_id);
+        }
+        self.dex_swaps_in_block.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(&dex_id, storage_usage_before, storage_usage_after);
+    }
+
+    fn internal_count_swap_in_block(&mut self, dex_id: &DexId, max_swaps_per_block: u32) {
+        let block_height = near_sdk::env::block_height();
+        let (last_block_height, swaps) = self
+            .dex_swaps_in_block
+            .entry(dex_id.clone())
+            .or_insert((block_height, 0));
+        if *last_block_height != block_height {
+            *last_block_height = block_height;
+            *swaps = 0;
+        }
+        expect!(
+            *swaps < max_swaps_per_block,
+            "Dex {dex_id} reached the limit of {max_swaps_per_block} swaps per block"
+        );
+        *swaps = swaps.checked_add(1).expect("Swap count overflow");
+    }
+
+    /// Returns `Err` with the message if the dex soft-failed the
+    /// swap. In this case, storage changes of the dex are kept, but
+    /// no assets are moved. Panics on any other failure, see
+    /// `internal_try_swap_simple`.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn internal_swap_simple(
         &mut self,
         dex_id: DexId,
@@ -126,80 +912,198 @@ impl DexEngine {
         asset_in: AssetId,
         asset_out: AssetId,
         amount: SwapRequestAmount,
-        mut trader: TradeAccount,
-    ) -> (U128, U128) {
+        min_amount_out: Option<U128>,
+        max_amount_in: Option<U128>,
+        max_price_impact_bps: Option<u16>,
+        trader: TradeAccount,
+        referral: Option<String>,
+    ) -> Result<(U128, U128), String> {
         let swap_request = SwapRequest {
             message,
             asset_in,
             asset_out,
             amount,
         };
+        self.internal_try_swap_simple(
+            dex_id,
+            swap_request,
+            min_amount_out,
+            max_amount_in,
+            max_price_impact_bps,
+            trader,
+            referral,
+        )
+        .map_err(|failure| match failure {
+            SwapFailure::SoftFailed(message) => message,
+            SwapFailure::Failed(err) => near_sdk::env::panic_str(&err),
+        })
+    }
+
+    /// Stores the swap as the last swap of the dex and emits the
+    /// swap event.
+    #[allow(clippy::too_many_arguments)]
+    fn internal_record_swap(
+        &mut self,
+        dex_id: &DexId,
+        swap_request: SwapRequest,
+        amount_in: U128,
+        amount_out: U128,
+        refunds: Vec<(AssetId, U128)>,
+        trader: AccountId,
+        referral: Option<String>,
+        fuel_used: u64,
+    ) {
+        let storage_usage_before = near_sdk::env::storage_usage();
+        self.last_swaps.insert(
+            dex_id.clone(),
+            LastSwap {
+                request: swap_request.clone(),
+                amount_in,
+                amount_out,
+                trader: trader.clone(),
+                block_height: near_sdk::env::block_height(),
+            },
+        );
+        self.last_swaps.flush();
+        let sequence = self.internal_dex_event_sequence(dex_id);
+        self.internal_set_dex_event_sequence(
+            dex_id,
+            sequence.checked_add(1).expect("Event sequence overflow"),
+        );
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(dex_id, storage_usage_before, storage_usage_after);
+
+        IntearDexEvent::Swap {
+            dex_id: dex_id.clone(),
+            request: swap_request,
+            amount_in,
+            amount_out,
+            refunds,
+            trader,
+            sequence,
+            referral,
+            fuel_used,
+        }
+        .emit();
+    }
 
-        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
-        let engine = Engine::default();
-        let module = match Module::new(&engine, code) {
-            Ok(module) => module,
-            Err(err) => panic!("Failed to load module: {err:?}"),
-        };
+    /// Same as `internal_swap_simple`, but failures of the dex or
+    /// of the swap itself, such as a dex panic, running out of
+    /// fuel, or insufficient balance, are returned as `Err` and
+    /// leave no changes behind, except for the storage of a dex
+    /// that soft-failed the swap. Failures of the engine, such as
+    /// the dex exceeding its storage balance, still panic.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn internal_try_swap_simple(
+        &mut self,
+        dex_id: DexId,
+        swap_request: SwapRequest,
+        min_amount_out: Option<U128>,
+        max_amount_in: Option<U128>,
+        max_price_impact_bps: Option<u16>,
+        mut trader: TradeAccount,
+        referral: Option<String>,
+    ) -> Result<(U128, U128), SwapFailure> {
+        let (engine, module) = self
+            .internal_load_dex(&dex_id)
+            .map_err(SwapFailure::Failed)?;
 
-        let storage_usage_before = near_sdk::env::storage_usage();
-        let mut store = Store::new(
+        self.internal_check_swap_request(&dex_id, &swap_request)
+            .map_err(|err| SwapFailure::Failed(err.to_string()))?;
+        let (max_swaps_per_block, allow_soft_fail) = self
+            .dex_metadata
+            .get(&dex_id)
+            .map_or((None, false), |metadata| {
+                (metadata.max_swaps_per_block, metadata.allow_soft_fail)
+            });
+        let min_swap_amount = self.min_swap_amounts.get(&swap_request.asset_in).copied();
+
+        // Writes of the dex stay in memory, and are written to the
+        // dex storage only if the swap succeeds
+        let mut scratch_storage = ScratchStorage::new();
+        let context = self.internal_dex_context(&dex_id);
+        let storage_usage_before = context.storage_usage_before;
+        let dex_swap_request = self.internal_swap_request_for_dex(&swap_request);
+        let (mut store, result) = instantiate_dex(
             &engine,
-            RunnerData {
-                request: near_sdk::borsh::to_vec(&swap_request)
+            &module,
+            RunnerData::new(
+                dex_id.clone(),
+                near_sdk::borsh::to_vec(&dex_swap_request)
                     .expect("Failed to serialize swap request"),
-                response: None,
-                registers: HashMap::new(),
-                call_type: CallType::Trade {
-                    dex_storage_mut: &mut self.dex_storage,
+                CallType::Trade {
+                    dex_storage: &self.dex_storage,
+                    scratch_storage: &mut scratch_storage,
                 },
-                dex_id: dex_id.clone(),
-                dex_storage_balances: &self.dex_storage_balances,
-                dex_storage_usage_before_transaction: storage_usage_before,
-            },
+                &self.dex_storage_balances,
+                &self.dex_configs,
+                context,
+            ),
+            "swap",
+            &format!("[{dex_id}] Dex failed"),
         );
-        let mut linker = Linker::new(&engine);
-
-        impl_supported_host_functions!(linker);
-        impl_unsupported_host_functions!(linker);
-
-        let instance = match linker.instantiate_and_start(&mut store, &module) {
-            Ok(i) => i,
-            Err(err) => panic!("Failed to instantiate module: {err:?}"),
-        };
-        let swap_func: Func = match instance.get_func(&mut store, "swap") {
-            Some(f) => f,
-            None => panic!("Failed to get function"),
-        };
-        match swap_func.call(&mut store, &[], &mut []) {
-            Ok(()) => (),
-            Err(err) => panic!("Failed to call function: {err:?}"),
-        };
         let response = store.data_mut().response.take();
+        let pool_count = store.data().pool_count;
+        let event_sequence = store.data().event_sequence;
+        let pending_events = std::mem::take(&mut store.data_mut().pending_events);
+        let soft_fail = store.data_mut().soft_fail.take();
         drop(store);
-        drop(linker);
-
-        self.dex_storage.flush();
-        let storage_usage_after = near_sdk::env::storage_usage();
-        self.dex_storage_balances
-            .charge(&dex_id, storage_usage_before, storage_usage_after);
 
-        let response: SwapResponse = match response {
-            Some(response) => {
-                near_sdk::borsh::from_slice(&response).expect("Failed to deserialize swap response")
+        if let (Err(_), Some(message), true) = (&result, soft_fail, allow_soft_fail) {
+            if let Some(max_swaps_per_block) = max_swaps_per_block {
+                self.internal_count_swap_in_block(&dex_id, max_swaps_per_block);
             }
-            None => panic!("No response from swap"),
-        };
-        match swap_request.amount {
-            SwapRequestAmount::ExactIn(exact_in) => {
-                expect!(exact_in == response.amount_in, "Amount in does not match");
+            self.internal_commit_scratch_storage(
+                &dex_id,
+                scratch_storage,
+                pool_count,
+                event_sequence,
+                storage_usage_before,
+            );
+            for event in pending_events {
+                event.emit();
             }
-            SwapRequestAmount::ExactOut(exact_out) => {
-                expect!(
-                    exact_out == response.amount_out,
-                    "Amount out does not match"
-                );
+            return Err(SwapFailure::SoftFailed(message));
+        }
+        let checked = result.and_then(|fuel_used| {
+            let response = SwapResponse::from_dex_bytes(
+                &response.ok_or_else(|| "No response from swap".to_string())?,
+            )
+            .map_err(|err| format!("Failed to deserialize swap response: {err}"))?;
+            let response = self.internal_swap_response_from_dex(response);
+            self.internal_check_swap_response(
+                &dex_id,
+                &swap_request,
+                &response,
+                min_swap_amount,
+                &trader,
+            )?;
+            check_slippage(&response, min_amount_out, max_amount_in)?;
+            if let Some(max_price_impact_bps) = max_price_impact_bps {
+                check_price_impact(
+                    &self.internal_pools_before_swap(&dex_id, &scratch_storage)?,
+                    &dex_swap_request,
+                    &response,
+                    max_price_impact_bps,
+                )?;
             }
+            Ok((response, fuel_used))
+        });
+        let (response, fuel_used) = checked.map_err(SwapFailure::Failed)?;
+
+        if let Some(max_swaps_per_block) = max_swaps_per_block {
+            self.internal_count_swap_in_block(&dex_id, max_swaps_per_block);
+        }
+        self.internal_commit_scratch_storage(
+            &dex_id,
+            scratch_storage,
+            pool_count,
+            event_sequence,
+            storage_usage_before,
+        );
+        for event in pending_events {
+            event.emit();
         }
 
         match &mut trader {
@@ -218,6 +1122,15 @@ impl DexEngine {
                     swap_request.asset_out.clone(),
                     response.amount_out,
                 );
+                // refunds
+                for (asset_id, amount) in &response.refunds {
+                    self.internal_transfer_asset(
+                        AccountOrDexId::Dex(dex_id.clone()),
+                        AccountOrDexId::Account(user_trader.clone()),
+                        asset_id.clone(),
+                        *amount,
+                    );
+                }
             }
             TradeAccount::Sandboxed { assets, .. } => {
                 // asset in
@@ -245,21 +1158,339 @@ impl DexEngine {
                     .0
                     .checked_add(response.amount_out.0)
                     .expect("Balance overflow");
+                // refunds
+                for (asset_id, amount) in &response.refunds {
+                    self.internal_decrease_assets(
+                        AccountOrDexId::Dex(dex_id.clone()),
+                        asset_id.clone(),
+                        *amount,
+                    );
+                    let anon_swap_balance = assets.entry(asset_id.clone()).or_default();
+                    anon_swap_balance.0 = anon_swap_balance
+                        .0
+                        .checked_add(amount.0)
+                        .expect("Balance overflow");
+                }
             }
         }
-        IntearDexEvent::Swap {
-            dex_id: dex_id.clone(),
-            request: swap_request,
-            amount_in: response.amount_in,
-            amount_out: response.amount_out,
-            trader: match trader {
-                TradeAccount::User(account) => account,
-                TradeAccount::Sandboxed { alleged_trader, .. } => alleged_trader,
-            },
+        let trader = match trader {
+            TradeAccount::User(account) => account,
+            TradeAccount::Sandboxed { alleged_trader, .. } => alleged_trader,
+        };
+
+        self.internal_record_swap(
+            &dex_id,
+            swap_request,
+            response.amount_in,
+            response.amount_out,
+            response.refunds,
+            trader,
+            referral,
+            fuel_used,
+        );
+
+        Ok((response.amount_in, response.amount_out))
+    }
+
+    /// The asset a dex sees in place of an asset of the engine,
+    /// see `wrap_near`.
+    fn internal_asset_for_dex(&self, asset_id: AssetId) -> AssetId {
+        match (&self.wrap_near, asset_id) {
+            (Some(wrap_near), AssetId::Near) => AssetId::Nep141(wrap_near.clone()),
+            (_, asset_id) => asset_id,
+        }
+    }
+
+    /// The asset of the engine that a dex refers to, the reverse
+    /// of `internal_asset_for_dex`.
+    fn internal_asset_from_dex(&self, asset_id: AssetId) -> AssetId {
+        match &self.wrap_near {
+            Some(wrap_near) if asset_id == AssetId::Nep141(wrap_near.clone()) => AssetId::Near,
+            _ => asset_id,
+        }
+    }
+
+    fn internal_swap_request_for_dex(&self, swap_request: &SwapRequest) -> SwapRequest {
+        SwapRequest {
+            asset_in: self.internal_asset_for_dex(swap_request.asset_in.clone()),
+            asset_out: self.internal_asset_for_dex(swap_request.asset_out.clone()),
+            ..swap_request.clone()
+        }
+    }
+
+    fn internal_swap_response_from_dex(&self, response: SwapResponse) -> SwapResponse {
+        SwapResponse {
+            refunds: response
+                .refunds
+                .into_iter()
+                .map(|(asset_id, amount)| (self.internal_asset_from_dex(asset_id), amount))
+                .collect(),
+            ..response
+        }
+    }
+
+    /// Wrapped NEAR can't be traded directly while it's accounted
+    /// as native NEAR, otherwise a dex would see two balances as
+    /// one asset.
+    pub(crate) fn internal_check_not_wrap_near(
+        &self,
+        asset_id: &AssetId,
+    ) -> Result<(), EngineError> {
+        match &self.wrap_near {
+            Some(wrap_near) if asset_id == &AssetId::Nep141(wrap_near.clone()) => {
+                Err(EngineError::WrappedNear {
+                    asset_id: asset_id.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks of a swap request that the engine makes before
+    /// invoking the dex, except for the existence of the dex.
+    fn internal_check_swap_request(
+        &self,
+        dex_id: &DexId,
+        swap_request: &SwapRequest,
+    ) -> Result<(), EngineError> {
+        self.internal_check_not_wrap_near(&swap_request.asset_in)?;
+        self.internal_check_not_wrap_near(&swap_request.asset_out)?;
+        if let Some(metadata) = self.dex_metadata.get(dex_id) {
+            for asset_id in [&swap_request.asset_in, &swap_request.asset_out] {
+                if !metadata.is_asset_supported(asset_id) {
+                    return Err(EngineError::AssetNotSupported {
+                        dex_id: dex_id.clone(),
+                        asset_id: asset_id.clone(),
+                    });
+                }
+            }
+            if let Some(max_swaps_per_block) = metadata.max_swaps_per_block {
+                let block_height = near_sdk::env::block_height();
+                if let Some((last_block_height, swaps)) = self.dex_swaps_in_block.get(dex_id) {
+                    if *last_block_height == block_height && *swaps >= max_swaps_per_block {
+                        return Err(EngineError::SwapsPerBlockLimitReached {
+                            dex_id: dex_id.clone(),
+                            max_swaps_per_block,
+                        });
+                    }
+                }
+            }
+        }
+        let min_swap_amount = self.min_swap_amounts.get(&swap_request.asset_in).copied();
+        if let (Some(min_swap_amount), SwapRequestAmount::ExactIn(amount_in)) =
+            (min_swap_amount, swap_request.amount)
+        {
+            if amount_in < min_swap_amount {
+                return Err(EngineError::BelowMinSwapAmount {
+                    asset_id: swap_request.asset_in.clone(),
+                    min_swap_amount,
+                });
+            }
+        }
+        swap_request
+            .validate()
+            .map_err(|reason| EngineError::InvalidRequest { reason })
+    }
+
+    /// Everything `swap_simple` checks before invoking the dex,
+    /// see `preflight`.
+    pub(crate) fn internal_preflight_swap(
+        &self,
+        dex_id: &DexId,
+        swap_request: &SwapRequest,
+        trader: &AccountId,
+        attached_deposit: NearToken,
+    ) -> Result<(), EngineError> {
+        if !self.dex_codes.contains_key(dex_id) {
+            return Err(EngineError::DexNotFound {
+                dex_id: dex_id.clone(),
+            });
+        }
+        if self.allow_swap_deposits {
+            if attached_deposit.is_zero() {
+                return Err(EngineError::DepositRequired);
+            }
+        } else if attached_deposit != NearToken::from_yoctonear(1) {
+            return Err(EngineError::ExactlyOneYoctoRequired);
+        }
+        self.internal_check_swap_request(dex_id, swap_request)?;
+        for asset_id in [&swap_request.asset_in, &swap_request.asset_out] {
+            if !self.asset_is_registered(AccountOrDexId::Account(trader.clone()), asset_id.clone())
+            {
+                return Err(EngineError::AssetNotRegistered {
+                    account_id: trader.clone(),
+                    asset_id: asset_id.clone(),
+                });
+            }
+        }
+        if let SwapRequestAmount::ExactIn(amount_in) = swap_request.amount {
+            let deposit = if self.allow_swap_deposits && swap_request.asset_in == AssetId::Near {
+                attached_deposit.as_yoctonear()
+            } else {
+                0
+            };
+            let balance_in = self
+                .user_balances
+                .get(&(trader.clone(), swap_request.asset_in.clone()))
+                .map_or(0, |balance| balance.0)
+                .saturating_add(deposit);
+            if balance_in < amount_in.0 {
+                return Err(EngineError::InsufficientBalance {
+                    account_id: trader.clone(),
+                    asset_id: swap_request.asset_in.clone(),
+                    balance: U128(balance_in),
+                    amount: amount_in,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves before a swap of the pools the dex updated with
+    /// `set_reserves` during the swap, for `check_price_impact`.
+    fn internal_pools_before_swap(
+        &self,
+        dex_id: &DexId,
+        scratch_storage: &ScratchStorage,
+    ) -> Result<Vec<Vec<(AssetId, U128)>>, String> {
+        scratch_storage
+            .keys()
+            .filter(|key| host_functions::is_reserves_storage_key(key))
+            .filter_map(|key| self.dex_storage.get(&(dex_id.clone(), key.clone())))
+            .map(|reserves| {
+                near_sdk::borsh::from_slice(reserves).map_err(|err| {
+                    format!("Price impact can't be checked: invalid reserves: {err}")
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the changes a swap kept in memory to the dex storage,
+    /// and charges the dex for them.
+    fn internal_commit_scratch_storage(
+        &mut self,
+        dex_id: &DexId,
+        scratch_storage: ScratchStorage,
+        pool_count: u32,
+        event_sequence: u64,
+        storage_usage_before: u64,
+    ) {
+        self.internal_set_dex_pool_count(dex_id, pool_count);
+        self.internal_set_dex_event_sequence(dex_id, event_sequence);
+        for (key, value) in scratch_storage {
+            match value {
+                Some(value) => self.dex_storage.insert((dex_id.clone(), key), value),
+                None => self.dex_storage.remove(&(dex_id.clone(), key)),
+            };
+        }
+        self.dex_storage.flush();
+        let storage_usage_after = near_sdk::env::storage_usage();
+        self.dex_storage_balances
+            .charge(dex_id, storage_usage_before, storage_usage_after);
+    }
+
+    /// Checks everything that `internal_swap_simple` would check
+    /// after the dex returned, including that all transfers of the
+    /// swap can be made.
+    fn internal_check_swap_response(
+        &self,
+        dex_id: &DexId,
+        swap_request: &SwapRequest,
+        response: &SwapResponse,
+        min_swap_amount: Option<U128>,
+        trader: &TradeAccount,
+    ) -> Result<(), String> {
+        match swap_request.amount {
+            SwapRequestAmount::ExactIn(exact_in) if exact_in != response.amount_in => {
+                return Err("Amount in does not match".to_string());
+            }
+            SwapRequestAmount::ExactOut(exact_out) if exact_out != response.amount_out => {
+                return Err("Amount out does not match".to_string());
+            }
+            _ => (),
+        }
+        if response.amount_out.0 == 0 {
+            return Err("Swap output is zero, amount in is too small".to_string());
+        }
+        if let Some(min_swap_amount) = min_swap_amount {
+            if response.amount_in < min_swap_amount {
+                return Err(format!(
+                    "Swap amount is below minimum of {} for {}",
+                    min_swap_amount.0, swap_request.asset_in
+                ));
+            }
         }
-        .emit();
 
-        (response.amount_in, response.amount_out)
+        let trader_balance_in = match trader {
+            TradeAccount::User(trader) => self
+                .user_balances
+                .get(&(trader.clone(), swap_request.asset_in.clone()))
+                .copied(),
+            TradeAccount::Sandboxed { assets, .. } => assets.get(&swap_request.asset_in).copied(),
+        }
+        .unwrap_or_default();
+        if trader_balance_in < response.amount_in {
+            return Err(match trader {
+                TradeAccount::User(trader) => format!(
+                    "Insufficient balance of {} for {trader}: {} < {}",
+                    swap_request.asset_in, trader_balance_in.0, response.amount_in.0
+                ),
+                TradeAccount::Sandboxed { .. } => format!(
+                    "Not enough input balance in anonymous assets: {} < {}",
+                    trader_balance_in.0, response.amount_in.0
+                ),
+            });
+        }
+        // Everything the dex pays out, by asset
+        let mut payouts: HashMap<&AssetId, u128> = HashMap::new();
+        for (asset_id, amount) in std::iter::once((&swap_request.asset_out, response.amount_out))
+            .chain(
+                response
+                    .refunds
+                    .iter()
+                    .map(|(asset_id, amount)| (asset_id, *amount)),
+            )
+        {
+            let payout = payouts.entry(asset_id).or_default();
+            *payout = payout
+                .checked_add(amount.0)
+                .ok_or_else(|| format!("Payout overflow for {asset_id}"))?;
+        }
+        for (asset_id, payout) in payouts {
+            // Sandboxed balances are kept in memory and don't need
+            // registration
+            if let TradeAccount::User(trader) = trader {
+                if !self
+                    .asset_is_registered(AccountOrDexId::Account(trader.clone()), asset_id.clone())
+                {
+                    return Err(format!("Asset {asset_id} is not registered for {trader}"));
+                }
+            }
+            let mut dex_balance = self
+                .dex_balances
+                .get(&(dex_id.clone(), asset_id.clone()))
+                .copied()
+                .unwrap_or_default()
+                .0;
+            if asset_id == &swap_request.asset_in {
+                dex_balance = dex_balance.saturating_add(response.amount_in.0);
+            }
+            if dex_balance < payout {
+                return Err(format!(
+                    "Insufficient balance for dex {dex_id} of {asset_id}: {dex_balance} < {payout}"
+                ));
+            }
+        }
+        if !self.asset_is_registered(
+            AccountOrDexId::Dex(dex_id.clone()),
+            swap_request.asset_in.clone(),
+        ) {
+            return Err(format!(
+                "Asset {} is not registered for dex {dex_id}",
+                swap_request.asset_in
+            ));
+        }
+        Ok(())
     }
 
     pub(crate) fn internal_dex_call(
@@ -275,7 +1506,16 @@ impl DexEngine {
             method != "swap",
             "Method name 'swap' is reserved for the swap operation"
         );
+        expect!(
+            method != "migrate",
+            "Method name 'migrate' is reserved for migrations"
+        );
 
+        for asset_id in attached_assets.keys() {
+            if let Err(err) = self.internal_check_not_wrap_near(asset_id) {
+                panic!("{err}");
+            }
+        }
         if anon_swap_available_assets.is_none() {
             for (asset_id, amount) in attached_assets.clone() {
                 self.assert_has_enough(
@@ -287,58 +1527,57 @@ impl DexEngine {
         }
 
         let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
-        let engine = Engine::default();
-        let module = match Module::new(&engine, code) {
+        let engine = dex_wasm_engine(&self.resource_limits);
+        let module = match load_dex_module(&engine, code) {
             Ok(module) => module,
-            Err(err) => panic!("Failed to load module: {err:?}"),
+            Err(err) => panic!("{err}"),
         };
 
-        let storage_usage_before = near_sdk::env::storage_usage();
         let request = DexCallRequest {
             args: args.0,
-            attached_assets,
+            attached_assets: attached_assets
+                .iter()
+                .map(|(asset_id, amount)| (self.internal_asset_for_dex(asset_id.clone()), *amount))
+                .collect(),
         };
-        let mut store = Store::new(
+        let context = self.internal_dex_context(&dex_id);
+        let storage_usage_before = context.storage_usage_before;
+        let (mut store, result) = instantiate_dex(
             &engine,
-            RunnerData {
-                request: near_sdk::borsh::to_vec(&request).expect("Failed to serialize request"),
-                response: None,
-                registers: HashMap::new(),
-                call_type: CallType::Call {
+            &module,
+            RunnerData::new(
+                dex_id.clone(),
+                near_sdk::borsh::to_vec(&request).expect("Failed to serialize request"),
+                CallType::Call {
                     dex_storage_mut: &mut self.dex_storage,
                     predecessor_id: predecessor.clone(),
                     is_authorized: anon_swap_available_assets.is_none(),
                 },
-                dex_id: dex_id.clone(),
-                dex_storage_balances: &self.dex_storage_balances,
-                dex_storage_usage_before_transaction: storage_usage_before,
-            },
+                &self.dex_storage_balances,
+                &self.dex_configs,
+                context,
+            ),
+            method.as_str(),
+            "Failed to call function",
         );
-        let mut linker = Linker::new(&engine);
-
-        impl_supported_host_functions!(linker);
-        impl_unsupported_host_functions!(linker);
-
-        let instance = match linker.instantiate_and_start(&mut store, &module) {
-            Ok(i) => i,
-            Err(err) => panic!("Failed to instantiate module: {err:?}"),
-        };
-        let dex_call_func: Func = match instance.get_func(&mut store, method.as_str()) {
-            Some(f) => f,
-            None => panic!("Failed to get function"),
-        };
-        match dex_call_func.call(&mut store, &[], &mut []) {
-            Ok(()) => (),
-            Err(err) => panic!("Failed to call function: {err:?}"),
-        };
+        if let Err(err) = result {
+            panic!("{err}");
+        }
         let response = store.data_mut().response.take();
+        let pool_count = store.data().pool_count;
+        let event_sequence = store.data().event_sequence;
+        let pending_events = std::mem::take(&mut store.data_mut().pending_events);
         drop(store);
-        drop(linker);
 
+        self.internal_set_dex_pool_count(&dex_id, pool_count);
+        self.internal_set_dex_event_sequence(&dex_id, event_sequence);
         self.dex_storage.flush();
         let storage_usage_after = near_sdk::env::storage_usage();
         self.dex_storage_balances
             .charge(&dex_id, storage_usage_before, storage_usage_after);
+        for event in pending_events {
+            event.emit();
+        }
 
         let response: DexCallResponse = match response {
             Some(response) => near_sdk::borsh::from_slice(&response)
@@ -346,7 +1585,7 @@ impl DexEngine {
             None => Default::default(),
         };
         if let Some(anon_swap_available_assets) = anon_swap_available_assets {
-            for (asset_id, amount) in request.attached_assets {
+            for (asset_id, amount) in attached_assets {
                 anon_swap_available_assets
                     .entry(asset_id.clone())
                     .and_modify(|b| {
@@ -364,7 +1603,7 @@ impl DexEngine {
                 );
             }
         } else {
-            for (asset_id, amount) in request.attached_assets {
+            for (asset_id, amount) in attached_assets {
                 self.internal_transfer_asset(
                     AccountOrDexId::Account(predecessor.clone()),
                     AccountOrDexId::Dex(dex_id.clone()),
@@ -380,6 +1619,7 @@ impl DexEngine {
             withdrawal_type,
         } in response.asset_withdraw_requests
         {
+            let asset_id = self.internal_asset_from_dex(asset_id);
             match withdrawal_type {
                 AssetWithdrawalType::ToInternalUserBalance(account) => {
                     self.internal_transfer_asset(
@@ -403,6 +1643,7 @@ impl DexEngine {
                         Some(amount),
                         Some(to_account_id.clone()),
                         AccountOrDexId::Dex(dex_id.clone()),
+                        None,
                     )
                     .detach();
                 }
@@ -431,51 +1672,166 @@ impl DexEngine {
             method != "swap",
             "Method name 'swap' is reserved for the swap operation"
         );
+        expect!(
+            method != "migrate",
+            "Method name 'migrate' is reserved for migrations"
+        );
 
         let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
-        let engine = Engine::default();
-        let module = match Module::new(&engine, code) {
+        let engine = dex_wasm_engine(&self.resource_limits);
+        let module = match load_dex_module(&engine, code) {
             Ok(module) => module,
-            Err(err) => panic!("Failed to load module: {err:?}"),
+            Err(err) => panic!("{err}"),
         };
 
-        let storage_usage_before = near_sdk::env::storage_usage();
-        let mut store = Store::new(
+        let context = self.internal_dex_context(&dex_id);
+        let (mut store, result) = instantiate_dex(
             &engine,
-            RunnerData {
-                request: args.0,
-                response: None,
-                registers: HashMap::new(),
-                call_type: CallType::View {
+            &module,
+            RunnerData::new(
+                dex_id.clone(),
+                args.0,
+                CallType::View {
                     dex_storage: &self.dex_storage,
                 },
-                dex_id: dex_id.clone(),
-                dex_storage_balances: &self.dex_storage_balances,
-                dex_storage_usage_before_transaction: storage_usage_before,
-            },
+                &self.dex_storage_balances,
+                &self.dex_configs,
+                context,
+            ),
+            method.as_str(),
+            "Failed to call function",
         );
-        let mut linker = Linker::new(&engine);
+        if let Err(err) = result {
+            panic!("{err}");
+        }
+        let response = store.data_mut().response.take();
+        drop(store);
 
-        impl_supported_host_functions!(linker);
-        impl_unsupported_host_functions!(linker);
+        Base64VecU8::from(response.unwrap_or_default())
+    }
 
-        let instance = match linker.instantiate_and_start(&mut store, &module) {
-            Ok(i) => i,
-            Err(err) => panic!("Failed to instantiate module: {err:?}"),
-        };
-        let dex_call_func: Func = match instance.get_func(&mut store, method.as_str()) {
-            Some(f) => f,
-            None => panic!("Failed to get function"),
+    pub(crate) fn internal_quote_batch(
+        &self,
+        dex_id: DexId,
+        requests: Vec<SwapRequest>,
+    ) -> Vec<SwapResponse> {
+        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
+        let engine = dex_wasm_engine(&self.resource_limits);
+        let module = match load_dex_module(&engine, code) {
+            Ok(module) => module,
+            Err(err) => panic!("{err}"),
         };
-        match dex_call_func.call(&mut store, &[], &mut []) {
-            Ok(()) => (),
-            Err(err) => panic!("Failed to call function: {err:?}"),
+
+        requests
+            .into_iter()
+            .map(|swap_request| {
+                self.internal_quote_swap(&engine, &module, &dex_id, swap_request, vec![])
+            })
+            .collect()
+    }
+
+    pub(crate) fn internal_simulate_swap_with_state(
+        &self,
+        dex_id: DexId,
+        request: SwapRequest,
+        storage_overrides: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> SwapResponse {
+        let mut keys = storage_overrides
+            .iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        expect!(
+            keys.windows(2).all(|pair| pair[0] != pair[1]),
+            "Duplicate key in storage overrides"
+        );
+
+        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
+        let engine = dex_wasm_engine(&self.resource_limits);
+        let module = match load_dex_module(&engine, code) {
+            Ok(module) => module,
+            Err(err) => panic!("{err}"),
         };
+        self.internal_quote_swap(&engine, &module, &dex_id, request, storage_overrides)
+    }
+
+    /// Runs the dex `swap` without persisting anything. The dex
+    /// sees its storage with `storage_overrides` applied.
+    fn internal_quote_swap(
+        &self,
+        engine: &Engine,
+        module: &Module,
+        dex_id: &DexId,
+        swap_request: SwapRequest,
+        storage_overrides: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> SwapResponse {
+        if let Some(metadata) = self.dex_metadata.get(dex_id) {
+            expect!(
+                metadata.is_asset_supported(&swap_request.asset_in),
+                "Asset {} is not supported by dex {dex_id}",
+                swap_request.asset_in
+            );
+            expect!(
+                metadata.is_asset_supported(&swap_request.asset_out),
+                "Asset {} is not supported by dex {dex_id}",
+                swap_request.asset_out
+            );
+        }
+        if let Err(err) = swap_request.validate() {
+            panic!("Invalid swap request: {err}");
+        }
+
+        // Reads go to the dex storage, writes stay in memory and
+        // are thrown away after the quote
+        let mut scratch_storage: ScratchStorage = storage_overrides
+            .into_iter()
+            .map(|(key, value)| (key, Some(value)))
+            .collect();
+        let context = self.internal_dex_context(dex_id);
+        let dex_swap_request = self.internal_swap_request_for_dex(&swap_request);
+        let (mut store, result) = instantiate_dex(
+            engine,
+            module,
+            RunnerData::new(
+                dex_id.clone(),
+                near_sdk::borsh::to_vec(&dex_swap_request)
+                    .expect("Failed to serialize swap request"),
+                CallType::Trade {
+                    dex_storage: &self.dex_storage,
+                    scratch_storage: &mut scratch_storage,
+                },
+                &self.dex_storage_balances,
+                &self.dex_configs,
+                context,
+            ),
+            "swap",
+            "Failed to call function",
+        );
+        if let Err(err) = result {
+            panic!("{err}");
+        }
         let response = store.data_mut().response.take();
         drop(store);
-        drop(linker);
 
-        Base64VecU8::from(response.unwrap_or_default())
+        let response: SwapResponse = match response {
+            Some(response) => self.internal_swap_response_from_dex(
+                SwapResponse::from_dex_bytes(&response)
+                    .expect("Failed to deserialize swap response"),
+            ),
+            None => panic!("No response from swap"),
+        };
+        match swap_request.amount {
+            SwapRequestAmount::ExactIn(exact_in) => {
+                expect!(exact_in == response.amount_in, "Amount in does not match");
+            }
+            SwapRequestAmount::ExactOut(exact_out) => {
+                expect!(
+                    exact_out == response.amount_out,
+                    "Amount out does not match"
+                );
+            }
+        }
+        response
     }
 
     pub(crate) fn internal_register_assets(
@@ -505,7 +1861,11 @@ impl DexEngine {
                         .is_none()
                     {
                         self.dex_balances
-                            .insert((dex_id, asset_id.clone()), U128(0));
+                            .insert((dex_id.clone(), asset_id.clone()), U128(0));
+                        let dex_assets = self.dex_assets.entry(dex_id).or_default();
+                        if let Err(index) = dex_assets.binary_search(&asset_id) {
+                            dex_assets.insert(index, asset_id.clone());
+                        }
                     }
                 }
             }
@@ -515,6 +1875,7 @@ impl DexEngine {
         }
         self.user_balances.flush();
         self.dex_balances.flush();
+        self.dex_assets.flush();
         self.total_in_custody.flush();
         let storage_usage_after = near_sdk::env::storage_usage();
         self.user_storage_balances.charge(
@@ -530,6 +1891,7 @@ impl DexEngine {
         amount: Option<U128>,
         withdraw_to: Option<AccountId>,
         withdraw_from: AccountOrDexId,
+        transfer_gas: Option<Gas>,
     ) -> PromiseOrValue<bool> {
         let amount = amount.unwrap_or_else(|| {
             self.asset_balance_of(withdraw_from.clone(), asset_id.clone())
@@ -563,69 +1925,47 @@ impl DexEngine {
                 panic!("withdraw_to must be present when withdrawing from a dex")
             }
         });
-        self.internal_withdraw_unchecked(asset_id, amount, withdraw_to, withdraw_from)
+        self.internal_withdraw_unchecked(asset_id, amount, withdraw_to, withdraw_from, transfer_gas)
     }
 
     /// Withdraws assets without reducing or checking any balances.
+    /// `transfer_gas` overrides the gas of the transfer call, see
+    /// `transfer::transfer`.
     fn internal_withdraw_unchecked(
         &mut self,
         asset_id: AssetId,
         amount: U128,
         withdraw_to: AccountId,
         withdraw_from: AccountOrDexId,
+        transfer_gas: Option<Gas>,
     ) -> PromiseOrValue<bool> {
-        const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
-        const GAS_FOR_NFT_TRANSFER: Gas = Gas::from_tgas(10);
-        const GAS_FOR_MT_TRANSFER: Gas = Gas::from_tgas(10);
         const GAS_FOR_WITHDRAWAL_CALLBACK: Gas = Gas::from_tgas(5);
 
-        PromiseOrValue::Promise(match &asset_id {
-            AssetId::Near => Promise::new(withdraw_to.clone())
-                .transfer(NearToken::from_yoctonear(amount.0))
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
-                ),
-            AssetId::Nep141(contract_id) => ext_ft_core::ext(contract_id.clone())
-                .with_attached_deposit(NearToken::from_yoctonear(1))
-                .with_static_gas(GAS_FOR_FT_TRANSFER)
-                .ft_transfer(withdraw_to.clone(), amount, None)
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
-                ),
-            AssetId::Nep171(contract_id, token_id) => ext_nft_core::ext(contract_id.clone())
-                .with_attached_deposit(NearToken::from_yoctonear(1))
-                .with_static_gas(GAS_FOR_NFT_TRANSFER)
-                .nft_transfer(withdraw_to.clone(), token_id.clone(), None, None)
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
-                ),
-            AssetId::Nep245(contract_id, token_id) => Promise::new(contract_id.clone())
-                .function_call(
-                    "mt_transfer",
-                    near_sdk::serde_json::json!({
-                        "receiver_id": withdraw_to,
-                        "token_id": token_id,
-                        "amount": amount,
-                        "approval": null,
-                        "memo": null,
-                    })
-                    .to_string()
-                    .into_bytes(),
-                    NearToken::from_yoctonear(1),
-                    GAS_FOR_MT_TRANSFER,
-                )
-                .then(
-                    Self::ext(near_sdk::env::current_account_id())
-                        .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
-                        .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
-                ),
-        })
+        expect!(
+            !self.recipient_denylist.contains(&withdraw_to),
+            "Withdrawals to {withdraw_to} are not allowed"
+        );
+        if let Some(transfer_gas) = transfer_gas {
+            expect!(
+                asset_id != AssetId::Near,
+                "transfer_gas doesn't apply to near, which is sent without a function call"
+            );
+            let remaining_gas =
+                near_sdk::env::prepaid_gas().saturating_sub(near_sdk::env::used_gas());
+            expect!(
+                transfer_gas.saturating_add(GAS_FOR_WITHDRAWAL_CALLBACK) <= remaining_gas,
+                "Not enough gas for a transfer with {} Tgas and the withdrawal callback, {} Tgas left",
+                transfer_gas.as_tgas(),
+                remaining_gas.as_tgas()
+            );
+        }
+        PromiseOrValue::Promise(
+            transfer::transfer(&asset_id, &withdraw_to, amount, transfer_gas).then(
+                Self::ext(near_sdk::env::current_account_id())
+                    .with_static_gas(GAS_FOR_WITHDRAWAL_CALLBACK)
+                    .after_withdraw(asset_id, amount, withdraw_to, withdraw_from),
+            ),
+        )
     }
 
     pub(crate) fn internal_execute_operations(
@@ -634,6 +1974,22 @@ impl DexEngine {
         by: AccountId,
         mut anon_swap_available_assets: Option<HashMap<AssetId, U128>>,
     ) {
+        let dex_invocations = operations
+            .iter()
+            .filter(|operation| {
+                matches!(
+                    operation,
+                    Operation::SwapSimple { .. } | Operation::DexCall { .. }
+                )
+            })
+            .count();
+        expect!(
+            dex_invocations <= MAX_DEX_INVOCATIONS_PER_BATCH,
+            "Too many dex invocations in one batch: {dex_invocations} > {MAX_DEX_INVOCATIONS_PER_BATCH}"
+        );
+
+        validate_route(&operations);
+
         let fully_authorized = anon_swap_available_assets.as_ref().is_none();
         near_sdk::env::log_str(&format!("Fully authorized: {fully_authorized}"));
         let mut last_output = None;
@@ -648,17 +2004,19 @@ impl DexEngine {
                 Operation::DeployDexCode {
                     last_part_of_id,
                     code_base64,
+                    config,
                 } => {
                     if !fully_authorized {
                         panic!("Operation only available in execute_actions");
                     }
-                    self.internal_deploy_dex_code(last_part_of_id, code_base64, by.clone());
+                    self.internal_deploy_dex_code(last_part_of_id, code_base64, config, by.clone());
                 }
                 Operation::Withdraw {
                     asset_id,
                     amount,
                     to,
                     rescue_address,
+                    transfer_gas,
                 } => {
                     if let Some(anonymous_assets) = &mut anon_swap_available_assets {
                         let asset_balance = anonymous_assets
@@ -707,6 +2065,7 @@ impl DexEngine {
                             amount,
                             by.clone(),
                             AccountOrDexId::Account(rescue_address.clone()),
+                            transfer_gas,
                         )
                         .detach();
                     } else {
@@ -715,6 +2074,7 @@ impl DexEngine {
                             amount,
                             to,
                             AccountOrDexId::Account(by.clone()),
+                            transfer_gas,
                         )
                         .detach();
                     }
@@ -725,6 +2085,8 @@ impl DexEngine {
                     asset_in,
                     asset_out,
                     amount,
+                    min_amount_out,
+                    max_amount_in,
                 } => {
                     let amount = match amount {
                         SwapOperationAmount::Amount(amount) => amount,
@@ -754,20 +2116,28 @@ impl DexEngine {
                             })
                         }
                     };
-                    let (_amount_in, amount_out) = self.internal_swap_simple(
-                        dex_id,
-                        message,
-                        asset_in,
-                        asset_out.clone(),
-                        amount,
-                        match &mut anon_swap_available_assets {
-                            Some(assets) => TradeAccount::Sandboxed {
-                                assets,
-                                alleged_trader: by.clone(),
+                    // Storage changes can't be kept when a step of a
+                    // batch fails, so a soft fail reverts everything
+                    let (_amount_in, amount_out) = self
+                        .internal_swap_simple(
+                            dex_id,
+                            message,
+                            asset_in,
+                            asset_out.clone(),
+                            amount,
+                            min_amount_out,
+                            max_amount_in,
+                            None,
+                            match &mut anon_swap_available_assets {
+                                Some(assets) => TradeAccount::Sandboxed {
+                                    assets,
+                                    alleged_trader: by.clone(),
+                                },
+                                None => TradeAccount::User(by.clone()),
                             },
-                            None => TradeAccount::User(by.clone()),
-                        },
-                    );
+                            None,
+                        )
+                        .unwrap_or_else(|message| panic!("Swap soft-failed: {message}"));
                     last_output = Some((asset_out, amount_out));
                 }
                 Operation::DexCall {
@@ -898,3 +2268,63 @@ impl DexEngine {
         }
     }
 }
+
+#[cfg(feature = "testing")]
+impl DexEngine {
+    pub(crate) fn internal_trace_swap(
+        &self,
+        dex_id: DexId,
+        swap_request: SwapRequest,
+    ) -> crate::DexTrace {
+        let code = self.dex_codes.get(&dex_id).expect("Dex code not found");
+        let engine = dex_wasm_engine(&self.resource_limits);
+        let module = match load_dex_module(&engine, code) {
+            Ok(module) => module,
+            Err(err) => panic!("{err}"),
+        };
+
+        // Same as in `internal_quote_swap`, the writes are thrown
+        // away after the trace
+        let mut scratch_storage = ScratchStorage::new();
+        let context = self.internal_dex_context(&dex_id);
+        let dex_swap_request = self.internal_swap_request_for_dex(&swap_request);
+        let (mut store, result) = instantiate_dex(
+            &engine,
+            &module,
+            RunnerData::new(
+                dex_id.clone(),
+                near_sdk::borsh::to_vec(&dex_swap_request)
+                    .expect("Failed to serialize swap request"),
+                CallType::Trade {
+                    dex_storage: &self.dex_storage,
+                    scratch_storage: &mut scratch_storage,
+                },
+                &self.dex_storage_balances,
+                &self.dex_configs,
+                context,
+            ),
+            "swap",
+            &format!("[{dex_id}] Dex failed"),
+        );
+        let data = store.data_mut();
+        let mut trace = std::mem::take(&mut data.trace);
+        trace.registers = data
+            .registers
+            .drain()
+            .map(|(register_id, value)| (register_id, value.into()))
+            .collect();
+        let response = data.response.take();
+        drop(store);
+
+        match result.and_then(|_fuel_used| {
+            SwapResponse::from_dex_bytes(
+                &response.ok_or_else(|| "No response from swap".to_string())?,
+            )
+            .map_err(|err| format!("Failed to deserialize swap response: {err}"))
+        }) {
+            Ok(response) => trace.response = Some(response),
+            Err(err) => trace.error = Some(err),
+        }
+        trace
+    }
+}
@@ -5,21 +5,26 @@ pub mod host_functions;
 pub mod internal_asset_operations;
 pub mod internal_operations;
 pub mod storage_management;
+pub mod transfer;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::{
     internal_asset_operations::AccountOrDexId,
-    internal_operations::{Operation, TradeAccount},
+    internal_operations::{
+        DEX_FUEL_LIMIT, EngineError, MAX_DEX_INVOCATIONS_PER_BATCH, MAX_REFERRAL_LEN, Operation,
+        SwapFailure, TradeAccount,
+    },
     storage_management::StorageBalances,
 };
-use intear_dex_types::{AssetId, DexId, SwapRequest, SwapRequestAmount};
+use intear_dex_types::{AssetId, DexId, SwapRequest, SwapRequestAmount, SwapResponse, expect};
 use near_sdk::{
-    AccountId, BorshStorageKey, PromiseOrValue,
+    AccountId, BlockHeight, BorshStorageKey, Gas, NearToken, PromiseOrValue,
     json_types::{Base58CryptoHash, Base64VecU8, U128},
     near,
-    store::{IterableMap, LookupMap},
+    store::{IterableMap, LookupMap, LookupSet},
 };
+use wasmi::{StoreLimits, StoreLimitsBuilder};
 
 #[near(contract_state)]
 pub struct DexEngine {
@@ -27,6 +32,9 @@ pub struct DexEngine {
     /// for the dexes that run inside it. Other dexes or users
     /// can't access other dexes' balances.
     dex_balances: LookupMap<(DexId, AssetId), U128>,
+    /// Assets each dex has a balance of, sorted, since
+    /// `dex_balances` can't be iterated.
+    dex_assets: LookupMap<DexId, Vec<AssetId>>,
     /// Persistent storage for each dex, similar to contract
     /// storage of traditional smart contract dexes. It's
     /// public, but currently there's no way to access other
@@ -34,6 +42,13 @@ pub struct DexEngine {
     dex_storage: DexStorage,
     /// Wasm code for each dex.
     dex_codes: LookupMap<DexId, Vec<u8>>,
+    /// Ids of the dexes each account has deployed, sorted, since
+    /// `dex_codes` can't be iterated.
+    deployed_dexes: LookupMap<AccountId, Vec<String>>,
+    /// Configuration of each dex, supplied at deploy time and
+    /// readable by the dex with `read_config`, so that the same
+    /// code can be deployed with different parameters.
+    dex_configs: LookupMap<DexId, Vec<u8>>,
     /// Storage balances for each dex, translated to storage
     /// of this smart contract. use dex_* methods to interact
     /// with it, such as dex_storage_deposit.
@@ -54,6 +69,52 @@ pub struct DexEngine {
     /// than this stored amount, it can be freely taken out
     /// without causing any issues.
     total_in_custody: IterableMap<AssetId, U128>,
+    /// Settings that dex developers can set for their dexes,
+    /// enforced by the engine.
+    dex_metadata: LookupMap<DexId, DexMetadata>,
+    /// Minimum `amount_in` of a swap for each asset, set by
+    /// the contract owner to deter dust swaps.
+    min_swap_amounts: LookupMap<AssetId, U128>,
+    /// Block height of the last swap on each dex that has
+    /// `max_swaps_per_block` set, and the number of swaps in
+    /// that block.
+    dex_swaps_in_block: LookupMap<DexId, (BlockHeight, u32)>,
+    /// The most recent swap on each dex, for monitoring.
+    last_swaps: LookupMap<DexId, LastSwap>,
+    /// If `false`, `swap_simple` requires exactly one yocto. If
+    /// `true`, any non-zero deposit is accepted and credited to
+    /// the trader's NEAR balance before the swap, so that NEAR
+    /// can be swapped without a separate `deposit_near` call.
+    allow_swap_deposits: bool,
+    /// Number of pools each dex has reported with
+    /// `on_pool_created`.
+    dex_pool_counts: LookupMap<DexId, u32>,
+    /// Maximum number of pools a dex can create, to bound the
+    /// storage footprint of a single dex. `None` means there's
+    /// no limit.
+    max_pools_per_dex: Option<u32>,
+    /// Limits of a single dex invocation.
+    resource_limits: ResourceLimits,
+    /// Sequence number of the next `Swap` or `DexEvent` event of
+    /// each dex, so that indexers can detect missed events.
+    dex_event_sequences: LookupMap<DexId, u64>,
+    /// Wrapped NEAR token that dexes see in place of native NEAR,
+    /// so that a dex can treat NEAR like any other NEP-141 token.
+    /// The engine keeps accounting NEAR as `AssetId::Near`: what
+    /// traders attach or withdraw is native NEAR, and the wrapped
+    /// token can't be traded directly, so a dex never sees two
+    /// balances as one asset. `None`, the default, means native
+    /// and wrapped NEAR are unrelated assets, and dexes see the
+    /// assets exactly as traders request them.
+    wrap_near: Option<AccountId>,
+    /// Accounts that assets can't be withdrawn to, for deployments
+    /// that have to block sanctioned accounts. Empty by default.
+    recipient_denylist: LookupSet<AccountId>,
+    /// Owner of each removed dex at the time of removal. The dex
+    /// storage can't be enumerated, so it outlives the dex, and
+    /// the id can't be deployed again. The owner keeps control
+    /// over what's left, such as the storage balance.
+    removed_dexes: LookupMap<DexId, AccountId>,
 }
 
 #[derive(BorshStorageKey)]
@@ -66,22 +127,63 @@ enum StorageKey {
     UserBalances,
     UserStorageBalances,
     ContractTrackedBalance,
+    DexMetadata,
+    MinSwapAmounts,
+    DexSwapsInBlock,
+    LastSwaps,
+    DexPoolCounts,
+    DexConfigs,
+    DexAssets,
+    DexEventSequences,
+    RecipientDenylist,
+    DeployedDexes,
+    RemovedDexes,
+    RandomSeedOverride,
 }
 
 impl Default for DexEngine {
     fn default() -> Self {
         Self {
             dex_balances: LookupMap::new(StorageKey::DexBalances),
+            dex_assets: LookupMap::new(StorageKey::DexAssets),
             dex_storage: LookupMap::new(StorageKey::DexStorage),
             dex_codes: LookupMap::new(StorageKey::DexCodes),
+            deployed_dexes: LookupMap::new(StorageKey::DeployedDexes),
+            dex_configs: LookupMap::new(StorageKey::DexConfigs),
             dex_storage_balances: StorageBalances::new(StorageKey::DexStorageBalances),
             user_balances: LookupMap::new(StorageKey::UserBalances),
             user_storage_balances: StorageBalances::new(StorageKey::UserStorageBalances),
             total_in_custody: IterableMap::new(StorageKey::ContractTrackedBalance),
+            dex_metadata: LookupMap::new(StorageKey::DexMetadata),
+            min_swap_amounts: LookupMap::new(StorageKey::MinSwapAmounts),
+            dex_swaps_in_block: LookupMap::new(StorageKey::DexSwapsInBlock),
+            last_swaps: LookupMap::new(StorageKey::LastSwaps),
+            allow_swap_deposits: false,
+            dex_pool_counts: LookupMap::new(StorageKey::DexPoolCounts),
+            max_pools_per_dex: None,
+            resource_limits: ResourceLimits::default(),
+            dex_event_sequences: LookupMap::new(StorageKey::DexEventSequences),
+            wrap_near: None,
+            recipient_denylist: LookupSet::new(StorageKey::RecipientDenylist),
+            removed_dexes: LookupMap::new(StorageKey::RemovedDexes),
         }
     }
 }
 
+/// Layout of the contract state before dex metadata, indexes and
+/// engine settings were added, read by `migrate`. The storage keys
+/// of the old collections haven't changed.
+#[near(serializers=[borsh])]
+struct DexEngineV0 {
+    dex_balances: LookupMap<(DexId, AssetId), U128>,
+    dex_storage: DexStorage,
+    dex_codes: LookupMap<DexId, Vec<u8>>,
+    dex_storage_balances: StorageBalances<DexId>,
+    user_balances: LookupMap<(AccountId, AssetId), U128>,
+    user_storage_balances: StorageBalances<AccountId>,
+    total_in_custody: IterableMap<AssetId, U128>,
+}
+
 #[near(event_json(standard = "inteardex"))]
 pub enum IntearDexEvent {
     #[event_version("1.0.0")]
@@ -90,9 +192,14 @@ pub enum IntearDexEvent {
         code_hash: Base58CryptoHash,
     },
     #[event_version("1.0.0")]
+    DexRemoved { dex_id: DexId },
+    #[event_version("1.1.0")]
     DexEvent {
         dex_id: DexId,
         event: near_sdk::serde_json::Value,
+        /// Shared with `Swap` events of the dex, starts from 0
+        /// and increases by one with every event.
+        sequence: u64,
     },
     #[event_version("1.0.0")]
     UserDeposit {
@@ -120,18 +227,44 @@ pub enum IntearDexEvent {
         balance: U128,
     },
     #[event_version("1.0.0")]
+    DexOwnershipTransferred {
+        dex_id: DexId,
+        old_owner: AccountId,
+        new_owner: AccountId,
+    },
+    #[event_version("1.0.0")]
+    BalanceReconciled {
+        dex_id: DexId,
+        asset_id: AssetId,
+        old_balance: U128,
+        new_balance: U128,
+    },
+    #[event_version("1.4.0")]
     Swap {
         dex_id: DexId,
         request: SwapRequest,
         amount_in: U128,
         amount_out: U128,
+        /// Assets released to the trader from the dex balance in
+        /// addition to `amount_out`, as returned by the dex.
+        refunds: Vec<(AssetId, U128)>,
         trader: AccountId,
+        /// Shared with `DexEvent` events of the dex, starts from 0
+        /// and increases by one with every event.
+        sequence: u64,
+        /// Referrer passed to `swap_simple`, for off-chain
+        /// attribution only. Nothing is paid to it.
+        referral: Option<String>,
+        /// Fuel consumed by the `swap` export of the dex, for
+        /// spotting dexes that are unusually expensive to run.
+        fuel_used: u64,
     },
 }
 
 enum CallType<'a> {
     Trade {
-        dex_storage_mut: &'a mut DexStorage,
+        dex_storage: &'a DexStorage,
+        scratch_storage: &'a mut ScratchStorage,
     },
     View {
         dex_storage: &'a DexStorage,
@@ -145,26 +278,118 @@ enum CallType<'a> {
 
 type DexStorage = LookupMap<(DexId, Vec<u8>), Vec<u8>>;
 
+/// Writes of a swap to the storage of one dex, kept in memory until
+/// the swap succeeds. `None` means the key was removed.
+type ScratchStorage = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
 impl CallType<'_> {
-    pub const fn dex_storage(&self) -> &DexStorage {
+    /// Reads a key of the dex storage, including the writes of a
+    /// swap that are still in memory.
+    pub fn storage_get(&self, dex_id: &DexId, key: &[u8]) -> Option<&Vec<u8>> {
         match self {
-            CallType::Trade { dex_storage_mut } => dex_storage_mut,
-            CallType::View { dex_storage } => dex_storage,
+            CallType::Trade {
+                dex_storage,
+                scratch_storage,
+            } => match scratch_storage.get(key) {
+                Some(value) => value.as_ref(),
+                None => dex_storage.get(&(dex_id.clone(), key.to_vec())),
+            },
+            CallType::View { dex_storage } => dex_storage.get(&(dex_id.clone(), key.to_vec())),
             CallType::Call {
                 dex_storage_mut, ..
-            } => dex_storage_mut,
+            } => dex_storage_mut.get(&(dex_id.clone(), key.to_vec())),
         }
     }
 
-    pub const fn dex_storage_mut(&mut self) -> Option<&mut DexStorage> {
+    /// Writes a key of the dex storage, or removes it if `value` is
+    /// `None`. Returns the old value. Panics in views, callers are
+    /// expected to check that first.
+    pub fn storage_set(
+        &mut self,
+        dex_id: &DexId,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
         match self {
-            CallType::Trade { dex_storage_mut } => Some(dex_storage_mut),
-            CallType::View { .. } => None,
+            CallType::Trade {
+                dex_storage,
+                scratch_storage,
+            } => scratch_storage
+                .insert(key.clone(), value)
+                .unwrap_or_else(|| dex_storage.get(&(dex_id.clone(), key)).cloned()),
+            CallType::View { .. } => panic!("Dex storage can't be changed in view functions"),
             CallType::Call {
                 dex_storage_mut, ..
-            } => Some(dex_storage_mut),
+            } => match value {
+                Some(value) => dex_storage_mut.insert((dex_id.clone(), key), value),
+                None => dex_storage_mut.remove(&(dex_id.clone(), key)),
+            },
         }
     }
+
+    pub const fn is_view(&self) -> bool {
+        matches!(self, CallType::View { .. })
+    }
+}
+
+#[derive(Clone, Default)]
+#[near(serializers=[borsh, json])]
+pub struct DexMetadata {
+    /// The account that controls the dex. `None` means it's
+    /// still controlled by the deployer.
+    pub owner: Option<AccountId>,
+    /// Assets that can be traded on the dex. Swaps with any
+    /// other asset are rejected before the dex is invoked.
+    /// Empty means there's no restriction.
+    pub supported_assets: Vec<AssetId>,
+    /// Maximum number of swaps on the dex in one block, to make
+    /// manipulation within a single block harder. `None` means
+    /// there's no limit.
+    pub max_swaps_per_block: Option<u32>,
+    /// Whether `debug_kv` logs of the dex are emitted. Off by
+    /// default, so that debug output doesn't end up in
+    /// production logs.
+    pub debug: bool,
+    /// Whether the dex can use `soft_fail` to fail a swap while
+    /// keeping its storage changes. Dangerous: the dex must not
+    /// write anything that assumes the swap happened, because no
+    /// assets are moved. Off by default.
+    pub allow_soft_fail: bool,
+    /// Fuel that is hidden from `fuel_remaining`. When it reports
+    /// zero, the dex still has this much fuel to wrap up and
+    /// return a partial result, for example a partial fill of an
+    /// order book. A dex that ignores it runs out of fuel as
+    /// usual. Zero by default.
+    pub fuel_reserve: u64,
+}
+
+impl DexMetadata {
+    pub fn is_asset_supported(&self, asset_id: &AssetId) -> bool {
+        self.supported_assets.is_empty() || self.supported_assets.contains(asset_id)
+    }
+}
+
+/// A swap of `swap_many`.
+#[derive(Clone)]
+#[near(serializers=[json])]
+pub struct SwapManyRequest {
+    pub dex_id: DexId,
+    pub request: SwapRequest,
+    /// Fails the swap if it gives less, same as in `swap_simple`.
+    pub min_amount_out: Option<U128>,
+    /// Fails the swap if it takes more, same as in `swap_simple`.
+    pub max_amount_in: Option<U128>,
+}
+
+/// Details of a swap, same as in the swap event.
+#[derive(Clone)]
+#[near(serializers=[borsh, json])]
+pub struct LastSwap {
+    pub request: SwapRequest,
+    pub amount_in: U128,
+    pub amount_out: U128,
+    pub trader: AccountId,
+    pub block_height: BlockHeight,
 }
 
 pub struct RunnerData<'a> {
@@ -174,42 +399,346 @@ pub struct RunnerData<'a> {
     call_type: CallType<'a>,
     dex_id: DexId,
     dex_storage_balances: &'a StorageBalances<DexId>,
+    dex_configs: &'a LookupMap<DexId, Vec<u8>>,
     dex_storage_usage_before_transaction: u64,
+    debug: bool,
+    pool_count: u32,
+    near_balance: u128,
+    event_sequence: u64,
+    max_pools: Option<u32>,
+    limits: ResourceLimits,
+    store_limits: StoreLimits,
+    storage_bytes_written: u64,
+    /// Change of the dex storage size made by this call that isn't
+    /// flushed to the contract storage yet, see `storage_usage`.
+    unflushed_storage_bytes: i64,
+    soft_fail: Option<String>,
+    fuel_reserve: u64,
+    /// `DexEvent`s logged by the dex. They're emitted only after
+    /// the changes of the invocation are committed, so that a
+    /// failed invocation doesn't emit events with sequence numbers
+    /// that will be given out again.
+    pending_events: Vec<IntearDexEvent>,
+    #[cfg(feature = "testing")]
+    trace: DexTrace,
+}
+
+/// What an invocation of a dex needs to know about the dex and the
+/// engine. Collected before the dex storage is borrowed for the
+/// invocation, see `DexEngine::internal_dex_context`.
+struct DexContext {
+    storage_usage_before: u64,
+    debug: bool,
+    fuel_reserve: u64,
+    pool_count: u32,
+    near_balance: u128,
+    event_sequence: u64,
+    max_pools: Option<u32>,
+    limits: ResourceLimits,
+}
+
+impl<'a> RunnerData<'a> {
+    fn new(
+        dex_id: DexId,
+        request: Vec<u8>,
+        call_type: CallType<'a>,
+        dex_storage_balances: &'a StorageBalances<DexId>,
+        dex_configs: &'a LookupMap<DexId, Vec<u8>>,
+        context: DexContext,
+    ) -> Self {
+        Self {
+            request,
+            response: None,
+            registers: HashMap::new(),
+            call_type,
+            dex_id,
+            dex_storage_balances,
+            dex_configs,
+            dex_storage_usage_before_transaction: context.storage_usage_before,
+            debug: context.debug,
+            pool_count: context.pool_count,
+            near_balance: context.near_balance,
+            event_sequence: context.event_sequence,
+            max_pools: context.max_pools,
+            limits: context.limits,
+            store_limits: context.limits.store_limits(),
+            storage_bytes_written: 0,
+            unflushed_storage_bytes: 0,
+            soft_fail: None,
+            fuel_reserve: context.fuel_reserve,
+            pending_events: Vec::new(),
+            #[cfg(feature = "testing")]
+            trace: Default::default(),
+        }
+    }
+}
+
+/// What a dex did during a swap, as returned by `trace_swap`.
+/// Only available with the `testing` feature.
+#[cfg(feature = "testing")]
+#[derive(Default)]
+#[near(serializers=[json])]
+pub struct DexTrace {
+    /// Messages logged by the dex with `log_utf8`, in order,
+    /// including events.
+    pub logs: Vec<String>,
+    /// Writes to the dex storage, in order. `None` is a removal.
+    pub storage_writes: Vec<(Base64VecU8, Option<Base64VecU8>)>,
+    /// Contents of the registers after the dex returned.
+    pub registers: std::collections::BTreeMap<u64, Base64VecU8>,
+    /// The response of the dex, if it returned one.
+    pub response: Option<SwapResponse>,
+    /// Why the dex failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Seed set with `set_random_seed_override`. It's kept under its
+/// own storage key rather than in the contract state, so that the
+/// state has the same layout with and without the `testing` feature.
+#[cfg(feature = "testing")]
+fn random_seed_override() -> Option<Vec<u8>> {
+    near_sdk::env::storage_read(&near_sdk::IntoStorageKey::into_storage_key(
+        StorageKey::RandomSeedOverride,
+    ))
+}
+
+#[cfg(feature = "testing")]
+#[near]
+impl DexEngine {
+    /// Make `random_seed` return the given 32-byte seed to dexes,
+    /// or the block's random seed again if `None`. Only available
+    /// with the `testing` feature.
+    #[private]
+    pub fn set_random_seed_override(&mut self, seed: Option<Base64VecU8>) {
+        if let Some(seed) = &seed {
+            expect!(
+                seed.0.len() == 32,
+                "Random seed must be 32 bytes, got {}",
+                seed.0.len()
+            );
+        }
+        let key = near_sdk::IntoStorageKey::into_storage_key(StorageKey::RandomSeedOverride);
+        match seed {
+            Some(seed) => near_sdk::env::storage_write(&key, &seed.0),
+            None => near_sdk::env::storage_remove(&key),
+        };
+    }
+
+    /// Run the dex `swap` like `quote_swap` does, without engine
+    /// checks, and return everything the dex did instead of only
+    /// the response. Nothing is persisted. Only available with
+    /// the `testing` feature.
+    pub fn trace_swap(&self, dex_id: DexId, request: SwapRequest) -> DexTrace {
+        self.internal_trace_swap(dex_id, request)
+    }
+}
+
+/// Limits of a single dex invocation, set by the contract owner.
+/// A dex that exceeds one of them fails with a message that names
+/// the resource.
+#[derive(Clone, Copy)]
+#[near(serializers=[borsh, json])]
+pub struct ResourceLimits {
+    /// Roughly the number of executed wasm instructions.
+    pub fuel: u64,
+    /// Size of the linear memory of the dex, in bytes.
+    pub memory_bytes: u64,
+    /// Total size of all registers, in bytes.
+    pub register_bytes: u64,
+    /// Total size of keys and values written to the dex storage,
+    /// in bytes.
+    pub storage_write_bytes: u64,
+    /// Depth of nested wasm function calls, so that deep
+    /// recursion fails cleanly instead of exhausting the stack of
+    /// the interpreter.
+    pub max_recursion_depth: u32,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            fuel: DEX_FUEL_LIMIT,
+            memory_bytes: 32 << 20,
+            register_bytes: 4 << 20,
+            storage_write_bytes: 256 << 10,
+            max_recursion_depth: 256,
+        }
+    }
+}
+
+impl ResourceLimits {
+    fn store_limits(&self) -> StoreLimits {
+        StoreLimitsBuilder::new()
+            .memory_size(usize::try_from(self.memory_bytes).unwrap_or(usize::MAX))
+            // Each invocation instantiates exactly one module with
+            // at most one memory, so `memory_bytes` bounds all of
+            // the linear memory of the dex
+            .instances(1)
+            .memories(1)
+            .trap_on_grow_failure(true)
+            .build()
+    }
 }
 
 #[near]
 impl DexEngine {
-    /// Deploy or upgrade the code for a dex.
+    /// Deploy the code of a new dex, with the configuration that
+    /// the dex reads with `read_config`. Fails if the dex is already
+    /// deployed, so that live code is only replaced on purpose with
+    /// `upgrade_dex_code` or `redeploy_and_migrate`.
     #[payable]
-    pub fn deploy_dex_code(&mut self, last_part_of_id: String, code_base64: Base64VecU8) {
+    pub fn deploy_dex_code(
+        &mut self,
+        last_part_of_id: String,
+        code_base64: Base64VecU8,
+        config: Option<Base64VecU8>,
+    ) {
         near_sdk::assert_one_yocto();
         self.internal_deploy_dex_code(
             last_part_of_id,
             code_base64,
+            config,
             near_sdk::env::predecessor_account_id(),
         )
     }
 
-    /// Swap one asset for another on a specific dex.
-    /// Multi-step aggregator method coming soon.
+    /// Replace the code of your dex that is already deployed. If
+    /// `config` is provided, it replaces the configuration, otherwise
+    /// the current one is kept. The dex storage is kept as is, use
+    /// `redeploy_and_migrate` if it needs to be converted. Only
+    /// callable by the owner.
     #[payable]
-    pub fn swap_simple(
+    pub fn upgrade_dex_code(
         &mut self,
         dex_id: DexId,
-        message: Base64VecU8,
-        asset_in: AssetId,
-        asset_out: AssetId,
-        amount: SwapRequestAmount,
-    ) -> (U128, U128) {
+        code_base64: Base64VecU8,
+        config: Option<Base64VecU8>,
+    ) {
         near_sdk::assert_one_yocto();
-        self.internal_swap_simple(
-            dex_id,
-            message,
-            asset_in,
-            asset_out,
-            amount,
-            TradeAccount::User(near_sdk::env::predecessor_account_id()),
-        )
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_upgrade_dex_code(dex_id, code_base64, config)
+    }
+
+    /// Upgrade the code of a dex and migrate its storage by calling
+    /// the `migrate` export of the new code, if it has one. Only
+    /// callable by the owner.
+    #[payable]
+    pub fn redeploy_and_migrate(&mut self, dex_id: DexId, code_base64: Base64VecU8) {
+        near_sdk::assert_one_yocto();
+        let owner = near_sdk::env::predecessor_account_id();
+        self.assert_dex_owner(&dex_id, &owner);
+        self.internal_redeploy_and_migrate(dex_id, code_base64, owner)
+    }
+
+    /// Remove your dex, so that it can't be called anymore, and
+    /// free the storage of its code. The dex must not hold any
+    /// assets. The id can't be deployed again, since the dex
+    /// storage is kept. Only callable by the owner.
+    #[payable]
+    pub fn remove_dex(&mut self, dex_id: DexId) {
+        near_sdk::assert_one_yocto();
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_remove_dex(dex_id)
+    }
+
+    /// Set the assets that can be traded on your dex. An empty
+    /// list removes the restriction. Only callable by the owner.
+    #[payable]
+    pub fn set_dex_supported_assets(&mut self, dex_id: DexId, supported_assets: Vec<AssetId>) {
+        near_sdk::assert_one_yocto();
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_set_dex_supported_assets(dex_id, supported_assets)
+    }
+
+    /// Limit the number of swaps on your dex in one block. `None`
+    /// removes the limit. Only callable by the owner.
+    #[payable]
+    pub fn set_dex_max_swaps_per_block(&mut self, dex_id: DexId, max_swaps_per_block: Option<u32>) {
+        near_sdk::assert_one_yocto();
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_set_dex_max_swaps_per_block(dex_id, max_swaps_per_block)
+    }
+
+    /// Enable or disable `debug_kv` logs of your dex. Only
+    /// callable by the owner.
+    #[payable]
+    pub fn set_dex_debug(&mut self, dex_id: DexId, debug: bool) {
+        near_sdk::assert_one_yocto();
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_set_dex_debug(dex_id, debug)
+    }
+
+    /// Set the fuel reserve of your dex, see
+    /// `DexMetadata::fuel_reserve`. Only callable by the owner.
+    #[payable]
+    pub fn set_dex_fuel_reserve(&mut self, dex_id: DexId, fuel_reserve: u64) {
+        near_sdk::assert_one_yocto();
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_set_dex_fuel_reserve(dex_id, fuel_reserve)
+    }
+
+    /// Allow or disallow `soft_fail` in swaps of your dex. Only
+    /// callable by the owner.
+    #[payable]
+    pub fn set_dex_allow_soft_fail(&mut self, dex_id: DexId, allow: bool) {
+        near_sdk::assert_one_yocto();
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_set_dex_allow_soft_fail(dex_id, allow)
+    }
+
+    /// Transfer control over the dex to another account, including
+    /// its code: only the owner can call `upgrade_dex_code`,
+    /// `redeploy_and_migrate` and `remove_dex`. The id of the dex
+    /// keeps the original deployer. Only callable by the current
+    /// owner.
+    #[payable]
+    pub fn transfer_dex_ownership(&mut self, dex_id: DexId, new_owner: AccountId) {
+        near_sdk::assert_one_yocto();
+        self.assert_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
+        self.internal_transfer_dex_ownership(dex_id, new_owner)
+    }
+
+    /// Execute independent swaps of the caller, so that relayers
+    /// can batch swaps. Unlike `execute_operations`, a failed
+    /// swap doesn't revert the others: it's reported as `Err` and
+    /// has no effect. The result of a successful swap is its
+    /// `(amount_in, amount_out)`, like in `swap_simple`.
+    #[payable]
+    pub fn swap_many(&mut self, swaps: Vec<SwapManyRequest>) -> Vec<Result<(U128, U128), String>> {
+        near_sdk::assert_one_yocto();
+        expect!(
+            swaps.len() <= MAX_DEX_INVOCATIONS_PER_BATCH,
+            "Too many dex invocations in one batch: {} > {MAX_DEX_INVOCATIONS_PER_BATCH}",
+            swaps.len()
+        );
+        let trader = near_sdk::env::predecessor_account_id();
+        swaps
+            .into_iter()
+            .map(
+                |SwapManyRequest {
+                     dex_id,
+                     request,
+                     min_amount_out,
+                     max_amount_in,
+                 }| {
+                    self.internal_try_swap_simple(
+                        dex_id.clone(),
+                        request,
+                        min_amount_out,
+                        max_amount_in,
+                        None,
+                        TradeAccount::User(trader.clone()),
+                        None,
+                    )
+                    .map_err(|failure| match failure {
+                        SwapFailure::SoftFailed(message) => {
+                            format!("[{dex_id}] Dex soft-failed: {message}")
+                        }
+                        SwapFailure::Failed(err) => err,
+                    })
+                },
+            )
+            .collect()
     }
 
     /// An arbitrary call to a dex method. Can be used for
@@ -262,12 +791,21 @@ impl DexEngine {
     /// Returns `true` if the withdrawal was successful, `false`
     /// otherwise. If a withdrawal fails, the assets will be
     /// refunded to the contract's custody balance of the user.
+    ///
+    /// `amount` is in base units of the asset, which is yoctoNEAR
+    /// for near. Use `withdraw_near` to pass a `NearToken`.
+    ///
+    /// `transfer_gas` overrides the gas attached to the transfer
+    /// call of the token, 10 Tgas by default. It must leave enough
+    /// gas for the callback that handles failures, and can't be set
+    /// for near.
     #[payable]
     pub fn withdraw(
         &mut self,
         asset_id: AssetId,
         amount: Option<U128>,
         withdraw_to: Option<AccountId>,
+        transfer_gas: Option<Gas>,
     ) -> PromiseOrValue<bool> {
         near_sdk::assert_one_yocto();
         self.internal_withdraw(
@@ -275,6 +813,26 @@ impl DexEngine {
             amount,
             withdraw_to,
             AccountOrDexId::Account(near_sdk::env::predecessor_account_id()),
+            transfer_gas,
+        )
+    }
+
+    /// Same as `withdraw` for near, with the amount as a
+    /// `NearToken`. Withdraws the whole balance if `amount` is
+    /// not provided.
+    #[payable]
+    pub fn withdraw_near(
+        &mut self,
+        amount: Option<NearToken>,
+        withdraw_to: Option<AccountId>,
+    ) -> PromiseOrValue<bool> {
+        near_sdk::assert_one_yocto();
+        self.internal_withdraw(
+            AssetId::Near,
+            amount.map(|amount| U128(amount.as_yoctonear())),
+            withdraw_to,
+            AccountOrDexId::Account(near_sdk::env::predecessor_account_id()),
+            None,
         )
     }
 
@@ -284,6 +842,8 @@ impl DexEngine {
         self.internal_execute_operations(operations, near_sdk::env::predecessor_account_id(), None);
     }
 
+    /// Balance in base units of the asset, which is yoctoNEAR for
+    /// near. `None` if the asset is not registered.
     pub fn asset_balance_of(&self, of: AccountOrDexId, asset_id: AssetId) -> Option<U128> {
         match of {
             AccountOrDexId::Account(account) => {
@@ -293,12 +853,421 @@ impl DexEngine {
         }
     }
 
+    /// Near balance as a `NearToken`, `None` if near is not
+    /// registered.
+    pub fn near_balance_of(&self, of: AccountOrDexId) -> Option<NearToken> {
+        self.asset_balance_of(of, AssetId::Near)
+            .map(|balance| NearToken::from_yoctonear(balance.0))
+    }
+
+    /// Assets registered for the dex, including ones with zero
+    /// balance, sorted by `AssetId`.
+    pub fn dex_assets(&self, dex_id: DexId) -> Vec<AssetId> {
+        self.dex_assets.get(&dex_id).cloned().unwrap_or_default()
+    }
+
+    /// Balances of the dex in the given assets, in the same order,
+    /// for routers pricing swaps. Unlike `asset_balance_of`,
+    /// assets the dex hasn't registered are reported as 0.
+    pub fn dex_balances(&self, dex_id: DexId, asset_ids: Vec<AssetId>) -> Vec<U128> {
+        asset_ids
+            .into_iter()
+            .map(|asset_id| {
+                self.dex_balances
+                    .get(&(dex_id.clone(), asset_id))
+                    .copied()
+                    .unwrap_or_default()
+            })
+            .collect()
+    }
+
     pub fn total_in_custody(&self, asset_id: AssetId) -> Option<U128> {
         self.total_in_custody.get(&asset_id).copied()
     }
 
+    /// Convert the state of a contract deployed with the old
+    /// layout, see `DexEngineV0`, after deploying this code over
+    /// it. New settings start with their defaults.
+    ///
+    /// `deployed_dexes` are the ids of all dexes that are deployed,
+    /// as found in `dex_deployed` events. The old layout can't be
+    /// iterated, so they are needed to fill `dexes_by_deployer`,
+    /// which owner-only methods rely on, and `dex_assets`, which
+    /// `remove_dex` relies on. The engine pays for the storage of
+    /// these entries. Pools created before the migration don't
+    /// count towards `max_pools_per_dex`.
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate(deployed_dexes: Vec<DexId>) -> Self {
+        let old_state: DexEngineV0 =
+            near_sdk::env::state_read().expect("Failed to read the old contract state");
+        let mut engine = Self {
+            dex_balances: old_state.dex_balances,
+            dex_storage: old_state.dex_storage,
+            dex_codes: old_state.dex_codes,
+            dex_storage_balances: old_state.dex_storage_balances,
+            user_balances: old_state.user_balances,
+            user_storage_balances: old_state.user_storage_balances,
+            total_in_custody: old_state.total_in_custody,
+            ..Default::default()
+        };
+        let assets: Vec<AssetId> = engine.total_in_custody.keys().cloned().collect();
+        for dex_id in deployed_dexes {
+            expect!(
+                engine.dex_codes.contains_key(&dex_id),
+                "Dex {dex_id} is not deployed"
+            );
+            let ids = engine
+                .deployed_dexes
+                .entry(dex_id.deployer.clone())
+                .or_default();
+            if let Err(index) = ids.binary_search(&dex_id.id) {
+                ids.insert(index, dex_id.id.clone());
+            }
+            let mut dex_assets: Vec<AssetId> = assets
+                .iter()
+                .filter(|asset_id| {
+                    engine
+                        .dex_balances
+                        .contains_key(&(dex_id.clone(), (*asset_id).clone()))
+                })
+                .cloned()
+                .collect();
+            if !dex_assets.is_empty() {
+                dex_assets.sort();
+                engine.dex_assets.insert(dex_id, dex_assets);
+            }
+        }
+        engine.deployed_dexes.flush();
+        engine.dex_assets.flush();
+        engine
+    }
+
+    /// Allow or disallow attaching more than one yocto to
+    /// `swap_simple`.
+    #[private]
+    pub fn set_allow_swap_deposits(&mut self, allow: bool) {
+        self.allow_swap_deposits = allow;
+    }
+
+    pub fn allow_swap_deposits(&self) -> bool {
+        self.allow_swap_deposits
+    }
+
+    /// Set the maximum number of pools a dex can create, or
+    /// remove the limit if `max_pools` is `None`. Dexes that
+    /// already have more pools keep them, but can't create new
+    /// ones.
+    #[private]
+    pub fn set_max_pools_per_dex(&mut self, max_pools: Option<u32>) {
+        self.max_pools_per_dex = max_pools;
+    }
+
+    pub fn max_pools_per_dex(&self) -> Option<u32> {
+        self.max_pools_per_dex
+    }
+
+    /// Set the limits of a single dex invocation.
+    #[private]
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        self.resource_limits = limits;
+    }
+
+    pub fn resource_limits(&self) -> ResourceLimits {
+        self.resource_limits
+    }
+
+    /// Set the wrapped NEAR token that dexes see in place of
+    /// native NEAR, or stop mapping NEAR if `wrap_near` is `None`.
+    #[private]
+    pub fn set_wrap_near(&mut self, wrap_near: Option<AccountId>) {
+        self.wrap_near = wrap_near;
+    }
+
+    pub fn wrap_near(&self) -> Option<AccountId> {
+        self.wrap_near.clone()
+    }
+
+    /// Block withdrawals to the given accounts.
+    #[private]
+    pub fn add_to_recipient_denylist(&mut self, account_ids: Vec<AccountId>) {
+        self.recipient_denylist.extend(account_ids);
+    }
+
+    /// Allow withdrawals to the given accounts again.
+    #[private]
+    pub fn remove_from_recipient_denylist(&mut self, account_ids: Vec<AccountId>) {
+        for account_id in account_ids {
+            self.recipient_denylist.remove(&account_id);
+        }
+    }
+
+    pub fn is_recipient_denied(&self, account_id: AccountId) -> bool {
+        self.recipient_denylist.contains(&account_id)
+    }
+
+    /// Number of pools the dex has created.
+    pub fn dex_pool_count(&self, dex_id: DexId) -> u32 {
+        self.internal_dex_pool_count(&dex_id)
+    }
+
+    /// Reserves of a pool, as set by the dex with `set_reserves`,
+    /// or `None` if the dex didn't set them.
+    pub fn dex_reserves(&self, dex_id: DexId, pool_id: u64) -> Option<Vec<(AssetId, U128)>> {
+        self.dex_storage
+            .get(&(dex_id, host_functions::reserves_storage_key(pool_id)))
+            .map(|reserves| {
+                near_sdk::borsh::from_slice(reserves).expect("Failed to deserialize reserves")
+            })
+    }
+
+    /// Correct the tracked balance of a dex, if it drifted from
+    /// the actual balance because of a bug. Emits an event with
+    /// the old and new balance.
+    #[private]
+    pub fn reconcile_balance(&mut self, dex_id: DexId, asset_id: AssetId, new_balance: U128) {
+        self.internal_reconcile_dex_balance(dex_id, asset_id, new_balance)
+    }
+
+    /// Set the minimum `amount_in` of swaps for an asset, or
+    /// remove it if `amount` is `None`.
+    #[private]
+    pub fn set_min_swap_amount(&mut self, asset_id: AssetId, amount: Option<U128>) {
+        match amount {
+            Some(amount) => self.min_swap_amounts.insert(asset_id, amount),
+            None => self.min_swap_amounts.remove(&asset_id),
+        };
+    }
+
+    pub fn min_swap_amount(&self, asset_id: AssetId) -> Option<U128> {
+        self.min_swap_amounts.get(&asset_id).copied()
+    }
+
+    /// The account that currently controls the dex, or `None`
+    /// if the dex doesn't exist.
+    pub fn dex_owner(&self, dex_id: DexId) -> Option<AccountId> {
+        self.internal_dex_owner(&dex_id)
+    }
+
+    /// Last parts of the ids of the dexes the account has deployed
+    /// and not removed, sorted. Ownership transfers don't change
+    /// this, since the deployer is part of the dex id.
+    pub fn dexes_by_deployer(&self, deployer: AccountId) -> Vec<String> {
+        self.deployed_dexes
+            .get(&deployer)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Configuration of the dex, empty if it has none.
+    pub fn dex_config(&self, dex_id: DexId) -> Base64VecU8 {
+        Base64VecU8(self.dex_configs.get(&dex_id).cloned().unwrap_or_default())
+    }
+
+    pub fn dex_metadata(&self, dex_id: DexId) -> DexMetadata {
+        self.dex_metadata.get(&dex_id).cloned().unwrap_or_default()
+    }
+
+    /// The most recent swap on the dex, or `None` if there were
+    /// no swaps yet.
+    pub fn last_swap(&self, dex_id: DexId) -> Option<LastSwap> {
+        self.last_swaps.get(&dex_id).cloned()
+    }
+
     // View method, but needs &mut for compatibility ergonomics with RunnerData
     pub fn dex_view(&self, dex_id: DexId, method: String, args: Base64VecU8) -> Base64VecU8 {
         self.internal_dex_view(dex_id, method, args)
     }
+
+    /// Price multiple swaps on a dex without executing them. Each
+    /// request is quoted independently against the current state
+    /// of the dex.
+    pub fn quote_batch(&self, dex_id: DexId, requests: Vec<SwapRequest>) -> Vec<SwapResponse> {
+        self.internal_quote_batch(dex_id, requests)
+    }
+
+    /// Why `swap_simple` of `trader` with the attached deposit
+    /// (1 yocto by default) would be rejected before the dex is
+    /// invoked, or `None` if the engine would accept it. The dex
+    /// doesn't run, so its own checks, such as whether the pool
+    /// exists, are not included.
+    pub fn preflight(
+        &self,
+        dex_id: DexId,
+        request: SwapRequest,
+        trader: AccountId,
+        attached_deposit: Option<NearToken>,
+    ) -> Option<EngineError> {
+        self.internal_preflight_swap(
+            &dex_id,
+            &request,
+            &trader,
+            attached_deposit.unwrap_or(NearToken::from_yoctonear(1)),
+        )
+        .err()
+    }
+
+    /// Output of the dex for each of the `amounts` in, such as
+    /// for a depth or slippage chart. `message` is passed to the
+    /// dex as is, for example to select a pool. Each amount is
+    /// quoted independently, like in `quote_batch`.
+    pub fn estimate_output_curve(
+        &self,
+        dex_id: DexId,
+        message: Base64VecU8,
+        asset_in: AssetId,
+        asset_out: AssetId,
+        amounts: Vec<U128>,
+    ) -> Vec<U128> {
+        let requests = amounts
+            .into_iter()
+            .map(|amount| SwapRequest {
+                message: message.clone(),
+                asset_in: asset_in.clone(),
+                asset_out: asset_out.clone(),
+                amount: SwapRequestAmount::ExactIn(amount),
+            })
+            .collect();
+        self.internal_quote_batch(dex_id, requests)
+            .into_iter()
+            .map(|response| response.amount_out)
+            .collect()
+    }
+
+    /// Price a swap as if the dex storage had the given values.
+    /// Nothing is written, so this can be used to explore
+    /// hypothetical states of the dex, such as different reserves.
+    pub fn simulate_swap_with_state(
+        &self,
+        dex_id: DexId,
+        request: SwapRequest,
+        storage_overrides: Vec<(Base64VecU8, Base64VecU8)>,
+    ) -> SwapResponse {
+        self.internal_simulate_swap_with_state(
+            dex_id,
+            request,
+            storage_overrides
+                .into_iter()
+                .map(|(key, value)| (key.0, value.0))
+                .collect(),
+        )
+    }
+}
+
+// `#[near]` generates a `DexEngineExt` method with the same arguments
+// as `swap_simple`, and doesn't forward lint attributes to it, so the
+// method lives in a module the attribute can be put on
+#[allow(clippy::too_many_arguments)]
+mod swap_simple {
+    use super::*;
+
+    #[near]
+    impl DexEngine {
+        /// Swap one asset for another on a specific dex.
+        /// Multi-step aggregator method coming soon.
+        ///
+        /// Requires exactly one yocto, unless swap deposits are
+        /// allowed, in which case the attached deposit is added to
+        /// the trader's NEAR balance before the swap. For `ExactOut`
+        /// swaps of NEAR, the attached deposit is the maximum input,
+        /// and whatever the dex didn't take is transferred back to
+        /// the trader. The 1 yocto minimum is not a fee, so it's part
+        /// of the refund, and nothing is withheld for storage, since
+        /// the NEAR balance is paid for by the storage deposit.
+        ///
+        /// If the dex soft-fails the swap, returns zero amounts and
+        /// nothing is swapped.
+        ///
+        /// `min_amount_out` and `max_amount_in` are the slippage
+        /// tolerance of the trader: if the dex gives less or takes more,
+        /// the swap fails and nothing is moved.
+        ///
+        /// `max_price_impact_bps` fails the swap if its price is worse
+        /// than the spot price of the pool before the swap by more
+        /// than this. The spot price is taken from the reserves the
+        /// dex reported with `set_reserves` for the pools of
+        /// `asset_in` and `asset_out` it updated during the swap, so
+        /// this is advisory: a safety net against bugs of the dex,
+        /// not a guarantee against a malicious one. It's not checked
+        /// if the dex didn't report such reserves.
+        ///
+        /// `deadline_ns` is the block timestamp in nanoseconds after
+        /// which the swap fails, so that a transaction executed late
+        /// doesn't go through at a price the trader didn't expect.
+        ///
+        /// `referral` is recorded in the swap event for attribution,
+        /// up to `MAX_REFERRAL_LEN` bytes.
+        #[payable]
+        pub fn swap_simple(
+            &mut self,
+            dex_id: DexId,
+            message: Base64VecU8,
+            asset_in: AssetId,
+            asset_out: AssetId,
+            amount: SwapRequestAmount,
+            min_amount_out: Option<U128>,
+            max_amount_in: Option<U128>,
+            max_price_impact_bps: Option<u16>,
+            deadline_ns: Option<u64>,
+            referral: Option<String>,
+        ) -> (U128, U128) {
+            if let Some(deadline_ns) = deadline_ns {
+                expect!(
+                    near_sdk::env::block_timestamp() <= deadline_ns,
+                    "Deadline exceeded: block timestamp {} is after the deadline of {deadline_ns}",
+                    near_sdk::env::block_timestamp()
+                );
+            }
+            if let Some(referral) = &referral {
+                expect!(
+                    referral.len() <= MAX_REFERRAL_LEN,
+                    "Referral is too long: {} > {MAX_REFERRAL_LEN} bytes",
+                    referral.len()
+                );
+            }
+            let trader = near_sdk::env::predecessor_account_id();
+            let max_native_amount_in = if self.allow_swap_deposits {
+                expect!(
+                    !near_sdk::env::attached_deposit().is_zero(),
+                    "Requires attached deposit of at least 1 yoctoNEAR"
+                );
+                self.deposit_near(None);
+                (asset_in == AssetId::Near && matches!(amount, SwapRequestAmount::ExactOut(_)))
+                    .then(|| near_sdk::env::attached_deposit().as_yoctonear())
+            } else {
+                near_sdk::assert_one_yocto();
+                None
+            };
+            let (amount_in, amount_out) = match self.internal_swap_simple(
+                dex_id.clone(),
+                message,
+                asset_in,
+                asset_out,
+                amount,
+                min_amount_out,
+                max_amount_in,
+                max_price_impact_bps,
+                TradeAccount::User(trader.clone()),
+                referral,
+            ) {
+                Ok(amounts) => amounts,
+                Err(message) => {
+                    near_sdk::env::log_str(&format!("[{dex_id}] Swap soft-failed: {message}"));
+                    (U128(0), U128(0))
+                }
+            };
+            if let Some(max_native_amount_in) = max_native_amount_in {
+                let surplus = max_native_amount_in.saturating_sub(amount_in.0);
+                self.internal_withdraw(
+                    AssetId::Near,
+                    Some(U128(surplus)),
+                    None,
+                    AccountOrDexId::Account(trader),
+                    None,
+                )
+                .detach();
+            }
+            (amount_in, amount_out)
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use intear_dex_types::{DexId, expect};
+use intear_dex_types::DexId;
 use near_contract_standards::storage_management::{
     StorageBalance, StorageBalanceBounds, StorageManagement,
 };
@@ -277,10 +277,7 @@ impl DexEngine {
         amount: Option<NearToken>,
     ) -> StorageBalance {
         near_sdk::assert_one_yocto();
-        expect!(
-            dex_id.deployer == near_sdk::env::predecessor_account_id(),
-            "Only the deployer can withdraw dex storage"
-        );
+        self.assert_dex_or_removed_dex_owner(&dex_id, &near_sdk::env::predecessor_account_id());
         self.dex_storage_balances.storage_withdraw(dex_id, amount)
     }
 
@@ -291,4 +288,12 @@ impl DexEngine {
     pub fn dex_storage_balance_of(&self, dex_id: DexId) -> Option<StorageBalance> {
         self.dex_storage_balances.storage_balance_of(dex_id)
     }
+
+    /// Bytes of contract storage charged to the dex: its code,
+    /// config, balances, and the keys and values it persisted,
+    /// including the per-record overhead of the runtime. Multiply
+    /// by the storage byte cost to get the storage balance in use.
+    pub fn dex_storage_usage(&self, dex_id: DexId) -> u64 {
+        self.dex_storage_balances.get_bytes_used(&dex_id)
+    }
 }
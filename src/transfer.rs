@@ -0,0 +1,46 @@
+use intear_dex_types::AssetId;
+use near_contract_standards::{
+    fungible_token::core::ext_ft_core, non_fungible_token::core::ext_nft_core,
+};
+use near_sdk::{AccountId, Gas, NearToken, Promise, json_types::U128};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_NFT_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_MT_TRANSFER: Gas = Gas::from_tgas(10);
+
+/// Sends `amount` of `asset` to `to` using the transfer method of
+/// the asset's standard. All transfers out of the contract should
+/// go through this function, so that there's one place to audit.
+/// Doesn't touch any balances, the caller is responsible for
+/// accounting and for handling failures.
+///
+/// `gas` overrides the default gas of the transfer call, for
+/// tokens with expensive transfer methods. It's ignored for near,
+/// which is sent without a function call.
+pub fn transfer(asset: &AssetId, to: &AccountId, amount: U128, gas: Option<Gas>) -> Promise {
+    match asset {
+        AssetId::Near => Promise::new(to.clone()).transfer(NearToken::from_yoctonear(amount.0)),
+        AssetId::Nep141(contract_id) => ext_ft_core::ext(contract_id.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(gas.unwrap_or(GAS_FOR_FT_TRANSFER))
+            .ft_transfer(to.clone(), amount, None),
+        AssetId::Nep171(contract_id, token_id) => ext_nft_core::ext(contract_id.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(gas.unwrap_or(GAS_FOR_NFT_TRANSFER))
+            .nft_transfer(to.clone(), token_id.clone(), None, None),
+        AssetId::Nep245(contract_id, token_id) => Promise::new(contract_id.clone()).function_call(
+            "mt_transfer",
+            near_sdk::serde_json::json!({
+                "receiver_id": to,
+                "token_id": token_id,
+                "amount": amount,
+                "approval": null,
+                "memo": null,
+            })
+            .to_string()
+            .into_bytes(),
+            NearToken::from_yoctonear(1),
+            gas.unwrap_or(GAS_FOR_MT_TRANSFER),
+        ),
+    }
+}
@@ -3,6 +3,9 @@
 use intear_dex::internal_asset_operations::AccountOrDexId;
 use intear_dex_types::AssetId;
 use near_crypto::KeyType;
+use near_primitives::errors::{
+    ActionError, ActionErrorKind, FunctionCallError, HostError, TxExecutionError,
+};
 use near_sdk::serde_json::json;
 use near_sdk::{AccountId, NearToken, json_types::U128};
 use near_workspaces::result::ExecutionFinalResult;
@@ -12,9 +15,12 @@ use tokio::sync::OnceCell;
 
 pub struct CompiledWasms {
     pub contract_wasm: Vec<u8>,
+    /// The engine built with the `testing` feature.
+    pub contract_testing_wasm: Vec<u8>,
     pub simple_amm_dex_wasm: Vec<u8>,
     pub minimal_dex_wasm: Vec<u8>,
     pub otc_dex_wasm: Vec<u8>,
+    pub sandbox_test_dex_wasm: Vec<u8>,
     pub ft_wasm: Vec<u8>,
 }
 
@@ -26,6 +32,36 @@ pub async fn get_compiled_wasms() -> &'static CompiledWasms {
             println!("Compiling intear-dex");
             let contract_wasm = near_workspaces::compile_project("./").await.unwrap();
 
+            println!("Compiling intear-dex with testing feature");
+            assert!(
+                Command::new("cargo")
+                    .args([
+                        "build",
+                        "--package=intear-dex",
+                        "--features=testing",
+                        "--release",
+                        "--target",
+                        "wasm32-unknown-unknown"
+                    ])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+            assert!(
+                Command::new("wasm-opt")
+                    .args([
+                        "-O",
+                        "./target/wasm32-unknown-unknown/release/intear_dex.wasm",
+                        "-o",
+                        "./target/wasm32-unknown-unknown/release/intear_dex.wasm"
+                    ])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+
             println!("Compiling simple-amm-dex");
             assert!(
                 Command::new("cargo")
@@ -113,8 +149,39 @@ pub async fn get_compiled_wasms() -> &'static CompiledWasms {
                     .success()
             );
 
+            println!("Compiling sandbox-test-dex");
+            assert!(
+                Command::new("cargo")
+                    .args([
+                        "build",
+                        "--package=sandbox-test-dex",
+                        "--release",
+                        "--target",
+                        "wasm32-unknown-unknown"
+                    ])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+            assert!(
+                Command::new("wasm-opt")
+                    .args([
+                        "-O",
+                        "./target/wasm32-unknown-unknown/release/sandbox_test_dex.wasm",
+                        "-o",
+                        "./target/wasm32-unknown-unknown/release/sandbox_test_dex.wasm"
+                    ])
+                    .status()
+                    .await
+                    .unwrap()
+                    .success()
+            );
+
             println!("Compilation complete");
 
+            let contract_testing_wasm =
+                std::fs::read("./target/wasm32-unknown-unknown/release/intear_dex.wasm").unwrap();
             let simple_amm_dex_wasm =
                 std::fs::read("./target/wasm32-unknown-unknown/release/simple_amm_dex.wasm")
                     .unwrap();
@@ -122,13 +189,18 @@ pub async fn get_compiled_wasms() -> &'static CompiledWasms {
                 std::fs::read("./target/wasm32-unknown-unknown/release/minimal_dex.wasm").unwrap();
             let otc_dex_wasm =
                 std::fs::read("./target/wasm32-unknown-unknown/release/otc_dex.wasm").unwrap();
+            let sandbox_test_dex_wasm =
+                std::fs::read("./target/wasm32-unknown-unknown/release/sandbox_test_dex.wasm")
+                    .unwrap();
             let ft_wasm = include_bytes!("../assets/ft.wasm").to_vec();
 
             CompiledWasms {
                 contract_wasm,
+                contract_testing_wasm,
                 simple_amm_dex_wasm,
                 minimal_dex_wasm,
                 otc_dex_wasm,
+                sandbox_test_dex_wasm,
                 ft_wasm,
             }
         })
@@ -149,6 +221,32 @@ pub fn track_tokens_burnt(
     *total_near_burnt = total_near_burnt.saturating_add(near_burnt)
 }
 
+/// Data of the engine events with the given name emitted by the
+/// transaction.
+pub fn events(result: &ExecutionFinalResult, name: &str) -> Vec<near_sdk::serde_json::Value> {
+    result
+        .logs()
+        .into_iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|event| near_sdk::serde_json::from_str::<near_sdk::serde_json::Value>(event).unwrap())
+        .filter(|event| event["event"] == name)
+        .map(|event| event["data"].clone())
+        .collect()
+}
+
+/// Data of the swap events emitted by the transaction.
+pub fn swap_events(result: &ExecutionFinalResult) -> Vec<near_sdk::serde_json::Value> {
+    events(result, "swap")
+}
+
+/// Sequence numbers of the swap events emitted by the transaction.
+pub fn swap_event_sequences(result: &ExecutionFinalResult) -> Vec<u64> {
+    swap_events(result)
+        .into_iter()
+        .map(|event| event["sequence"].as_u64().unwrap())
+        .collect()
+}
+
 /// Assert the balance of a NEP-141 token of an account.
 pub async fn assert_ft_balance(
     account: &Account,
@@ -247,6 +345,40 @@ pub fn assert_success(result: &ExecutionFinalResult) -> Result<(), String> {
     Ok(())
 }
 
+/// Assert that a result failed because a function call ran out of prepaid gas.
+pub fn assert_gas_exceeded(result: &ExecutionFinalResult) -> Result<(), String> {
+    let gas_exceeded = result.receipt_failures().into_iter().any(|outcome| {
+        let Err(error) = outcome.clone().into_result() else {
+            return false;
+        };
+        let Ok(error) = error.into_inner() else {
+            return false;
+        };
+        let Ok(error) = error.downcast::<TxExecutionError>() else {
+            return false;
+        };
+        match *error {
+            TxExecutionError::ActionError(ActionError {
+                kind: ActionErrorKind::FunctionCallError(error),
+                ..
+            }) => match error {
+                FunctionCallError::HostError(HostError::GasExceeded) => true,
+                // The runtime reports host errors as their display string
+                FunctionCallError::ExecutionError(message) => {
+                    message == "Exceeded the prepaid gas."
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    });
+    if !gas_exceeded {
+        println!("{result:#?}");
+        return Err("Didn't run out of prepaid gas".to_string());
+    }
+    Ok(())
+}
+
 /// Create a new user account.
 pub async fn create_user(
     sandbox: &near_workspaces::Worker<near_workspaces::network::Sandbox>,
@@ -292,6 +424,61 @@ pub async fn ft_storage_deposit_for(ft: &Contract, account: &Account, for_accoun
         .unwrap();
 }
 
+/// Give `account` NEP-141 tokens owned by `owner` and deposit them
+/// to the account's inner balance on the dex engine contract. The
+/// account must already have a storage deposit on the engine.
+pub async fn engine_ft_deposit(
+    dex_engine_contract: &Contract,
+    ft: &Contract,
+    owner: &Account,
+    account: &Account,
+    amount: U128,
+) {
+    if account.id() != owner.id() {
+        ft_storage_deposit(ft, account).await;
+        let result = owner
+            .call(ft.id(), "ft_transfer")
+            .args_json(json!({
+                "receiver_id": account.id(),
+                "amount": amount,
+            }))
+            .deposit(NearToken::from_yoctonear(1))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    ft_storage_deposit_for(ft, account, dex_engine_contract.id()).await;
+
+    let result = account
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft.id().clone())],
+            "for": AccountOrDexId::Account(account.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = account
+        .call(ft.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": amount,
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+}
+
 /// Storage deposit amount for users on engine contract.
 pub const fn engine_user_storage_deposit() -> NearToken {
     NearToken::from_near(1)
@@ -1,18 +1,20 @@
 mod common;
 use common::*;
 
-use intear_dex::internal_operations::SwapOperationAmount;
+use intear_dex::internal_operations::{
+    EngineError, MAX_DEX_INVOCATIONS_PER_BATCH, SwapOperationAmount,
+};
 use intear_dex::{internal_asset_operations::AccountOrDexId, internal_operations::Operation};
-use intear_dex_types::{AssetId, DexId, SwapRequestAmount};
+use intear_dex_types::{AssetId, DexId, SwapRequest, SwapRequestAmount, SwapResponse};
 use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds};
 use near_sdk::serde_json::json;
 use near_sdk::{
-    AccountId, NearToken,
+    AccountId, Gas, NearToken,
     base64::{Engine, prelude::BASE64_STANDARD},
     json_types::{Base64VecU8, U128},
     near,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 #[tokio::test]
 async fn test_minimal() {
@@ -20,10 +22,12 @@ async fn test_minimal() {
     let initial_near_deposit = NearToken::from_near(20);
     let transfer_amount = 1000u128;
     let swap_amount = 10u128;
+    let ft_deposit_amount = 100u128;
 
     let TestContext {
         dex_engine_contract,
         deployer,
+        ft1,
         ..
     } = setup_test_environment().await;
     let wasms = get_compiled_wasms().await;
@@ -184,6 +188,27 @@ async fn test_minimal() {
     )
     .await
     .unwrap();
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &deployer,
+        U128(ft_deposit_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
     let result = deployer
         .call(dex_engine_contract.id(), "swap_simple")
         .max_gas()
@@ -198,15 +223,76 @@ async fn test_minimal() {
         .transact()
         .await
         .unwrap();
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures()).contains("Asset in and asset out must be different")
+    );
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Nep141(ft1.id().clone()),
+            "asset_out": AssetId::Near,
+            "amount": SwapRequestAmount::ExactIn(U128(0)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Amount must be greater than 0"));
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Nep141(ft1.id().clone()),
+            "asset_out": AssetId::Near,
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
     let result: (U128, U128) = result.json().unwrap();
     assert_eq!(result, (U128(swap_amount), U128(swap_amount)));
     assert_inner_asset_balance(
         &dex_engine_contract,
         AccountOrDexId::Dex(dex_id.clone()),
         AssetId::Near,
-        Some(U128(transfer_amount)),
+        Some(U128(transfer_amount - swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear() - transfer_amount + swap_amount,
+        )),
     )
     .await
     .unwrap();
@@ -416,571 +502,605 @@ async fn test_dex_storage_actions() {
 }
 
 #[tokio::test]
-async fn test_withdraw_failures() {
-    let ft_total_supply = NearToken::from_near(1_000_000_000);
-    let storage_deposit_amount = NearToken::from_near(1);
-    let initial_near_deposit = NearToken::from_near(2);
-    let ft_deposit_amount = 1_000_000_000u128;
-    let ft_withdraw_attempt = 100_000_000u128;
-    let near_withdraw_amount = NearToken::from_near(1);
-
+async fn test_deploy_dex_code_validation() {
     let TestContext {
         dex_engine_contract,
-        ft1,
-        user1,
-        user2,
         deployer,
         ..
     } = setup_test_environment().await;
-    let nonexistent_account: AccountId = "nonexistent.test.near".parse().unwrap();
-
-    ft_storage_deposit(&ft1, &user1).await;
 
     let result = deployer
-        .call(ft1.id(), "ft_transfer")
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
         .args_json(json!({
-            "receiver_id": user1.id(),
-            "amount": U128(ft_total_supply.as_yoctonear()),
+            "dex_id": DexId {
+                deployer: deployer.id().clone(),
+                id: "dex".to_string(),
+            },
         }))
-        .deposit(NearToken::from_yoctonear(1))
-        .max_gas()
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
-
-    let result = user1
-        .call(dex_engine_contract.id(), "storage_deposit")
-        .max_gas()
-        .deposit(storage_deposit_amount)
-        .args_json(json!({}))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    let result = user1
-        .call(dex_engine_contract.id(), "register_assets")
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
-            "for": AccountOrDexId::Account(user1.id().clone()),
+            "last_part_of_id": "dex",
+            "code_base64": BASE64_STANDARD.encode(b"not wasm"),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
+    assert!(result.is_failure());
+    let failures = format!("{:?}", result.failures());
+    assert!(failures.contains("Invalid dex code"));
+    assert!(!failures.contains("Failed to compile dex code"));
 
-    let result = user1
-        .call(dex_engine_contract.id(), "deposit_near")
+    // A valid module with no exports
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
-        .deposit(initial_near_deposit)
-        .args_json(json!({}))
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": "dex",
+            "code_base64": BASE64_STANDARD.encode(b"\0asm\x01\0\0\0"),
+        }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
-
-    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Dex code doesn't export a swap function"));
 
-    let result = user1
-        .call(ft1.id(), "ft_transfer_call")
+    // A valid module with a `swap` function of the wrong signature
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "receiver_id": dex_engine_contract.id(),
-            "amount": U128(ft_deposit_amount),
-            "msg": "",
+            "last_part_of_id": "dex",
+            "code_base64": BASE64_STANDARD.encode(
+                [
+                    &b"\0asm\x01\0\0\0"[..],
+                    // type 0: (i32) -> ()
+                    &[1, 5, 1, 0x60, 1, 0x7f, 0],
+                    &[3, 2, 1, 0],
+                    &[7, 8, 1, 4],
+                    b"swap",
+                    &[0, 0],
+                    &[10, 4, 1, 2, 0, 0x0b],
+                ]
+                .concat()
+            ),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
-
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Near,
-        Some(U128(initial_near_deposit.as_yoctonear())),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures()).contains(
+            "The swap function of the dex code must take no parameters and return nothing"
+        )
+    );
 
-    let result = user1
-        .call(dex_engine_contract.id(), "withdraw")
+    // A valid module with a `swap` function, but without memory
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_id": AssetId::Nep141(ft1.id().clone()),
-            "amount": U128(ft_withdraw_attempt),
-            "withdraw_to": user2.id(),
+            "last_part_of_id": "dex",
+            "code_base64": BASE64_STANDARD.encode(
+                [
+                    &b"\0asm\x01\0\0\0"[..],
+                    // type 0: () -> ()
+                    &[1, 4, 1, 0x60, 0, 0],
+                    &[3, 2, 1, 0],
+                    &[7, 8, 1, 4],
+                    b"swap",
+                    &[0, 0],
+                    &[10, 4, 1, 2, 0, 0x0b],
+                ]
+                .concat()
+            ),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
-    assert!(!result.json::<bool>().unwrap());
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
-
-    let result = user1
-        .call(dex_engine_contract.id(), "withdraw")
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Dex code doesn't export its memory as"));
+
+    // A valid module that exports `swap`, but has more functions
+    // than the engine allows
+    let functions = 10_001u32;
+    let leb128 = |mut value: u32| {
+        let mut bytes = vec![];
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    };
+    let section =
+        |id: u8, content: Vec<u8>| [vec![id], leb128(content.len() as u32), content].concat();
+    let code = [
+        b"\0asm\x01\0\0\0".to_vec(),
+        // type 0: () -> ()
+        section(1, vec![1, 0x60, 0, 0]),
+        section(3, [leb128(functions), vec![0; functions as usize]].concat()),
+        section(7, [&[1, 4][..], b"swap", &[0, 0]].concat()),
+        section(
+            10,
+            [leb128(functions), [2, 0, 0x0b].repeat(functions as usize)].concat(),
+        ),
+    ]
+    .concat();
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_id": AssetId::Near,
-            "amount": U128(near_withdraw_amount.as_yoctonear()),
-            "withdraw_to": nonexistent_account,
+            "last_part_of_id": "dex",
+            "code_base64": BASE64_STANDARD.encode(code),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
-    assert!(!result.json::<bool>().unwrap());
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Near,
-        Some(U128(initial_near_deposit.as_yoctonear())),
-    )
-    .await
-    .unwrap();
+    assert!(result.is_failure());
+    let failures = format!("{:?}", result.failures());
+    assert!(failures.contains("Failed to compile dex code"));
+    assert!(failures.contains("exceeds the limit of 10000 functions"));
+    assert!(!failures.contains("Invalid dex code"));
+
+    let wasms = get_compiled_wasms().await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+    let deploy = async || {
+        deployer
+            .call(dex_engine_contract.id(), "deploy_dex_code")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "last_part_of_id": dex_id.id,
+                "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let upgrade = async || {
+        deployer
+            .call(dex_engine_contract.id(), "upgrade_dex_code")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let result = upgrade().await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures()).contains("is not deployed yet, use deploy_dex_code")
+    );
+    assert_success(&deploy().await).unwrap();
+
+    // Deploying again doesn't replace the code by accident
+    let result = deploy().await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("is already deployed, use upgrade_dex_code or redeploy_and_migrate")
+    );
+    assert_success(&upgrade().await).unwrap();
 }
 
 #[tokio::test]
-async fn test_total_in_custody_consistency() {
-    let ft_total_supply = NearToken::from_near(1_000_000_000);
-    let storage_deposit_amount = NearToken::from_near(1);
-    let initial_near_deposit = NearToken::from_near(2);
-    let ft_deposit_amount = 1_000_000_000u128;
-    let ft_withdraw_attempt = 100_000_000u128;
-    let near_withdraw_amount = NearToken::from_near(1);
-    let ft_successful_withdraw = 500_000_000u128;
-
+async fn test_dex_ownership_transfer() {
     let TestContext {
         dex_engine_contract,
-        ft1: ft,
-        user1,
-        user2,
         deployer,
+        user1,
         ..
     } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
 
-    ft_storage_deposit(&ft, &user1).await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    let dex_owner = async || {
+        dex_engine_contract
+            .view("dex_owner")
+            .args_json(json!({ "dex_id": dex_id.clone() }))
+            .await
+            .unwrap()
+            .json::<Option<AccountId>>()
+            .unwrap()
+    };
+    assert_eq!(dex_owner().await, None);
 
     let result = deployer
-        .call(ft.id(), "ft_transfer")
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
         .args_json(json!({
-            "receiver_id": user1.id(),
-            "amount": U128(ft_total_supply.as_yoctonear()),
+            "dex_id": dex_id.clone(),
         }))
-        .deposit(NearToken::from_yoctonear(1))
-        .max_gas()
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    let result = user1
-        .call(dex_engine_contract.id(), "storage_deposit")
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
-        .deposit(storage_deposit_amount)
-        .args_json(json!({}))
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
+        }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
+    assert_eq!(dex_owner().await, Some(deployer.id().clone()));
 
     let result = user1
-        .call(dex_engine_contract.id(), "register_assets")
+        .call(dex_engine_contract.id(), "transfer_dex_ownership")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [AssetId::Near, AssetId::Nep141(ft.id().clone())],
-            "for": AccountOrDexId::Account(user1.id().clone()),
+            "dex_id": dex_id.clone(),
+            "new_owner": user1.id(),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Only the owner of dex"));
 
-    assert_total_in_custody(&dex_engine_contract, AssetId::Near, Some(U128(0)))
-        .await
-        .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft.id().clone()),
-        Some(U128(0)),
-    )
-    .await
-    .unwrap();
-
-    let result = user1
-        .call(dex_engine_contract.id(), "deposit_near")
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_dex_ownership")
         .max_gas()
-        .deposit(initial_near_deposit)
-        .args_json(json!({}))
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "new_owner": user1.id(),
+        }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
+    assert_eq!(dex_owner().await, Some(user1.id().clone()));
+    assert_ne!(dex_owner().await, Some(dex_id.deployer.clone()));
 
-    ft_storage_deposit_for(&ft, &user1, dex_engine_contract.id()).await;
-
-    let result = user1
-        .call(ft.id(), "ft_transfer_call")
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_withdraw")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "receiver_id": dex_engine_contract.id(),
-            "amount": U128(ft_deposit_amount),
-            "msg": "",
+            "dex_id": dex_id.clone(),
+            "amount": NearToken::from_yoctonear(1),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
+    assert!(result.is_failure());
 
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Near,
-        Some(U128(initial_near_deposit.as_yoctonear())),
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
     let result = user1
-        .call(dex_engine_contract.id(), "withdraw")
+        .call(dex_engine_contract.id(), "dex_storage_withdraw")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_id": AssetId::Nep141(ft.id().clone()),
-            "amount": U128(ft_withdraw_attempt),
-            "withdraw_to": user2.id(),
+            "dex_id": dex_id.clone(),
+            "amount": NearToken::from_yoctonear(1),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    assert!(!result.json::<bool>().unwrap());
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Nep141(ft.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
 
-    let result = user1
-        .call(dex_engine_contract.id(), "withdraw")
+    // The code is controlled by the owner too, not by the deployer
+    let upgrade = async |account: &near_workspaces::Account| {
+        account
+            .call(dex_engine_contract.id(), "upgrade_dex_code")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id.clone(),
+                "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let result = upgrade(&deployer).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Only the owner of dex"));
+    assert_success(&upgrade(&user1).await).unwrap();
+}
+
+#[tokio::test]
+async fn test_remove_dex() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let mut dex_ids = Vec::new();
+    for id in ["empty", "funded"] {
+        let dex_id = DexId {
+            deployer: deployer.id().clone(),
+            id: id.to_string(),
+        };
+        let result = deployer
+            .call(dex_engine_contract.id(), "dex_storage_deposit")
+            .max_gas()
+            .deposit(engine_dex_storage_deposit())
+            .args_json(json!({
+                "dex_id": dex_id,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let result = deployer
+            .call(dex_engine_contract.id(), "deploy_dex_code")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "last_part_of_id": dex_id.id,
+                "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
+                "config": BASE64_STANDARD.encode(b"config"),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        dex_ids.push(dex_id);
+    }
+    let [empty_dex_id, funded_dex_id] = dex_ids.try_into().unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_id": AssetId::Near,
-            "amount": U128(near_withdraw_amount.as_yoctonear()),
+            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Dex(funded_dex_id.clone()),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    assert!(result.json::<bool>().unwrap());
-
-    let result = user1
-        .call(dex_engine_contract.id(), "withdraw")
+    engine_ft_deposit(&dex_engine_contract, &ft1, &deployer, &deployer, U128(100)).await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_id": AssetId::Nep141(ft.id().clone()),
-            "amount": U128(ft_successful_withdraw),
-            "withdraw_to": user1.id(),
+            "to": AccountOrDexId::Dex(funded_dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(100),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    assert!(result.json::<bool>().unwrap());
-
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Near,
-        Some(U128(near_withdraw_amount.as_yoctonear())),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Near,
-        Some(U128(near_withdraw_amount.as_yoctonear())),
-    )
-    .await
-    .unwrap();
 
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft.id().clone()),
-        Some(U128(ft_successful_withdraw)),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Nep141(ft.id().clone()),
-        Some(U128(ft_successful_withdraw)),
-    )
-    .await
-    .unwrap();
-}
+    let remove_dex = async |account: &near_workspaces::Account, dex_id: &DexId| {
+        account
+            .call(dex_engine_contract.id(), "remove_dex")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let dex_owner = async |dex_id: &DexId| {
+        dex_engine_contract
+            .view("dex_owner")
+            .args_json(json!({ "dex_id": dex_id }))
+            .await
+            .unwrap()
+            .json::<Option<AccountId>>()
+            .unwrap()
+    };
+    let dexes_by_deployer = async || {
+        dex_engine_contract
+            .view("dexes_by_deployer")
+            .args_json(json!({ "deployer": deployer.id() }))
+            .await
+            .unwrap()
+            .json::<Vec<String>>()
+            .unwrap()
+    };
+    let dex_storage_usage = async |dex_id: &DexId| {
+        dex_engine_contract
+            .view("dex_storage_usage")
+            .args_json(json!({ "dex_id": dex_id }))
+            .await
+            .unwrap()
+            .json::<u64>()
+            .unwrap()
+    };
 
-#[tokio::test]
-async fn test_execute_operations() {
-    let storage_deposit_amount = NearToken::from_near(5);
-    let initial_near_deposit = NearToken::from_near(3);
-    let transfer_amount = NearToken::from_millinear(1);
-    let withdraw_amount = NearToken::from_millinear(2);
-    let swap_amount = NearToken::from_millinear(1);
+    let mut all_dexes = vec![empty_dex_id.id.clone(), funded_dex_id.id.clone()];
+    all_dexes.sort();
+    assert_eq!(dexes_by_deployer().await, all_dexes);
 
-    let TestContext {
-        dex_engine_contract,
-        user1,
-        ..
-    } = setup_test_environment().await;
-    let wasms = get_compiled_wasms().await;
-    let dex_wasm = &wasms.minimal_dex_wasm;
+    let result = remove_dex(&user1, &empty_dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Only the owner of dex"));
 
-    let dex_id_string = "dex".to_string();
-    let dex_id = DexId {
-        deployer: user1.id().clone(),
-        id: dex_id_string.clone(),
-    };
+    let result = remove_dex(&deployer, &funded_dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains(&format!(
+        "Dex {funded_dex_id} still holds 100 of nep141:{}, it has to be withdrawn first",
+        ft1.id()
+    )));
+    assert_eq!(dex_owner(&funded_dex_id).await, Some(deployer.id().clone()));
 
-    let result = user1
-        .call(dex_engine_contract.id(), "dex_storage_deposit")
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_dex_ownership")
         .max_gas()
-        .deposit(engine_user_storage_deposit())
+        .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "dex_id": dex_id,
+            "dex_id": empty_dex_id,
+            "new_owner": user1.id(),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
+    let result = remove_dex(&deployer, &empty_dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Only the owner of dex"));
 
-    let result = user1
-        .call(dex_engine_contract.id(), "storage_deposit")
-        .max_gas()
-        .deposit(storage_deposit_amount)
-        .args_json(json!({}))
-        .transact()
+    let storage_usage_before = dex_storage_usage(&empty_dex_id).await;
+    let result = remove_dex(&user1, &empty_dex_id).await;
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"dex_removed\""))
+    );
+    assert_eq!(dex_owner(&empty_dex_id).await, None);
+    assert_eq!(dexes_by_deployer().await, vec![funded_dex_id.id.clone()]);
+    assert!(
+        dex_storage_usage(&empty_dex_id).await + wasms.minimal_dex_wasm.len() as u64
+            <= storage_usage_before
+    );
+    let result = dex_engine_contract
+        .view("dex_config")
+        .args_json(json!({ "dex_id": empty_dex_id }))
         .await
+        .unwrap()
+        .json::<Base64VecU8>()
         .unwrap();
+    assert!(result.0.is_empty());
+
+    let result = remove_dex(&user1, &empty_dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("is not deployed"));
+
+    // The owner keeps control over the storage balance of the
+    // removed dex
+    let dex_storage_withdraw = async |account: &near_workspaces::Account| {
+        account
+            .call(dex_engine_contract.id(), "dex_storage_withdraw")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": empty_dex_id,
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let result = dex_storage_withdraw(&deployer).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Only the owner of dex"));
+    let result = dex_storage_withdraw(&user1).await;
     assert_success(&result).unwrap();
 
-    let result = user1
-        .call(dex_engine_contract.id(), "register_assets")
+    // Everything else needs a deployed dex, for the last owner of a
+    // removed dex and for the deployer of an id that was never used
+    let set_dex_debug = async |account: &near_workspaces::Account, dex_id: &DexId| {
+        account
+            .call(dex_engine_contract.id(), "set_dex_debug")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "debug": true,
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let result = set_dex_debug(&user1, &empty_dex_id).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures()).contains(&format!("Dex {empty_dex_id} is not deployed"))
+    );
+    let never_deployed_dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "never-deployed".to_string(),
+    };
+    let result = set_dex_debug(&deployer, &never_deployed_dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("is not deployed yet"));
+
+    // The dex storage is kept, so the id can't be deployed again
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [AssetId::Near],
-            "for": AccountOrDexId::Account(user1.id().clone()),
+            "last_part_of_id": empty_dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
-
-    let result = user1
-        .call(dex_engine_contract.id(), "deposit_near")
-        .max_gas()
-        .deposit(initial_near_deposit)
-        .args_json(json!({}))
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
-
-    let operations = vec![
-        Operation::RegisterAssets {
-            asset_ids: vec![AssetId::Near],
-            r#for: Some(AccountOrDexId::Dex(DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            })),
-        },
-        Operation::DeployDexCode {
-            last_part_of_id: dex_id_string.clone(),
-            code_base64: Base64VecU8(dex_wasm.to_vec()),
-        },
-        Operation::TransferAsset {
-            to: AccountOrDexId::Dex(DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            }),
-            asset_id: AssetId::Near,
-            amount: U128(transfer_amount.as_yoctonear()),
-        },
-        Operation::SwapSimple {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            message: Base64VecU8(vec![]),
-            asset_in: AssetId::Near,
-            asset_out: AssetId::Near,
-            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
-                swap_amount.as_yoctonear(),
-            ))),
-        },
-        Operation::Withdraw {
-            asset_id: AssetId::Near,
-            amount: Some(U128(withdraw_amount.as_yoctonear())),
-            to: None,
-            rescue_address: None,
-        },
-    ];
-
-    let result = user1
-        .call(dex_engine_contract.id(), "execute_operations")
-        .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
-        .args_json(json!({
-            "operations": operations,
-        }))
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
-
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Near,
-        Some(U128(
-            initial_near_deposit
-                .saturating_sub(transfer_amount)
-                .saturating_sub(withdraw_amount)
-                .as_yoctonear(),
-        )),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Dex(DexId {
-            deployer: user1.id().clone(),
-            id: dex_id_string,
-        }),
-        AssetId::Near,
-        Some(U128(transfer_amount.as_yoctonear())),
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Near,
-        Some(U128(
-            initial_near_deposit
-                .saturating_sub(withdraw_amount)
-                .as_yoctonear(),
-        )),
-    )
-    .await
-    .unwrap();
-}
-
-#[tokio::test]
-async fn test_execute_operations_failure_reverts() {
-    let deposit_amount = NearToken::from_near(1);
-
-    let TestContext {
-        dex_engine_contract,
-        user1,
-        ..
-    } = setup_test_environment().await;
-
-    let operations = vec![Operation::Withdraw {
-        asset_id: AssetId::Near,
-        amount: None,
-        to: None,
-        rescue_address: None,
-    }];
-
-    let result = user1
-        .call(dex_engine_contract.id(), "deposit_near")
-        .max_gas()
-        .deposit(deposit_amount)
-        .args_json(json!({
-            "operations": operations,
-        }))
-        .transact()
-        .await
-        .unwrap();
-    assert!(!result.is_success());
-
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Near,
-        None,
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(&dex_engine_contract, AssetId::Near, None)
-        .await
-        .unwrap();
-}
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains(&format!(
+        "Dex {empty_dex_id} was removed, its id can't be deployed again"
+    )));
+    assert_eq!(dex_owner(&empty_dex_id).await, None);
+    assert_eq!(dexes_by_deployer().await, vec![funded_dex_id.id.clone()]);
+}
 
 #[tokio::test]
-async fn test_ft_transfer_call_failure_reverts() {
-    let ft_total_supply = NearToken::from_near(10);
+async fn test_withdraw_failures() {
+    let ft_total_supply = NearToken::from_near(1_000_000_000);
     let storage_deposit_amount = NearToken::from_near(1);
-    let ft_transfer_amount = 1_000_000u128;
-    let ft_withdraw_attempt = 1_000_000_000_000u128;
+    let initial_near_deposit = NearToken::from_near(2);
+    let ft_deposit_amount = 1_000_000_000u128;
+    let ft_withdraw_attempt = 100_000_000u128;
+    let near_withdraw_amount = NearToken::from_near(1);
 
     let TestContext {
         dex_engine_contract,
         ft1,
         user1,
+        user2,
         deployer,
         ..
     } = setup_test_environment().await;
+    let nonexistent_account: AccountId = "nonexistent.test.near".parse().unwrap();
 
     ft_storage_deposit(&ft1, &user1).await;
 
@@ -1012,7 +1132,7 @@ async fn test_ft_transfer_call_failure_reverts() {
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
             "for": AccountOrDexId::Account(user1.id().clone()),
         }))
         .transact()
@@ -1020,24 +1140,17 @@ async fn test_ft_transfer_call_failure_reverts() {
         .unwrap();
     assert_success(&result).unwrap();
 
-    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
-
-    let operations = vec![Operation::Withdraw {
-        asset_id: AssetId::Nep141(ft1.id().clone()),
-        amount: Some(U128(ft_withdraw_attempt)),
-        to: None,
-        rescue_address: None,
-    }];
-
-    let initial_ft_balance = ft1
-        .view("ft_balance_of")
-        .args_json(json!({
-            "account_id": user1.id(),
-        }))
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
         .await
-        .unwrap()
-        .json::<U128>()
         .unwrap();
+    assert_success(&result).unwrap();
+
+    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
 
     let result = user1
         .call(ft1.id(), "ft_transfer_call")
@@ -1045,94 +1158,91 @@ async fn test_ft_transfer_call_failure_reverts() {
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
             "receiver_id": dex_engine_contract.id(),
-            "amount": U128(ft_transfer_amount),
-            "msg": near_sdk::serde_json::to_string(&operations).unwrap(),
+            "amount": U128(ft_deposit_amount),
+            "msg": "",
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    assert!(result.json::<U128>().unwrap() == U128(0));
 
-    assert_ft_balance(&user1, ft1.clone(), initial_ft_balance)
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear())),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(ft_withdraw_attempt),
+            "withdraw_to": user2.id(),
+        }))
+        .transact()
         .await
         .unwrap();
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
     assert_inner_asset_balance(
         &dex_engine_contract,
         AccountOrDexId::Account(user1.id().clone()),
         AssetId::Nep141(ft1.id().clone()),
-        Some(U128(0)),
+        Some(U128(ft_deposit_amount)),
     )
     .await
     .unwrap();
-    assert_total_in_custody(
+
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+            "amount": U128(near_withdraw_amount.as_yoctonear()),
+            "withdraw_to": nonexistent_account,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
+    assert_inner_asset_balance(
         &dex_engine_contract,
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(0)),
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear())),
     )
     .await
     .unwrap();
 }
 
 #[tokio::test]
-async fn test_execute_operations_liquidity_and_swaps() {
-    let ft_total_supply = NearToken::from_near(1_000_000_000);
-    let storage_deposit_amount = NearToken::from_near(5);
-    let initial_near_deposit = NearToken::from_near(5);
-    let ft_deposit_amount = 1_000_000u128;
-    let pool_creation_fee = NearToken::from_millinear(10);
-    let swap_amount_in = NearToken::from_millinear(1);
-    let lp1_near_amount = NearToken::from_near(1);
-    let lp1_ft1_amount = 500_000u128;
-    let lp2_ft1_amount = 200_000u128;
-    let lp2_ft2_amount = 600_000u128;
+async fn test_withdraw_transfer_gas() {
+    let storage_deposit_amount = NearToken::from_near(1);
+    let ft_deposit_amount = 1_000_000_000u128;
+    let ft_withdraw_amount = 100_000_000u128;
 
     let TestContext {
         dex_engine_contract,
         ft1,
-        ft2,
         user1,
         deployer,
         ..
     } = setup_test_environment().await;
-    let wasms = get_compiled_wasms().await;
-    let dex_wasm = &wasms.simple_amm_dex_wasm;
-
-    let dex_id_string = "dex".to_string();
-    let dex_id = DexId {
-        deployer: user1.id().clone(),
-        id: dex_id_string.clone(),
-    };
-
-    ft_storage_deposit(&ft1, &user1).await;
-    ft_storage_deposit(&ft2, &user1).await;
-
-    for ft in [&ft1, &ft2] {
-        let result = deployer
-            .call(ft.id(), "ft_transfer")
-            .args_json(json!({
-                "receiver_id": user1.id(),
-                "amount": U128(ft_total_supply.as_yoctonear()),
-            }))
-            .deposit(NearToken::from_yoctonear(1))
-            .max_gas()
-            .transact()
-            .await
-            .unwrap();
-        assert_success(&result).unwrap();
-    }
-
-    let result = user1
-        .call(dex_engine_contract.id(), "dex_storage_deposit")
-        .max_gas()
-        .deposit(engine_dex_storage_deposit())
-        .args_json(json!({
-            "dex_id": dex_id,
-        }))
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
 
     let result = user1
         .call(dex_engine_contract.id(), "storage_deposit")
@@ -1149,11 +1259,7 @@ async fn test_execute_operations_liquidity_and_swaps() {
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [
-                AssetId::Near,
-                AssetId::Nep141(ft1.id().clone()),
-                AssetId::Nep141(ft2.id().clone())
-            ],
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
             "for": AccountOrDexId::Account(user1.id().clone()),
         }))
         .transact()
@@ -1161,316 +1267,226 @@ async fn test_execute_operations_liquidity_and_swaps() {
         .unwrap();
     assert_success(&result).unwrap();
 
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &user1,
+        U128(ft_deposit_amount),
+    )
+    .await;
+
+    let withdraw = async |asset_id: AssetId, transfer_gas: Gas| {
+        user1
+            .call(dex_engine_contract.id(), "withdraw")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_id": asset_id,
+                "amount": U128(ft_withdraw_amount),
+                "transfer_gas": transfer_gas,
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+
+    // Doesn't leave gas for the callback
+    let result = withdraw(AssetId::Nep141(ft1.id().clone()), Gas::from_tgas(300)).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Not enough gas for a transfer with 300 Tgas and the withdrawal callback")
+    );
+
+    let result = withdraw(AssetId::Near, Gas::from_tgas(20)).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("transfer_gas doesn't apply to near"));
+
+    // Too little gas for ft_transfer, the tokens are refunded
+    let result = withdraw(AssetId::Nep141(ft1.id().clone()), Gas::from_ggas(500)).await;
+    assert_success(&result).unwrap();
+    assert!(!result.json::<bool>().unwrap());
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+
+    let result = withdraw(AssetId::Nep141(ft1.id().clone()), Gas::from_tgas(20)).await;
+    assert_success(&result).unwrap();
+    assert!(result.json::<bool>().unwrap());
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - ft_withdraw_amount)),
+    )
+    .await
+    .unwrap();
+    assert_ft_balance(&user1, ft1.clone(), U128(ft_withdraw_amount))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_recipient_denylist() {
+    let near_deposit = NearToken::from_near(2);
+    let near_withdraw_amount = NearToken::from_near(1);
+
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        user2,
+        ..
+    } = setup_test_environment().await;
+
     let result = user1
-        .call(dex_engine_contract.id(), "register_assets")
+        .call(dex_engine_contract.id(), "storage_deposit")
         .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
-        .args_json(json!({
-            "asset_ids": [
-                AssetId::Near,
-                AssetId::Nep141(ft1.id().clone()),
-                AssetId::Nep141(ft2.id().clone())
-            ],
-            "for": AccountOrDexId::Dex(DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            }),
-        }))
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-
-    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
-    ft_storage_deposit_for(&ft2, &user1, dex_engine_contract.id()).await;
-
     let result = user1
-        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .call(dex_engine_contract.id(), "register_assets")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "last_part_of_id": dex_id_string,
-            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+            "asset_ids": [AssetId::Near],
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-
     let result = user1
         .call(dex_engine_contract.id(), "deposit_near")
         .max_gas()
-        .deposit(initial_near_deposit)
+        .deposit(near_deposit)
         .args_json(json!({}))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    for ft in [&ft1, &ft2] {
-        let result = user1
-            .call(ft.id(), "ft_transfer_call")
+    let withdraw_near_to = async |withdraw_to: &AccountId| {
+        user1
+            .call(dex_engine_contract.id(), "withdraw_near")
             .max_gas()
             .deposit(NearToken::from_yoctonear(1))
             .args_json(json!({
-                "receiver_id": dex_engine_contract.id(),
-                "amount": U128(ft_deposit_amount),
-                "msg": "",
+                "amount": near_withdraw_amount,
+                "withdraw_to": withdraw_to,
             }))
             .transact()
             .await
-            .unwrap();
-        assert_success(&result).unwrap();
-    }
-
-    #[near(serializers=[borsh])]
-    struct CreatePoolArgs {
-        assets: (AssetId, AssetId),
-    }
-    #[near(serializers=[borsh])]
-    struct AddLiquidityArgs {
-        pool_id: u64,
-    }
-    #[near(serializers=[borsh])]
-    struct SwapArgs {
-        pool_id: u64,
-    }
-
-    let operations = vec![
-        Operation::DexCall {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            method: "new".to_string(),
-            args: Base64VecU8(vec![]),
-            attached_assets: HashMap::new(),
-        },
-        Operation::DexCall {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            method: "create_pool".to_string(),
-            args: Base64VecU8(
-                near_sdk::borsh::to_vec(&CreatePoolArgs {
-                    assets: (AssetId::Near, AssetId::Nep141(ft1.id().clone())),
-                })
-                .unwrap(),
-            ),
-            attached_assets: HashMap::from_iter([(
-                AssetId::Near,
-                U128(pool_creation_fee.as_yoctonear()),
-            )]),
-        },
-        Operation::DexCall {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            method: "create_pool".to_string(),
-            args: Base64VecU8(
-                near_sdk::borsh::to_vec(&CreatePoolArgs {
-                    assets: (
-                        AssetId::Nep141(ft1.id().clone()),
-                        AssetId::Nep141(ft2.id().clone()),
-                    ),
-                })
-                .unwrap(),
-            ),
-            attached_assets: HashMap::from_iter([(
-                AssetId::Near,
-                U128(pool_creation_fee.as_yoctonear()),
-            )]),
-        },
-        Operation::DexCall {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            method: "add_liquidity".to_string(),
-            args: Base64VecU8(near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 0 }).unwrap()),
-            attached_assets: HashMap::from_iter([
-                (AssetId::Near, U128(lp1_near_amount.as_yoctonear())),
-                (AssetId::Nep141(ft1.id().clone()), U128(lp1_ft1_amount)),
-            ]),
-        },
-        Operation::DexCall {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            method: "add_liquidity".to_string(),
-            args: Base64VecU8(near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 1 }).unwrap()),
-            attached_assets: HashMap::from_iter([
-                (AssetId::Nep141(ft1.id().clone()), U128(lp2_ft1_amount)),
-                (AssetId::Nep141(ft2.id().clone()), U128(lp2_ft2_amount)),
-            ]),
-        },
-        Operation::SwapSimple {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            message: Base64VecU8(near_sdk::borsh::to_vec(&SwapArgs { pool_id: 0 }).unwrap()),
-            asset_in: AssetId::Near,
-            asset_out: AssetId::Nep141(ft1.id().clone()),
-            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
-                swap_amount_in.as_yoctonear(),
-            ))),
-        },
-        Operation::SwapSimple {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            message: Base64VecU8(near_sdk::borsh::to_vec(&SwapArgs { pool_id: 1 }).unwrap()),
-            asset_in: AssetId::Nep141(ft1.id().clone()),
-            asset_out: AssetId::Nep141(ft2.id().clone()),
-            amount: SwapOperationAmount::OutputOfLastIn,
-        },
-    ];
+            .unwrap()
+    };
+    let is_recipient_denied = async |account_id: &AccountId| {
+        dex_engine_contract
+            .view("is_recipient_denied")
+            .args_json(json!({
+                "account_id": account_id,
+            }))
+            .await
+            .unwrap()
+            .json::<bool>()
+            .unwrap()
+    };
 
-    let ft2_balance_before = user1
-        .view(dex_engine_contract.id(), "asset_balance_of")
+    // Only the contract can manage the denylist
+    let result = user1
+        .call(dex_engine_contract.id(), "add_to_recipient_denylist")
+        .max_gas()
         .args_json(json!({
-            "asset_id": AssetId::Nep141(ft2.id().clone()),
-            "of": AccountOrDexId::Account(user1.id().clone()),
+            "account_ids": [user2.id()],
         }))
+        .transact()
         .await
-        .unwrap()
-        .json::<U128>()
         .unwrap();
+    assert!(result.is_failure());
+    assert!(!is_recipient_denied(user2.id()).await);
 
-    let result = user1
-        .call(dex_engine_contract.id(), "execute_operations")
+    let result = dex_engine_contract
+        .call("add_to_recipient_denylist")
         .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "operations": operations,
+            "account_ids": [user2.id()],
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
+    assert!(is_recipient_denied(user2.id()).await);
+    assert!(!is_recipient_denied(user1.id()).await);
+
+    let result = withdraw_near_to(user2.id()).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains(&format!("Withdrawals to {} are not allowed", user2.id()))
+    );
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(near_deposit.as_yoctonear())),
+    )
+    .await
+    .unwrap();
 
-    let ft2_balance_after = user1
-        .view(dex_engine_contract.id(), "asset_balance_of")
-        .args_json(json!({
-            "asset_id": AssetId::Nep141(ft2.id().clone()),
-            "of": AccountOrDexId::Account(user1.id().clone()),
-        }))
+    let result = withdraw_near_to(user1.id()).await;
+    assert_success(&result).unwrap();
+    assert!(result.json::<bool>().unwrap());
+
+    let result = dex_engine_contract
+        .call("remove_from_recipient_denylist")
+        .max_gas()
+        .args_json(json!({
+            "account_ids": [user2.id()],
+        }))
+        .transact()
         .await
-        .unwrap()
-        .json::<U128>()
-        .unwrap();
-    let ft2_balance_after_lp_add = ft2_balance_before.0.checked_sub(lp2_ft2_amount).unwrap();
-    let amount_out = ft2_balance_after
-        .0
-        .checked_sub(ft2_balance_after_lp_add)
         .unwrap();
-    assert_eq!(amount_out, 1493);
-
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Near,
-        Some(U128(initial_near_deposit.as_yoctonear())),
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft2.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
+    assert_success(&result).unwrap();
+    assert!(!is_recipient_denied(user2.id()).await);
 
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Dex(dex_id.clone()),
-        AssetId::Near,
-        Some(U128(
-            lp1_near_amount.as_yoctonear() + swap_amount_in.as_yoctonear(),
-        )),
-    )
-    .await
-    .unwrap();
-    // same as it was, since it was an intermediate asset
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Dex(dex_id.clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(lp1_ft1_amount + lp2_ft1_amount)),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Dex(dex_id),
-        AssetId::Nep141(ft2.id().clone()),
-        Some(U128(lp2_ft2_amount - amount_out)),
-    )
-    .await
-    .unwrap();
+    let result = withdraw_near_to(user2.id()).await;
+    assert_success(&result).unwrap();
+    assert!(result.json::<bool>().unwrap());
     assert_inner_asset_balance(
         &dex_engine_contract,
         AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Nep141(ft2.id().clone()),
-        Some(U128(ft_deposit_amount - lp2_ft2_amount + amount_out)),
+        AssetId::Near,
+        Some(U128(0)),
     )
     .await
     .unwrap();
 }
 
 #[tokio::test]
-async fn test_operations_with_ft_deposit() {
-    let ft_total_supply = NearToken::from_near(1_000_000_000);
+async fn test_reconcile_balance() {
     let storage_deposit_amount = NearToken::from_near(5);
-    let ft_deposit_amount = 1_000_000u128;
-    let ft_transfer_to_dex = 200_000u128;
-    let ft_swap_amount = 100_000u128;
+    let initial_near_deposit = NearToken::from_near(20);
+    let transfer_amount = 100u128;
 
     let TestContext {
         dex_engine_contract,
-        ft1,
-        user1,
-        user2,
         deployer,
         ..
     } = setup_test_environment().await;
-    let wasms = get_compiled_wasms().await;
-    let dex_wasm = &wasms.minimal_dex_wasm;
 
-    let dex_id_string = "dex".to_string();
     let dex_id = DexId {
-        deployer: user1.id().clone(),
-        id: dex_id_string.clone(),
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
     };
 
-    ft_storage_deposit(&ft1, &user1).await;
-
     let result = deployer
-        .call(ft1.id(), "ft_transfer")
-        .args_json(json!({
-            "receiver_id": user1.id(),
-            "amount": U128(ft_total_supply.as_yoctonear()),
-        }))
-        .deposit(NearToken::from_yoctonear(1))
-        .max_gas()
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
-
-    let result = user1
         .call(dex_engine_contract.id(), "dex_storage_deposit")
         .max_gas()
         .deposit(engine_user_storage_deposit())
@@ -1482,7 +1498,7 @@ async fn test_operations_with_ft_deposit() {
         .unwrap();
     assert_success(&result).unwrap();
 
-    let result = user1
+    let result = deployer
         .call(dex_engine_contract.id(), "storage_deposit")
         .max_gas()
         .deposit(storage_deposit_amount)
@@ -1492,755 +1508,4995 @@ async fn test_operations_with_ft_deposit() {
         .unwrap();
     assert_success(&result).unwrap();
 
-    let result = user1
-        .call(dex_engine_contract.id(), "register_assets")
+    for r#for in [
+        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = deployer
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near],
+                "for": r#for,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
         .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
-        .args_json(json!({
-            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
-            "for": AccountOrDexId::Dex(DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            }),
-        }))
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    let result = user1
-        .call(dex_engine_contract.id(), "register_assets")
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
-            "for": AccountOrDexId::Account(user2.id().clone()),
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Near,
+            "amount": U128(transfer_amount),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    let result = user1
-        .call(dex_engine_contract.id(), "deploy_dex_code")
+    let result = deployer
+        .call(dex_engine_contract.id(), "reconcile_balance")
         .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "last_part_of_id": dex_id_string,
-            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+            "dex_id": dex_id,
+            "asset_id": AssetId::Near,
+            "new_balance": U128(transfer_amount * 2),
         }))
         .transact()
         .await
         .unwrap();
-    assert_success(&result).unwrap();
+    assert!(result.is_failure());
 
-    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+    for new_balance in [transfer_amount + 50, transfer_amount - 30] {
+        let result = dex_engine_contract
+            .call("reconcile_balance")
+            .max_gas()
+            .args_json(json!({
+                "dex_id": dex_id,
+                "asset_id": AssetId::Near,
+                "new_balance": U128(new_balance),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        assert!(
+            result
+                .logs()
+                .iter()
+                .any(|log| log.contains("\"event\":\"balance_reconciled\""))
+        );
+
+        assert_inner_asset_balance(
+            &dex_engine_contract,
+            AccountOrDexId::Dex(dex_id.clone()),
+            AssetId::Near,
+            Some(U128(new_balance)),
+        )
+        .await
+        .unwrap();
+        assert_total_in_custody(
+            &dex_engine_contract,
+            AssetId::Near,
+            Some(U128(
+                initial_near_deposit.as_yoctonear() - transfer_amount + new_balance,
+            )),
+        )
+        .await
+        .unwrap();
+    }
+}
 
-    let operations = vec![
-        Operation::TransferAsset {
-            to: AccountOrDexId::Dex(DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            }),
-            asset_id: AssetId::Nep141(ft1.id().clone()),
-            amount: U128(ft_transfer_to_dex),
-        },
-        Operation::SwapSimple {
-            dex_id: DexId {
-                deployer: user1.id().clone(),
-                id: dex_id_string.clone(),
-            },
-            message: Base64VecU8(vec![]),
-            asset_in: AssetId::Nep141(ft1.id().clone()),
-            asset_out: AssetId::Nep141(ft1.id().clone()),
-            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(ft_swap_amount))),
-        },
-        Operation::Withdraw {
-            asset_id: AssetId::Nep141(ft1.id().clone()),
-            amount: None,
-            to: Some(user1.id().clone()),
-            rescue_address: Some(user2.id().clone()),
-        },
-    ];
+#[tokio::test]
+async fn test_total_in_custody_consistency() {
+    let ft_total_supply = NearToken::from_near(1_000_000_000);
+    let storage_deposit_amount = NearToken::from_near(1);
+    let initial_near_deposit = NearToken::from_near(2);
+    let ft_deposit_amount = 1_000_000_000u128;
+    let ft_withdraw_attempt = 100_000_000u128;
+    let near_withdraw_amount = NearToken::from_near(1);
+    let ft_successful_withdraw = 500_000_000u128;
+
+    let TestContext {
+        dex_engine_contract,
+        ft1: ft,
+        user1,
+        user2,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+
+    ft_storage_deposit(&ft, &user1).await;
+
+    let result = deployer
+        .call(ft.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": user1.id(),
+            "amount": U128(ft_total_supply.as_yoctonear()),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
 
     let result = user1
-        .call(ft1.id(), "ft_transfer_call")
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "receiver_id": dex_engine_contract.id(),
-            "amount": U128(ft_deposit_amount),
-            "msg": near_sdk::serde_json::to_string(&operations).unwrap(),
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft.id().clone())],
+            "for": AccountOrDexId::Account(user1.id().clone()),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    assert_inner_asset_balance(
+    assert_total_in_custody(&dex_engine_contract, AssetId::Near, Some(U128(0)))
+        .await
+        .unwrap();
+    assert_total_in_custody(
         &dex_engine_contract,
-        AccountOrDexId::Account(user1.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        None,
+        AssetId::Nep141(ft.id().clone()),
+        Some(U128(0)),
     )
     .await
     .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Dex(DexId {
-            deployer: user1.id().clone(),
-            id: dex_id_string,
-        }),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_transfer_to_dex)),
-    )
-    .await
-    .unwrap();
-    assert_total_in_custody(
-        &dex_engine_contract,
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_transfer_to_dex)),
-    )
-    .await
-    .unwrap();
-}
-
-#[tokio::test]
-async fn test_regular_flow() {
-    let storage_deposit_amount = NearToken::from_near(5);
-    let initial_near_deposit = NearToken::from_near(20);
-    let ft_deposit_amount = 1_000_000_000u128;
-    let pool_creation_fee = NearToken::from_millinear(10);
-    let add_liquidity_near = NearToken::from_near(1);
-    let add_liquidity_ft = 1_000_000u128;
-    let swap_amount_in = NearToken::from_millinear(100);
-    let remove_liquidity_near = NearToken::from_millinear(500);
-    let remove_liquidity_ft = 500_000u128;
-    let withdraw_near_amount = NearToken::from_near(1);
-    let withdraw_ft_amount = 100_000_000u128;
-
-    let TestContext {
-        sandbox,
-        dex_engine_contract,
-        ft1,
-        deployer,
-        ..
-    } = setup_test_environment().await;
-    let wasms = get_compiled_wasms().await;
-    let dex_wasm = &wasms.simple_amm_dex_wasm;
-
-    let dex_id_string = "dex".to_string();
-    let dex_id = DexId {
-        deployer: deployer.id().clone(),
-        id: dex_id_string.clone(),
-    };
-
-    let result = deployer
-        .call(dex_engine_contract.id(), "dex_storage_deposit")
-        .max_gas()
-        .deposit(engine_dex_storage_deposit())
-        .args_json(json!({
-            "dex_id": dex_id,
-        }))
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
 
-    let result = deployer
-        .call(dex_engine_contract.id(), "storage_deposit")
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
         .max_gas()
-        .deposit(storage_deposit_amount)
+        .deposit(initial_near_deposit)
         .args_json(json!({}))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    let result = deployer
-        .call(dex_engine_contract.id(), "deploy_dex_code")
+    ft_storage_deposit_for(&ft, &user1, dex_engine_contract.id()).await;
+
+    let result = user1
+        .call(ft.id(), "ft_transfer_call")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "last_part_of_id": dex_id_string,
-            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_deposit_amount),
+            "msg": "",
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
 
-    let initial_near_balance = deployer.view_account().await.unwrap().balance;
-    let mut total_near_burnt = NearToken::from_yoctonear(0);
-    assert_inner_asset_balance(
+    assert_total_in_custody(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
         AssetId::Near,
-        None,
+        Some(U128(initial_near_deposit.as_yoctonear())),
     )
     .await
     .unwrap();
-
-    let result = deployer
-        .call(dex_engine_contract.id(), "register_assets")
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
-            "for": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft.id().clone()),
+            "amount": U128(ft_withdraw_attempt),
+            "withdraw_to": user2.id(),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-
+    assert!(!result.json::<bool>().unwrap());
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Near,
-        None,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft.id().clone()),
+        Some(U128(ft_deposit_amount)),
     )
     .await
     .unwrap();
-    assert_inner_asset_balance(
+    assert_total_in_custody(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        None,
+        AssetId::Nep141(ft.id().clone()),
+        Some(U128(ft_deposit_amount)),
     )
     .await
     .unwrap();
 
-    let result = deployer
-        .call(dex_engine_contract.id(), "register_assets")
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
-            "for": AccountOrDexId::Account(deployer.id().clone()),
+            "asset_id": AssetId::Near,
+            "amount": U128(near_withdraw_amount.as_yoctonear()),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
+    assert!(result.json::<bool>().unwrap());
 
-    let result = deployer
-        .call(dex_engine_contract.id(), "deposit_near")
+    let result = user1
+        .call(dex_engine_contract.id(), "withdraw")
         .max_gas()
-        .deposit(initial_near_deposit)
-        .args_json(json!({}))
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft.id().clone()),
+            "amount": U128(ft_successful_withdraw),
+            "withdraw_to": user1.id(),
+        }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    assert_near_balance(
-        &deployer,
-        initial_near_balance
-            .saturating_sub(initial_near_deposit)
-            .saturating_sub(total_near_burnt)
-            .saturating_sub(NearToken::from_yoctonear(2)),
+    assert!(result.json::<bool>().unwrap());
+
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Near,
+        Some(U128(near_withdraw_amount.as_yoctonear())),
     )
     .await
     .unwrap();
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Account(user1.id().clone()),
         AssetId::Near,
-        Some(U128(initial_near_deposit.as_yoctonear())),
+        Some(U128(near_withdraw_amount.as_yoctonear())),
     )
     .await
     .unwrap();
 
-    ft_storage_deposit_for(&ft1, ft1.as_account(), dex_engine_contract.id()).await;
-
-    let initial_ft_balance = ft1
-        .view("ft_balance_of")
-        .args_json(json!({
-            "account_id": deployer.id(),
-        }))
-        .await
-        .unwrap()
-        .json::<U128>()
-        .unwrap();
-    assert_inner_asset_balance(
+    assert_total_in_custody(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(0)),
-    )
-    .await
-    .unwrap();
-    let result = deployer
-        .call(ft1.id(), "ft_transfer_call")
-        .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
-        .args_json(json!({
-            "receiver_id": dex_engine_contract.id(),
-            "amount": U128(ft_deposit_amount),
-            "msg": "",
-        }))
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    assert_ft_balance(
-        &deployer,
-        ft1.clone(),
-        U128(initial_ft_balance.0 - ft_deposit_amount),
+        AssetId::Nep141(ft.id().clone()),
+        Some(U128(ft_successful_withdraw)),
     )
     .await
     .unwrap();
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount)),
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft.id().clone()),
+        Some(U128(ft_successful_withdraw)),
     )
     .await
     .unwrap();
+}
 
-    #[near(serializers=[borsh])]
-    struct CreatePoolArgs {
-        assets: (AssetId, AssetId),
-    }
-    type PoolId = u64;
-    #[near(serializers=[borsh])]
-    struct CreatePoolResponse {
-        pool_id: PoolId,
-    }
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Near,
-        Some(U128(initial_near_deposit.as_yoctonear())),
-    )
-    .await
-    .unwrap();
+#[tokio::test]
+async fn test_execute_operations() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(3);
+    let transfer_amount = NearToken::from_millinear(1);
+    let withdraw_amount = NearToken::from_millinear(2);
+    let swap_amount = NearToken::from_millinear(1);
 
-    let result = deployer
-        .call(dex_engine_contract.id(), "dex_call")
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: user1.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
         .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
+        .deposit(engine_user_storage_deposit())
         .args_json(json!({
-            "dex_id": dex_id.clone(),
-            "method": "new",
-            "args": BASE64_STANDARD.encode([]),
-            "attached_assets": {},
+            "dex_id": dex_id,
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    assert!(result.json::<Base64VecU8>().unwrap().0.is_empty());
 
-    let storage_usage_by_dex_before_pool_creation = dex_engine_contract
-        .view("dex_storage_balance_of")
-        .args_json(json!({
-            "dex_id": dex_id.clone(),
-        }))
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
         .await
-        .unwrap()
-        .json::<StorageBalance>()
         .unwrap();
-    assert_eq!(
-        storage_usage_by_dex_before_pool_creation.total,
-        NearToken::from_yoctonear(20000000000000000000000000),
-    );
-    let storage_usage_by_engine_before_pool_creation = dex_engine_contract
-        .view_account()
-        .await
-        .unwrap()
-        .storage_usage;
+    assert_success(&result).unwrap();
 
-    let near_for_create_pool = pool_creation_fee;
-    let result = deployer
-        .call(dex_engine_contract.id(), "dex_call")
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "dex_id": dex_id.clone(),
-            "method": "create_pool",
-            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&CreatePoolArgs {
-                assets: (AssetId::Near, AssetId::Nep141(ft1.id().clone())),
-            }).unwrap()),
-            "attached_assets": {
-                "near": near_for_create_pool,
-            },
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Account(user1.id().clone()),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    let result = result.json::<Base64VecU8>().unwrap();
-    let pool_id = near_sdk::borsh::from_slice::<CreatePoolResponse>(&result.0)
-        .unwrap()
-        .pool_id;
 
-    let storage_usage_by_dex_after_pool_creation = dex_engine_contract
-        .view("dex_storage_balance_of")
-        .args_json(json!({
-            "dex_id": dex_id.clone(),
-        }))
-        .await
-        .unwrap()
-        .json::<StorageBalance>()
-        .unwrap();
-    let storage_usage_by_engine_after_pool_creation = dex_engine_contract
-        .view_account()
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
         .await
-        .unwrap()
-        .storage_usage;
-    assert_eq!(
-        storage_usage_by_dex_after_pool_creation.total,
-        NearToken::from_yoctonear(20002100000000000000000000),
-    );
-
-    let pool_storage_cost = storage_usage_by_dex_after_pool_creation
-        .total
-        .checked_sub(storage_usage_by_dex_before_pool_creation.total)
         .unwrap();
-    assert_eq!(
-        storage_usage_by_dex_before_pool_creation.available,
-        storage_usage_by_dex_after_pool_creation.available,
-    );
-    assert_eq!(
-        near_sdk::env::storage_byte_cost()
-            .checked_mul(
-                storage_usage_by_engine_after_pool_creation
-                    .checked_sub(storage_usage_by_engine_before_pool_creation)
-                    .unwrap() as u128,
-            )
-            .unwrap(),
-        pool_storage_cost,
-    );
+    assert_success(&result).unwrap();
 
-    assert_near_balance(
-        &deployer,
-        initial_near_balance
-            .saturating_sub(total_near_burnt)
-            .saturating_sub(initial_near_deposit)
-            .saturating_sub(NearToken::from_yoctonear(5)),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
+    engine_ft_deposit(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Near,
-        Some(U128(
-            initial_near_deposit.as_yoctonear() - pool_storage_cost.as_yoctonear(),
-        )),
+        &ft1,
+        &deployer,
+        &user1,
+        U128(transfer_amount.as_yoctonear()),
     )
-    .await
-    .unwrap();
+    .await;
 
-    #[near(serializers=[borsh])]
-    struct AddLiquidityArgs {
-        pool_id: PoolId,
-    }
-    #[near(serializers=[borsh])]
-    struct AddLiquidityResponse;
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount)),
-    )
-    .await
-    .unwrap();
-    let result = deployer
-        .call(dex_engine_contract.id(), "dex_call")
+    let operations = vec![
+        Operation::RegisterAssets {
+            asset_ids: vec![AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            r#for: Some(AccountOrDexId::Dex(DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            })),
+        },
+        Operation::DeployDexCode {
+            last_part_of_id: dex_id_string.clone(),
+            code_base64: Base64VecU8(dex_wasm.to_vec()),
+            config: None,
+        },
+        Operation::TransferAsset {
+            to: AccountOrDexId::Dex(DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            }),
+            asset_id: AssetId::Nep141(ft1.id().clone()),
+            amount: U128(transfer_amount.as_yoctonear()),
+        },
+        Operation::SwapSimple {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            message: Base64VecU8(vec![]),
+            asset_in: AssetId::Near,
+            asset_out: AssetId::Nep141(ft1.id().clone()),
+            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
+                swap_amount.as_yoctonear(),
+            ))),
+            min_amount_out: None,
+            max_amount_in: None,
+        },
+        Operation::Withdraw {
+            asset_id: AssetId::Near,
+            amount: Some(U128(withdraw_amount.as_yoctonear())),
+            to: None,
+            rescue_address: None,
+            transfer_gas: None,
+        },
+    ];
+
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "dex_id": dex_id.clone(),
-            "method": "add_liquidity",
-            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&AddLiquidityArgs {
-                pool_id,
-            }).unwrap()),
-            "attached_assets": {
-                "near": U128(add_liquidity_near.as_yoctonear()),
-                format!("nep141:{}", ft1.id()): U128(add_liquidity_ft),
-            },
+            "operations": operations,
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    let response = result.json::<Base64VecU8>().unwrap();
-    let _ = near_sdk::borsh::from_slice::<AddLiquidityResponse>(&response.0).unwrap();
-    assert_near_balance(
-        &deployer,
-        initial_near_balance
-            .saturating_sub(total_near_burnt)
-            .saturating_sub(initial_near_deposit)
-            .saturating_sub(NearToken::from_yoctonear(6)),
-    )
-    .await
-    .unwrap();
+
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Account(user1.id().clone()),
         AssetId::Near,
         Some(U128(
-            initial_near_deposit.as_yoctonear()
-                - pool_storage_cost.as_yoctonear()
-                - add_liquidity_near.as_yoctonear(),
+            initial_near_deposit
+                .saturating_sub(swap_amount)
+                .saturating_sub(withdraw_amount)
+                .as_yoctonear(),
         )),
     )
     .await
     .unwrap();
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Account(user1.id().clone()),
         AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount - add_liquidity_ft)),
+        Some(U128(swap_amount.as_yoctonear())),
     )
     .await
     .unwrap();
-
-    #[near(serializers=[borsh])]
-    struct SwapArgs {
-        pool_id: PoolId,
-    }
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount - add_liquidity_ft)),
-    )
-    .await
-    .unwrap();
-    let result = deployer
-        .call(dex_engine_contract.id(), "swap_simple")
-        .max_gas()
-        .deposit(NearToken::from_yoctonear(1))
-        .args_json(json!({
-            "dex_id": dex_id.clone(),
-            "message": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&SwapArgs {
-                pool_id,
-            }).unwrap()),
-            "asset_in": AssetId::Near,
-            "asset_out": AssetId::Nep141(ft1.id().clone()),
-            "amount": SwapRequestAmount::ExactIn(U128(swap_amount_in.as_yoctonear())),
-        }))
-        .transact()
-        .await
-        .unwrap();
-    assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    let result: (U128, U128) = result.json().unwrap();
-    assert_eq!(result, (U128(100000000000000000000000), U128(90909)));
-    assert_near_balance(
-        &deployer,
-        initial_near_balance
-            .saturating_sub(total_near_burnt)
-            .saturating_sub(initial_near_deposit)
-            .saturating_sub(NearToken::from_yoctonear(7)),
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Near,
+        Some(U128(swap_amount.as_yoctonear())),
     )
     .await
     .unwrap();
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Near,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft1.id().clone()),
         Some(U128(
-            initial_near_deposit.as_yoctonear()
-                - pool_storage_cost.as_yoctonear()
-                - add_liquidity_near.as_yoctonear()
-                - swap_amount_in.as_yoctonear(),
+            transfer_amount.saturating_sub(swap_amount).as_yoctonear(),
         )),
     )
     .await
     .unwrap();
-    assert_inner_asset_balance(
+    assert_total_in_custody(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(ft_deposit_amount - add_liquidity_ft + 90909)),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit
+                .saturating_sub(withdraw_amount)
+                .as_yoctonear(),
+        )),
     )
     .await
     .unwrap();
+}
 
-    #[near(serializers=[borsh])]
-    struct RemoveLiquidityArgs {
-        pool_id: PoolId,
-        assets_to_remove: (U128, U128),
+#[tokio::test]
+async fn test_execute_operations_failure_reverts() {
+    let deposit_amount = NearToken::from_near(1);
+
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        ..
+    } = setup_test_environment().await;
+
+    let operations = vec![Operation::Withdraw {
+        asset_id: AssetId::Near,
+        amount: None,
+        to: None,
+        rescue_address: None,
+        transfer_gas: None,
+    }];
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(deposit_amount)
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(!result.is_success());
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(&dex_engine_contract, AssetId::Near, None)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_execute_operations_route_length_limit() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(3);
+    let transfer_amount = NearToken::from_millinear(1);
+    let swap_amount = NearToken::from_millinear(1);
+
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id = DexId {
+        deployer: user1.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let mut operations = vec![
+        Operation::RegisterAssets {
+            asset_ids: vec![AssetId::Near],
+            r#for: Some(AccountOrDexId::Dex(dex_id.clone())),
+        },
+        Operation::DeployDexCode {
+            last_part_of_id: dex_id.id.clone(),
+            code_base64: Base64VecU8(dex_wasm.to_vec()),
+            config: None,
+        },
+        Operation::TransferAsset {
+            to: AccountOrDexId::Dex(dex_id.clone()),
+            asset_id: AssetId::Near,
+            amount: U128(transfer_amount.as_yoctonear()),
+        },
+        Operation::SwapSimple {
+            dex_id: dex_id.clone(),
+            message: Base64VecU8(vec![]),
+            asset_in: AssetId::Near,
+            asset_out: AssetId::Near,
+            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
+                swap_amount.as_yoctonear(),
+            ))),
+            min_amount_out: None,
+            max_amount_in: None,
+        },
+    ];
+    // a cycle that feeds the output of the dex back into it
+    for _ in 0..MAX_DEX_INVOCATIONS_PER_BATCH {
+        operations.push(Operation::SwapSimple {
+            dex_id: dex_id.clone(),
+            message: Base64VecU8(vec![]),
+            asset_in: AssetId::Near,
+            asset_out: AssetId::Near,
+            amount: SwapOperationAmount::OutputOfLastIn,
+            min_amount_out: None,
+            max_amount_in: None,
+        });
+    }
+
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Too many dex invocations"));
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Near,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear())),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_execute_operations_disconnected_route() {
+    let TestContext {
+        dex_engine_contract,
+        user1,
+        ..
+    } = setup_test_environment().await;
+
+    let dex_id = DexId {
+        deployer: user1.id().clone(),
+        id: "dex".to_string(),
+    };
+    let operations = vec![
+        Operation::SwapSimple {
+            dex_id: dex_id.clone(),
+            message: Base64VecU8(vec![]),
+            asset_in: AssetId::Near,
+            asset_out: AssetId::Nep141("ft1.near".parse().unwrap()),
+            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(1))),
+            min_amount_out: None,
+            max_amount_in: None,
+        },
+        Operation::SwapSimple {
+            dex_id,
+            message: Base64VecU8(vec![]),
+            asset_in: AssetId::Nep141("ft2.near".parse().unwrap()),
+            asset_out: AssetId::Near,
+            amount: SwapOperationAmount::OutputOfLastIn,
+            min_amount_out: None,
+            max_amount_in: None,
+        },
+    ];
+
+    // The dex doesn't exist, so the route is rejected before the
+    // first swap is executed
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    let failures = format!("{:?}", result.failures());
+    assert!(failures.contains(
+        "Route is disconnected at operation 1: it takes nep141:ft2.near, but the previous swap gives nep141:ft1.near"
+    ));
+    assert!(!failures.contains("Dex code not found"));
+}
+
+#[tokio::test]
+async fn test_ft_transfer_call_failure_reverts() {
+    let ft_total_supply = NearToken::from_near(10);
+    let storage_deposit_amount = NearToken::from_near(1);
+    let ft_transfer_amount = 1_000_000u128;
+    let ft_withdraw_attempt = 1_000_000_000_000u128;
+
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        user1,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+
+    ft_storage_deposit(&ft1, &user1).await;
+
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": user1.id(),
+            "amount": U128(ft_total_supply.as_yoctonear()),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+
+    let operations = vec![Operation::Withdraw {
+        asset_id: AssetId::Nep141(ft1.id().clone()),
+        amount: Some(U128(ft_withdraw_attempt)),
+        to: None,
+        rescue_address: None,
+        transfer_gas: None,
+    }];
+
+    let initial_ft_balance = ft1
+        .view("ft_balance_of")
+        .args_json(json!({
+            "account_id": user1.id(),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+
+    let result = user1
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_transfer_amount),
+            "msg": near_sdk::serde_json::to_string(&operations).unwrap(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.json::<U128>().unwrap() == U128(0));
+
+    assert_ft_balance(&user1, ft1.clone(), initial_ft_balance)
+        .await
+        .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_execute_operations_liquidity_and_swaps() {
+    let ft_total_supply = NearToken::from_near(1_000_000_000);
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(5);
+    let ft_deposit_amount = 1_000_000u128;
+    let pool_creation_fee = NearToken::from_millinear(10);
+    let swap_amount_in = NearToken::from_millinear(1);
+    let lp1_near_amount = NearToken::from_near(1);
+    let lp1_ft1_amount = 500_000u128;
+    let lp2_ft1_amount = 200_000u128;
+    let lp2_ft2_amount = 600_000u128;
+
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.simple_amm_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: user1.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    ft_storage_deposit(&ft1, &user1).await;
+    ft_storage_deposit(&ft2, &user1).await;
+
+    for ft in [&ft1, &ft2] {
+        let result = deployer
+            .call(ft.id(), "ft_transfer")
+            .args_json(json!({
+                "receiver_id": user1.id(),
+                "amount": U128(ft_total_supply.as_yoctonear()),
+            }))
+            .deposit(NearToken::from_yoctonear(1))
+            .max_gas()
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [
+                AssetId::Near,
+                AssetId::Nep141(ft1.id().clone()),
+                AssetId::Nep141(ft2.id().clone())
+            ],
+            "for": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [
+                AssetId::Near,
+                AssetId::Nep141(ft1.id().clone()),
+                AssetId::Nep141(ft2.id().clone())
+            ],
+            "for": AccountOrDexId::Dex(DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            }),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+    ft_storage_deposit_for(&ft2, &user1, dex_engine_contract.id()).await;
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for ft in [&ft1, &ft2] {
+        let result = user1
+            .call(ft.id(), "ft_transfer_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "receiver_id": dex_engine_contract.id(),
+                "amount": U128(ft_deposit_amount),
+                "msg": "",
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    #[near(serializers=[borsh])]
+    struct CreatePoolArgs {
+        assets: (AssetId, AssetId),
+    }
+    #[near(serializers=[borsh])]
+    struct AddLiquidityArgs {
+        pool_id: u64,
+    }
+    #[near(serializers=[borsh])]
+    struct SwapArgs {
+        pool_id: u64,
+    }
+
+    let operations = vec![
+        Operation::DexCall {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            method: "new".to_string(),
+            args: Base64VecU8(vec![]),
+            attached_assets: HashMap::new(),
+        },
+        Operation::DexCall {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            method: "create_pool".to_string(),
+            args: Base64VecU8(
+                near_sdk::borsh::to_vec(&CreatePoolArgs {
+                    assets: (AssetId::Near, AssetId::Nep141(ft1.id().clone())),
+                })
+                .unwrap(),
+            ),
+            attached_assets: HashMap::from_iter([(
+                AssetId::Near,
+                U128(pool_creation_fee.as_yoctonear()),
+            )]),
+        },
+        Operation::DexCall {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            method: "create_pool".to_string(),
+            args: Base64VecU8(
+                near_sdk::borsh::to_vec(&CreatePoolArgs {
+                    assets: (
+                        AssetId::Nep141(ft1.id().clone()),
+                        AssetId::Nep141(ft2.id().clone()),
+                    ),
+                })
+                .unwrap(),
+            ),
+            attached_assets: HashMap::from_iter([(
+                AssetId::Near,
+                U128(pool_creation_fee.as_yoctonear()),
+            )]),
+        },
+        Operation::DexCall {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            method: "add_liquidity".to_string(),
+            args: Base64VecU8(near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 0 }).unwrap()),
+            attached_assets: HashMap::from_iter([
+                (AssetId::Near, U128(lp1_near_amount.as_yoctonear())),
+                (AssetId::Nep141(ft1.id().clone()), U128(lp1_ft1_amount)),
+            ]),
+        },
+        Operation::DexCall {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            method: "add_liquidity".to_string(),
+            args: Base64VecU8(near_sdk::borsh::to_vec(&AddLiquidityArgs { pool_id: 1 }).unwrap()),
+            attached_assets: HashMap::from_iter([
+                (AssetId::Nep141(ft1.id().clone()), U128(lp2_ft1_amount)),
+                (AssetId::Nep141(ft2.id().clone()), U128(lp2_ft2_amount)),
+            ]),
+        },
+        Operation::SwapSimple {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            message: Base64VecU8(near_sdk::borsh::to_vec(&SwapArgs { pool_id: 0 }).unwrap()),
+            asset_in: AssetId::Near,
+            asset_out: AssetId::Nep141(ft1.id().clone()),
+            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(
+                swap_amount_in.as_yoctonear(),
+            ))),
+            min_amount_out: None,
+            max_amount_in: None,
+        },
+        Operation::SwapSimple {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            message: Base64VecU8(near_sdk::borsh::to_vec(&SwapArgs { pool_id: 1 }).unwrap()),
+            asset_in: AssetId::Nep141(ft1.id().clone()),
+            asset_out: AssetId::Nep141(ft2.id().clone()),
+            amount: SwapOperationAmount::OutputOfLastIn,
+            min_amount_out: None,
+            max_amount_in: None,
+        },
+    ];
+
+    let ft2_balance_before = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let ft2_balance_after = user1
+        .view(dex_engine_contract.id(), "asset_balance_of")
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "of": AccountOrDexId::Account(user1.id().clone()),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    let ft2_balance_after_lp_add = ft2_balance_before.0.checked_sub(lp2_ft2_amount).unwrap();
+    let amount_out = ft2_balance_after
+        .0
+        .checked_sub(ft2_balance_after_lp_add)
+        .unwrap();
+    assert_eq!(amount_out, 1493);
+
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear())),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Near,
+        Some(U128(
+            lp1_near_amount.as_yoctonear() + swap_amount_in.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+    // same as it was, since it was an intermediate asset
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(lp1_ft1_amount + lp2_ft1_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(lp2_ft2_amount - amount_out)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(ft_deposit_amount - lp2_ft2_amount + amount_out)),
+    )
+    .await
+    .unwrap();
+
+    // 1 yocto against a 1 NEAR pool rounds down to zero output
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            "message": Base64VecU8(near_sdk::borsh::to_vec(&SwapArgs { pool_id: 0 }).unwrap()),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(1)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Swap output is zero"));
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(DexId {
+            deployer: user1.id().clone(),
+            id: dex_id_string,
+        }),
+        AssetId::Near,
+        Some(U128(
+            lp1_near_amount.as_yoctonear() + swap_amount_in.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_operations_with_ft_deposit() {
+    let ft_total_supply = NearToken::from_near(1_000_000_000);
+    let storage_deposit_amount = NearToken::from_near(5);
+    let ft_deposit_amount = 1_000_000u128;
+    let ft_transfer_to_dex = 200_000u128;
+    let ft_swap_amount = 100_000u128;
+
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        user2,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: user1.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    ft_storage_deposit(&ft1, &user1).await;
+
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .args_json(json!({
+            "receiver_id": user1.id(),
+            "amount": U128(ft_total_supply.as_yoctonear()),
+        }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone()), AssetId::Nep141(ft2.id().clone())],
+            "for": AccountOrDexId::Dex(DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            }),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Account(user2.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    ft_storage_deposit_for(&ft1, &user1, dex_engine_contract.id()).await;
+
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft2,
+        &deployer,
+        &user1,
+        U128(ft_swap_amount),
+    )
+    .await;
+    let result = user1
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "amount": U128(ft_swap_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let operations = vec![
+        Operation::TransferAsset {
+            to: AccountOrDexId::Dex(DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            }),
+            asset_id: AssetId::Nep141(ft1.id().clone()),
+            amount: U128(ft_transfer_to_dex),
+        },
+        Operation::SwapSimple {
+            dex_id: DexId {
+                deployer: user1.id().clone(),
+                id: dex_id_string.clone(),
+            },
+            message: Base64VecU8(vec![]),
+            asset_in: AssetId::Nep141(ft1.id().clone()),
+            asset_out: AssetId::Nep141(ft2.id().clone()),
+            amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(ft_swap_amount))),
+            min_amount_out: None,
+            max_amount_in: None,
+        },
+        Operation::Withdraw {
+            asset_id: AssetId::Nep141(ft1.id().clone()),
+            amount: None,
+            to: Some(user1.id().clone()),
+            rescue_address: Some(user2.id().clone()),
+            transfer_gas: None,
+        },
+        Operation::Withdraw {
+            asset_id: AssetId::Nep141(ft2.id().clone()),
+            amount: None,
+            to: Some(user1.id().clone()),
+            rescue_address: None,
+            transfer_gas: None,
+        },
+    ];
+
+    let result = user1
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_deposit_amount),
+            "msg": near_sdk::serde_json::to_string(&operations).unwrap(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(user1.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        None,
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(DexId {
+            deployer: user1.id().clone(),
+            id: dex_id_string,
+        }),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_transfer_to_dex + ft_swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_transfer_to_dex + ft_swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_swap_insufficient_gas() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(20);
+    let transfer_amount = 1000u128;
+    let swap_amount = 10u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Account(deployer.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Near,
+            "amount": U128(transfer_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .gas(Gas::from_tgas(5))
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_gas_exceeded(&result).unwrap();
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear() - transfer_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Near,
+        Some(U128(transfer_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear())),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_swap_unsupported_asset() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_dex_supported_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "supported_assets": [AssetId::Near],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let metadata = dex_engine_contract
+        .view("dex_metadata")
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .await
+        .unwrap()
+        .json::<near_sdk::serde_json::Value>()
+        .unwrap();
+    assert_eq!(metadata["supported_assets"], json!([AssetId::Near]));
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Nep141(ft1.id().clone()),
+            "asset_out": AssetId::Near,
+            "amount": SwapRequestAmount::ExactIn(U128(10)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains(&format!(
+        "Asset nep141:{} is not supported by dex {dex_id}",
+        ft1.id()
+    )));
+}
+
+#[tokio::test]
+async fn test_min_swap_amount() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(20);
+    let transfer_amount = 1000u128;
+    let min_swap_amount = 100u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near],
+            "for": AccountOrDexId::Account(deployer.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &deployer,
+        U128(transfer_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(transfer_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_min_swap_amount")
+        .max_gas()
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+            "amount": U128(min_swap_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+
+    let result = dex_engine_contract
+        .call("set_min_swap_amount")
+        .max_gas()
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+            "amount": U128(min_swap_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = dex_engine_contract
+        .view("min_swap_amount")
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+        }))
+        .await
+        .unwrap();
+    assert_eq!(
+        result.json::<Option<U128>>().unwrap(),
+        Some(U128(min_swap_amount))
+    );
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(min_swap_amount - 1)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Swap amount is below minimum"));
+    let last_swap = dex_engine_contract
+        .view("last_swap")
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .await
+        .unwrap()
+        .json::<Option<near_sdk::serde_json::Value>>()
+        .unwrap();
+    assert!(last_swap.is_none());
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(min_swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    // The failed swap didn't take a sequence number
+    assert_eq!(swap_event_sequences(&result), [0]);
+    let result: (U128, U128) = result.json().unwrap();
+    assert_eq!(result, (U128(min_swap_amount), U128(min_swap_amount)));
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(min_swap_amount + 1)),
+            "referral": "wallet.near",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_eq!(swap_event_sequences(&result), [1]);
+    assert_eq!(swap_events(&result)[0]["referral"], json!("wallet.near"));
+    assert!(swap_events(&result)[0]["fuel_used"].as_u64().unwrap() > 0);
+    assert_eq!(swap_events(&result)[0]["refunds"], json!([]));
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(min_swap_amount + 1)),
+            "referral": "x".repeat(65),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Referral is too long: 65 > 64 bytes"));
+
+    let last_swap = dex_engine_contract
+        .view("last_swap")
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .await
+        .unwrap()
+        .json::<near_sdk::serde_json::Value>()
+        .unwrap();
+    assert_eq!(last_swap["amount_in"], json!(U128(min_swap_amount + 1)));
+    assert_eq!(last_swap["amount_out"], json!(U128(min_swap_amount + 1)));
+    assert_eq!(last_swap["trader"], json!(deployer.id()));
+    assert_eq!(
+        last_swap["request"]["asset_out"],
+        json!(AssetId::Nep141(ft1.id().clone()))
+    );
+}
+
+#[tokio::test]
+async fn test_swap_refunds() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(20);
+    let transfer_amount = 1000u128;
+    let swap_amount = 100u128;
+    let refund_amount = 10u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.sandbox_test_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for r#for in [
+        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = deployer
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+                "for": r#for,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &deployer,
+        U128(transfer_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(transfer_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // The dex doesn't have this much of the input asset
+    let refunds = vec![(AssetId::Near, U128(swap_amount + 1))];
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&refunds).unwrap()),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Insufficient balance for dex"));
+
+    let refunds = vec![(AssetId::Near, U128(refund_amount))];
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&refunds).unwrap()),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_eq!(
+        swap_events(&result)[0]["refunds"],
+        json!([["near", refund_amount.to_string()]])
+    );
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear() - swap_amount + refund_amount,
+        )),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Near,
+        Some(U128(swap_amount - refund_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(transfer_amount - swap_amount)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_wrap_near() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(20);
+    let transfer_amount = 1000u128;
+    let swap_amount = 100u128;
+    let refund_amount = 10u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ft2: wrap_near,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.sandbox_test_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for r#for in [
+        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = deployer
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+                "for": r#for,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &deployer,
+        U128(transfer_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(transfer_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let wrap_near_setting = dex_engine_contract
+        .view("wrap_near")
+        .args_json(json!({}))
+        .await
+        .unwrap()
+        .json::<Option<AccountId>>()
+        .unwrap();
+    assert_eq!(wrap_near_setting, None);
+    let result = dex_engine_contract
+        .call("set_wrap_near")
+        .max_gas()
+        .args_json(json!({
+            "wrap_near": wrap_near.id(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let wrap_near_setting = dex_engine_contract
+        .view("wrap_near")
+        .args_json(json!({}))
+        .await
+        .unwrap()
+        .json::<Option<AccountId>>()
+        .unwrap();
+    assert_eq!(wrap_near_setting, Some(wrap_near.id().clone()));
+
+    // Native NEAR in, the dex sees wrapped NEAR, but the engine
+    // accounts it as native NEAR
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(b"record_assets"),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "read_kv",
+            "args": BASE64_STANDARD.encode(b"last_assets"),
+        }))
+        .await
+        .unwrap();
+    let response = result.json::<Base64VecU8>().unwrap();
+    let last_assets = near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        near_sdk::borsh::from_slice::<(AssetId, AssetId)>(&last_assets).unwrap(),
+        (
+            AssetId::Nep141(wrap_near.id().clone()),
+            AssetId::Nep141(ft1.id().clone())
+        )
+    );
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Near,
+        Some(U128(swap_amount)),
+    )
+    .await
+    .unwrap();
+
+    // Wrapped NEAR released by the dex goes out as native NEAR
+    let refunds = vec![(AssetId::Nep141(wrap_near.id().clone()), U128(refund_amount))];
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&refunds).unwrap()),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear() - 2 * swap_amount + refund_amount,
+        )),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Near,
+        Some(U128(2 * swap_amount - refund_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Nep141(wrap_near.id().clone()),
+        None,
+    )
+    .await
+    .unwrap();
+
+    // Wrapped NEAR can't be swapped directly
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "message": "",
+            "asset_in": AssetId::Nep141(wrap_near.id().clone()),
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("is accounted as native NEAR by dexes"));
+}
+
+#[tokio::test]
+async fn test_swap_many() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(20);
+    let transfer_amount = 1000u128;
+    let swap_amount = 100u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.sandbox_test_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for r#for in [
+        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = deployer
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+                "for": r#for,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &deployer,
+        U128(transfer_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(transfer_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let swap = |message: Vec<u8>, amount: u128| {
+        json!({
+            "dex_id": dex_id,
+            "request": {
+                "message": BASE64_STANDARD.encode(message),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(amount)),
+            },
+        })
+    };
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_many")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "swaps": [
+                swap(vec![], swap_amount),
+                // The dex doesn't have this much of the output asset
+                swap(vec![], transfer_amount + 1),
+                // The dex can't parse the message and panics
+                swap(vec![1, 2, 3], swap_amount),
+                swap(vec![], swap_amount),
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let results = result.json::<Vec<Result<(U128, U128), String>>>().unwrap();
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0], Ok((U128(swap_amount), U128(swap_amount))));
+    assert!(
+        results[1]
+            .as_ref()
+            .is_err_and(|err| err.contains("Insufficient balance for dex"))
+    );
+    assert!(
+        results[2]
+            .as_ref()
+            .is_err_and(|err| err.contains(&format!("[{dex_id}] Dex failed")))
+    );
+    assert_eq!(results[3], Ok((U128(swap_amount), U128(swap_amount))));
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear() - 2 * swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(2 * swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Near,
+        Some(U128(2 * swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(transfer_amount - 2 * swap_amount)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_swap_deposits() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let transfer_amount = 1000u128;
+    let swap_amount = 100u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for r#for in [
+        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = deployer
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+                "for": r#for,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &deployer,
+        U128(transfer_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(transfer_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let swap = async |deposit: NearToken| {
+        deployer
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(deposit)
+            .args_json(json!({
+                "dex_id": dex_id.clone(),
+                "message": BASE64_STANDARD.encode(vec![]),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+
+    // Exactly one yocto by default
+    let result = swap(NearToken::from_yoctonear(swap_amount)).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Requires attached deposit of exactly 1 yoctoNEAR")
+    );
+
+    let result = dex_engine_contract
+        .call("set_allow_swap_deposits")
+        .max_gas()
+        .args_json(json!({
+            "allow": true,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let allow_swap_deposits = dex_engine_contract
+        .view("allow_swap_deposits")
+        .args_json(json!({}))
+        .await
+        .unwrap()
+        .json::<bool>()
+        .unwrap();
+    assert!(allow_swap_deposits);
+
+    let result = swap(NearToken::from_yoctonear(0)).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Requires attached deposit of at least 1 yoctoNEAR")
+    );
+
+    let result = swap(NearToken::from_yoctonear(swap_amount)).await;
+    assert_success(&result).unwrap();
+    let result: (U128, U128) = result.json().unwrap();
+    assert_eq!(result, (U128(swap_amount), U128(swap_amount)));
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(&dex_engine_contract, AssetId::Near, Some(U128(swap_amount)))
+        .await
+        .unwrap();
+
+    // For ExactOut, the deposit is the maximum input, and the
+    // surplus is transferred back
+    let surplus = NearToken::from_millinear(1).as_yoctonear();
+    let near_before = deployer.view_account().await.unwrap().balance;
+    let mut total_near_burnt = NearToken::from_yoctonear(0);
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(swap_amount + surplus))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(vec![]),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactOut(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    let result: (U128, U128) = result.json().unwrap();
+    assert_eq!(result, (U128(swap_amount), U128(swap_amount)));
+    assert_near_balance(
+        &deployer,
+        near_before
+            .saturating_sub(NearToken::from_yoctonear(swap_amount))
+            .saturating_sub(total_near_burnt),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(swap_amount * 2)),
+    )
+    .await
+    .unwrap();
+    assert_total_in_custody(
+        &dex_engine_contract,
+        AssetId::Near,
+        Some(U128(swap_amount * 2)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_preflight() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = 1000u128;
+    let min_swap_amount = 10u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ft1,
+        ft2,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Account(deployer.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(initial_near_deposit))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_dex_supported_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "supported_assets": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = dex_engine_contract
+        .call("set_min_swap_amount")
+        .max_gas()
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+            "amount": U128(min_swap_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let preflight = async |dex_id: &DexId,
+                           trader: &near_workspaces::Account,
+                           asset_out: AssetId,
+                           amount_in: u128,
+                           attached_deposit: Option<NearToken>| {
+        dex_engine_contract
+            .view("preflight")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "request": {
+                    "message": "",
+                    "asset_in": AssetId::Near,
+                    "asset_out": asset_out,
+                    "amount": SwapRequestAmount::ExactIn(U128(amount_in)),
+                },
+                "trader": trader.id(),
+                "attached_deposit": attached_deposit,
+            }))
+            .await
+            .unwrap()
+            .json::<Option<EngineError>>()
+            .unwrap()
+    };
+    let ft1_asset = AssetId::Nep141(ft1.id().clone());
+
+    assert_eq!(
+        preflight(&dex_id, &deployer, ft1_asset.clone(), 100, None).await,
+        None
+    );
+
+    let unknown_dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "unknown".to_string(),
+    };
+    assert_eq!(
+        preflight(&unknown_dex_id, &deployer, ft1_asset.clone(), 100, None).await,
+        Some(EngineError::DexNotFound {
+            dex_id: unknown_dex_id.clone()
+        })
+    );
+    assert_eq!(
+        preflight(
+            &dex_id,
+            &deployer,
+            ft1_asset.clone(),
+            100,
+            Some(NearToken::from_yoctonear(100))
+        )
+        .await,
+        Some(EngineError::ExactlyOneYoctoRequired)
+    );
+    assert_eq!(
+        preflight(&dex_id, &deployer, AssetId::Near, 100, None).await,
+        Some(EngineError::InvalidRequest {
+            reason: "Asset in and asset out must be different, got near for both".to_string()
+        })
+    );
+    let ft2_asset = AssetId::Nep141(ft2.id().clone());
+    assert_eq!(
+        preflight(&dex_id, &deployer, ft2_asset.clone(), 100, None).await,
+        Some(EngineError::AssetNotSupported {
+            dex_id: dex_id.clone(),
+            asset_id: ft2_asset.clone()
+        })
+    );
+    assert_eq!(
+        preflight(
+            &dex_id,
+            &deployer,
+            ft1_asset.clone(),
+            min_swap_amount - 1,
+            None
+        )
+        .await,
+        Some(EngineError::BelowMinSwapAmount {
+            asset_id: AssetId::Near,
+            min_swap_amount: U128(min_swap_amount)
+        })
+    );
+    assert_eq!(
+        preflight(&dex_id, &user1, ft1_asset.clone(), 100, None).await,
+        Some(EngineError::AssetNotRegistered {
+            account_id: user1.id().clone(),
+            asset_id: AssetId::Near
+        })
+    );
+    assert_eq!(
+        preflight(
+            &dex_id,
+            &deployer,
+            ft1_asset.clone(),
+            initial_near_deposit + 1,
+            None
+        )
+        .await,
+        Some(EngineError::InsufficientBalance {
+            account_id: deployer.id().clone(),
+            asset_id: AssetId::Near,
+            balance: U128(initial_near_deposit),
+            amount: U128(initial_near_deposit + 1)
+        })
+    );
+
+    // With swap deposits, the attached NEAR counts towards the
+    // balance
+    let result = dex_engine_contract
+        .call("set_allow_swap_deposits")
+        .max_gas()
+        .args_json(json!({
+            "allow": true,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_eq!(
+        preflight(
+            &dex_id,
+            &deployer,
+            ft1_asset.clone(),
+            initial_near_deposit + 1,
+            Some(NearToken::from_yoctonear(1))
+        )
+        .await,
+        None
+    );
+    assert_eq!(
+        preflight(
+            &dex_id,
+            &deployer,
+            ft1_asset,
+            100,
+            Some(NearToken::from_yoctonear(0))
+        )
+        .await,
+        Some(EngineError::DepositRequired)
+    );
+}
+
+#[tokio::test]
+async fn test_max_swaps_per_block() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(20);
+    let transfer_amount = 1000u128;
+    let swap_amount = 10u128;
+    let max_swaps_per_block = 2;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.minimal_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Account(deployer.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &deployer,
+        U128(transfer_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(transfer_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "set_dex_max_swaps_per_block")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "max_swaps_per_block": max_swaps_per_block,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_dex_max_swaps_per_block")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "max_swaps_per_block": max_swaps_per_block,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let metadata = dex_engine_contract
+        .view("dex_metadata")
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .await
+        .unwrap()
+        .json::<near_sdk::serde_json::Value>()
+        .unwrap();
+    assert_eq!(metadata["max_swaps_per_block"], json!(max_swaps_per_block));
+
+    // All operations in one transaction are executed in the same block
+    let swaps = |count: usize| {
+        vec![
+            Operation::SwapSimple {
+                dex_id: dex_id.clone(),
+                message: Base64VecU8(vec![]),
+                asset_in: AssetId::Near,
+                asset_out: AssetId::Nep141(ft1.id().clone()),
+                amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(swap_amount))),
+                min_amount_out: None,
+                max_amount_in: None,
+            };
+            count
+        ]
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": swaps(max_swaps_per_block as usize + 1),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains(&format!(
+        "Dex {dex_id} reached the limit of {max_swaps_per_block} swaps per block"
+    )));
+
+    for _ in 0..2 {
+        let result = deployer
+            .call(dex_engine_contract.id(), "execute_operations")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "operations": swaps(max_swaps_per_block as usize),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(transfer_amount - 4 * swap_amount)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_regular_flow() {
+    let storage_deposit_amount = NearToken::from_near(5);
+    let initial_near_deposit = NearToken::from_near(20);
+    let ft_deposit_amount = 1_000_000_000u128;
+    let pool_creation_fee = NearToken::from_millinear(10);
+    let add_liquidity_near = NearToken::from_near(1);
+    let add_liquidity_ft = 1_000_000u128;
+    let swap_amount_in = NearToken::from_millinear(100);
+    let remove_liquidity_near = NearToken::from_millinear(500);
+    let remove_liquidity_ft = 500_000u128;
+    let withdraw_near_amount = NearToken::from_near(1);
+    let withdraw_ft_amount = 100_000_000u128;
+
+    let TestContext {
+        sandbox,
+        dex_engine_contract,
+        ft1,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.simple_amm_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(storage_deposit_amount)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let initial_near_balance = deployer.view_account().await.unwrap().balance;
+    let mut total_near_burnt = NearToken::from_yoctonear(0);
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        None,
+    )
+    .await
+    .unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Account(deployer.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    assert_near_balance(
+        &deployer,
+        initial_near_balance
+            .saturating_sub(initial_near_deposit)
+            .saturating_sub(total_near_burnt)
+            .saturating_sub(NearToken::from_yoctonear(2)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear())),
+    )
+    .await
+    .unwrap();
+
+    ft_storage_deposit_for(&ft1, ft1.as_account(), dex_engine_contract.id()).await;
+
+    let initial_ft_balance = ft1
+        .view("ft_balance_of")
+        .args_json(json!({
+            "account_id": deployer.id(),
+        }))
+        .await
+        .unwrap()
+        .json::<U128>()
+        .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+    let result = deployer
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_deposit_amount),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    assert_ft_balance(
+        &deployer,
+        ft1.clone(),
+        U128(initial_ft_balance.0 - ft_deposit_amount),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+
+    #[near(serializers=[borsh])]
+    struct CreatePoolArgs {
+        assets: (AssetId, AssetId),
+    }
+    type PoolId = u64;
+    #[near(serializers=[borsh])]
+    struct CreatePoolResponse {
+        pool_id: PoolId,
+    }
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(initial_near_deposit.as_yoctonear())),
+    )
+    .await
+    .unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "new",
+            "args": BASE64_STANDARD.encode([]),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    assert!(result.json::<Base64VecU8>().unwrap().0.is_empty());
+
+    let storage_usage_by_dex_before_pool_creation = dex_engine_contract
+        .view("dex_storage_balance_of")
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+        }))
+        .await
+        .unwrap()
+        .json::<StorageBalance>()
+        .unwrap();
+    assert_eq!(
+        storage_usage_by_dex_before_pool_creation.total,
+        NearToken::from_yoctonear(20000000000000000000000000),
+    );
+    let storage_usage_by_engine_before_pool_creation = dex_engine_contract
+        .view_account()
+        .await
+        .unwrap()
+        .storage_usage;
+
+    let near_for_create_pool = pool_creation_fee;
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "create_pool",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&CreatePoolArgs {
+                assets: (AssetId::Near, AssetId::Nep141(ft1.id().clone())),
+            }).unwrap()),
+            "attached_assets": {
+                "near": near_for_create_pool,
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    let result = result.json::<Base64VecU8>().unwrap();
+    let pool_id = near_sdk::borsh::from_slice::<CreatePoolResponse>(&result.0)
+        .unwrap()
+        .pool_id;
+
+    let storage_usage_by_dex_after_pool_creation = dex_engine_contract
+        .view("dex_storage_balance_of")
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+        }))
+        .await
+        .unwrap()
+        .json::<StorageBalance>()
+        .unwrap();
+    let storage_usage_by_engine_after_pool_creation = dex_engine_contract
+        .view_account()
+        .await
+        .unwrap()
+        .storage_usage;
+    assert_eq!(
+        storage_usage_by_dex_after_pool_creation.total,
+        NearToken::from_yoctonear(20002100000000000000000000),
+    );
+
+    let pool_storage_cost = storage_usage_by_dex_after_pool_creation
+        .total
+        .checked_sub(storage_usage_by_dex_before_pool_creation.total)
+        .unwrap();
+    assert_eq!(
+        storage_usage_by_dex_before_pool_creation.available,
+        storage_usage_by_dex_after_pool_creation.available,
+    );
+    assert_eq!(
+        near_sdk::env::storage_byte_cost()
+            .checked_mul(
+                storage_usage_by_engine_after_pool_creation
+                    .checked_sub(storage_usage_by_engine_before_pool_creation)
+                    .unwrap() as u128,
+            )
+            .unwrap(),
+        pool_storage_cost,
+    );
+
+    assert_near_balance(
+        &deployer,
+        initial_near_balance
+            .saturating_sub(total_near_burnt)
+            .saturating_sub(initial_near_deposit)
+            .saturating_sub(NearToken::from_yoctonear(5)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear() - pool_storage_cost.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+
+    #[near(serializers=[borsh])]
+    struct AddLiquidityArgs {
+        pool_id: PoolId,
+    }
+    #[near(serializers=[borsh])]
+    struct AddLiquidityResponse;
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount)),
+    )
+    .await
+    .unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "add_liquidity",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&AddLiquidityArgs {
+                pool_id,
+            }).unwrap()),
+            "attached_assets": {
+                "near": U128(add_liquidity_near.as_yoctonear()),
+                format!("nep141:{}", ft1.id()): U128(add_liquidity_ft),
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    let response = result.json::<Base64VecU8>().unwrap();
+    let _ = near_sdk::borsh::from_slice::<AddLiquidityResponse>(&response.0).unwrap();
+    assert_near_balance(
+        &deployer,
+        initial_near_balance
+            .saturating_sub(total_near_burnt)
+            .saturating_sub(initial_near_deposit)
+            .saturating_sub(NearToken::from_yoctonear(6)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear()
+                - pool_storage_cost.as_yoctonear()
+                - add_liquidity_near.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - add_liquidity_ft)),
+    )
+    .await
+    .unwrap();
+
+    #[near(serializers=[borsh])]
+    struct SwapArgs {
+        pool_id: PoolId,
+    }
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - add_liquidity_ft)),
+    )
+    .await
+    .unwrap();
+
+    let quote_amounts = [
+        swap_amount_in.as_yoctonear(),
+        swap_amount_in.as_yoctonear(),
+        swap_amount_in.as_yoctonear() * 2,
+        swap_amount_in.as_yoctonear() * 5,
+    ];
+    let quotes = dex_engine_contract
+        .view("quote_batch")
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "requests": quote_amounts.map(|amount| SwapRequest {
+                message: Base64VecU8(near_sdk::borsh::to_vec(&SwapArgs { pool_id }).unwrap()),
+                asset_in: AssetId::Near,
+                asset_out: AssetId::Nep141(ft1.id().clone()),
+                amount: SwapRequestAmount::ExactIn(U128(amount)),
+            }),
+        }))
+        .await
+        .unwrap()
+        .json::<Vec<SwapResponse>>()
+        .unwrap();
+    assert_eq!(quotes.len(), quote_amounts.len());
+    for (quote, amount) in quotes.iter().zip(quote_amounts) {
+        assert_eq!(quote.amount_in, U128(amount));
+    }
+    // Quotes don't affect each other
+    assert_eq!(quotes[0].amount_out, U128(90909));
+    assert_eq!(quotes[1].amount_out, quotes[0].amount_out);
+    assert!(quotes[2].amount_out.0 > quotes[1].amount_out.0);
+    assert!(quotes[3].amount_out.0 > quotes[2].amount_out.0);
+
+    let curve = dex_engine_contract
+        .view("estimate_output_curve")
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&SwapArgs { pool_id }).unwrap()),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amounts": (1..=5)
+                .map(|step| U128(swap_amount_in.as_yoctonear() * step))
+                .collect::<Vec<_>>(),
+        }))
+        .await
+        .unwrap()
+        .json::<Vec<U128>>()
+        .unwrap();
+    assert_eq!(curve.len(), 5);
+    assert_eq!(curve[0], quotes[0].amount_out);
+    // Constant product: more in gives more out, but each step of
+    // the same size gives less than the previous one
+    let increments = curve
+        .windows(2)
+        .map(|pair| pair[1].0.checked_sub(pair[0].0).unwrap())
+        .collect::<Vec<_>>();
+    assert!(increments.iter().all(|increment| *increment > 0));
+    assert!(increments.windows(2).all(|pair| pair[1] < pair[0]));
+    assert!(increments[0] < curve[0].0);
+
+    // The storage key of a simple-amm pool is the `Pools` prefix
+    // followed by the pool id
+    let pool_key = [&[0][..], &pool_id.to_le_bytes()].concat();
+    let hypothetical_pool = SimplePool {
+        assets: (
+            AssetWithBalance {
+                asset_id: AssetId::Near,
+                balance: U128(add_liquidity_near.as_yoctonear()),
+            },
+            AssetWithBalance {
+                asset_id: AssetId::Nep141(ft1.id().clone()),
+                balance: U128(add_liquidity_ft * 2),
+            },
+        ),
+        owner_id: deployer.id().clone(),
+    };
+    let simulate = async |storage_overrides: Vec<(Base64VecU8, Base64VecU8)>| {
+        dex_engine_contract
+            .view("simulate_swap_with_state")
+            .args_json(json!({
+                "dex_id": dex_id.clone(),
+                "request": SwapRequest {
+                    message: Base64VecU8(
+                        near_sdk::borsh::to_vec(&SwapArgs { pool_id }).unwrap(),
+                    ),
+                    asset_in: AssetId::Near,
+                    asset_out: AssetId::Nep141(ft1.id().clone()),
+                    amount: SwapRequestAmount::ExactIn(U128(swap_amount_in.as_yoctonear())),
+                },
+                "storage_overrides": storage_overrides,
+            }))
+            .await
+    };
+    let response = simulate(vec![(
+        Base64VecU8(pool_key.clone()),
+        Base64VecU8(near_sdk::borsh::to_vec(&hypothetical_pool).unwrap()),
+    )])
+    .await
+    .unwrap()
+    .json::<SwapResponse>()
+    .unwrap();
+    assert_eq!(response.amount_out, U128(90909 * 2));
+    let err = simulate(vec![
+        (Base64VecU8(pool_key.clone()), Base64VecU8(vec![])),
+        (Base64VecU8(pool_key), Base64VecU8(vec![])),
+    ])
+    .await
+    .unwrap_err();
+    assert!(format!("{err:?}").contains("Duplicate key in storage overrides"));
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "message": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&SwapArgs {
+                pool_id,
+            }).unwrap()),
+            "asset_in": AssetId::Near,
+            "asset_out": AssetId::Nep141(ft1.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount_in.as_yoctonear())),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    let result: (U128, U128) = result.json().unwrap();
+    assert_eq!(result, (U128(100000000000000000000000), U128(90909)));
+    assert_near_balance(
+        &deployer,
+        initial_near_balance
+            .saturating_sub(total_near_burnt)
+            .saturating_sub(initial_near_deposit)
+            .saturating_sub(NearToken::from_yoctonear(7)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear()
+                - pool_storage_cost.as_yoctonear()
+                - add_liquidity_near.as_yoctonear()
+                - swap_amount_in.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(ft_deposit_amount - add_liquidity_ft + 90909)),
+    )
+    .await
+    .unwrap();
+
+    #[near(serializers=[borsh])]
+    struct RemoveLiquidityArgs {
+        pool_id: PoolId,
+        assets_to_remove: (U128, U128),
+    }
+    #[near(serializers=[borsh])]
+    struct RemoveLiquidityResponse;
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "remove_liquidity",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&RemoveLiquidityArgs {
+                pool_id,
+                assets_to_remove: (U128(remove_liquidity_near.as_yoctonear()), U128(remove_liquidity_ft)),
+            }).unwrap()),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    let response = result.json::<Base64VecU8>().unwrap();
+    let _ = near_sdk::borsh::from_slice::<RemoveLiquidityResponse>(&response.0).unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear()
+                - pool_storage_cost.as_yoctonear()
+                - add_liquidity_near.as_yoctonear()
+                - swap_amount_in.as_yoctonear()
+                + remove_liquidity_near.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(
+            ft_deposit_amount - add_liquidity_ft + 90909 + remove_liquidity_ft,
+        )),
+    )
+    .await
+    .unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Near,
+            "amount": U128(withdraw_near_amount.as_yoctonear()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    assert!(result.json::<bool>().unwrap());
+    sandbox.fast_forward(1).await.unwrap();
+    assert_near_balance(
+        &deployer,
+        initial_near_balance
+            .saturating_sub(total_near_burnt)
+            .saturating_sub(initial_near_deposit)
+            .saturating_sub(NearToken::from_yoctonear(9))
+            .saturating_add(withdraw_near_amount),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Near,
+        Some(U128(
+            initial_near_deposit.as_yoctonear()
+                - pool_storage_cost.as_yoctonear()
+                - add_liquidity_near.as_yoctonear()
+                - swap_amount_in.as_yoctonear()
+                + remove_liquidity_near.as_yoctonear()
+                - withdraw_near_amount.as_yoctonear(),
+        )),
+    )
+    .await
+    .unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(withdraw_ft_amount),
+            "withdraw_to": null,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    track_tokens_burnt(&result, &mut total_near_burnt);
+    assert!(result.json::<bool>().unwrap());
+    assert_ft_balance(
+        &deployer,
+        ft1.clone(),
+        U128(initial_ft_balance.0 - ft_deposit_amount + withdraw_ft_amount),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(
+            ft_deposit_amount - add_liquidity_ft + 90909 + remove_liquidity_ft - withdraw_ft_amount,
+        )),
+    )
+    .await
+    .unwrap();
+
+    #[near(serializers=[borsh])]
+    struct GetPoolArgs {
+        pool_id: PoolId,
+    }
+    #[derive(PartialEq, Debug)]
+    #[near(serializers=[borsh])]
+    struct SimplePool {
+        assets: (AssetWithBalance, AssetWithBalance),
+        owner_id: AccountId,
+    }
+    #[derive(PartialEq, Debug)]
+    #[near(serializers=[borsh])]
+    struct AssetWithBalance {
+        asset_id: AssetId,
+        balance: U128,
+    }
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "get_pool",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&GetPoolArgs {
+                pool_id,
+            }).unwrap()),
+        }))
+        .await
+        .unwrap();
+    let pool_view_result = result.json::<Base64VecU8>().unwrap();
+    let pool: Option<SimplePool> = near_sdk::borsh::from_slice(&pool_view_result.0).unwrap();
+    assert_eq!(
+        pool,
+        Some(SimplePool {
+            assets: (
+                AssetWithBalance {
+                    asset_id: AssetId::Near,
+                    balance: U128(
+                        add_liquidity_near.as_yoctonear() + swap_amount_in.as_yoctonear()
+                            - remove_liquidity_near.as_yoctonear()
+                    ),
+                },
+                AssetWithBalance {
+                    asset_id: AssetId::Nep141(ft1.id().clone()),
+                    balance: U128(add_liquidity_ft - 90909 - remove_liquidity_ft),
+                },
+            ),
+            owner_id: deployer.id().clone(),
+        })
+    );
+
+    #[near(serializers=[borsh])]
+    struct GetPoolPriceArgs {
+        pool_id: PoolId,
+        base: AssetId,
+        quote: AssetId,
+    }
+    let get_pool_price = async |pool_id: PoolId, base: AssetId, quote: AssetId| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id.clone(),
+                "method": "get_pool_price",
+                "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&GetPoolPriceArgs {
+                    pool_id,
+                    base,
+                    quote,
+                }).unwrap()),
+            }))
+            .await
+            .unwrap();
+        let price = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<Option<U128>>(&price.0).unwrap()
+    };
+    let near_reserve = add_liquidity_near.as_yoctonear() + swap_amount_in.as_yoctonear()
+        - remove_liquidity_near.as_yoctonear();
+    let ft_reserve = add_liquidity_ft - 90909 - remove_liquidity_ft;
+    let price_scale = 10u128.pow(24);
+    assert_eq!(
+        get_pool_price(pool_id, AssetId::Nep141(ft1.id().clone()), AssetId::Near).await,
+        Some(U128(near_reserve * price_scale / ft_reserve))
+    );
+    assert_eq!(
+        get_pool_price(pool_id, AssetId::Near, AssetId::Nep141(ft1.id().clone())).await,
+        Some(U128(ft_reserve * price_scale / near_reserve))
+    );
+    assert_eq!(
+        get_pool_price(
+            pool_id,
+            AssetId::Near,
+            AssetId::Nep141(deployer.id().clone())
+        )
+        .await,
+        None
+    );
+    assert_eq!(
+        get_pool_price(
+            pool_id + 1,
+            AssetId::Near,
+            AssetId::Nep141(ft1.id().clone())
+        )
+        .await,
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_ft_deposit_swap_withdraw_round_trip() {
+    let liquidity_amount = 1000u128;
+    let trader_deposit_amount = 500u128;
+    let swap_amount = 200u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1: trader,
+        ft1,
+        ft2,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.sandbox_test_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for account in [&deployer, &trader] {
+        let result = account
+            .call(dex_engine_contract.id(), "storage_deposit")
+            .max_gas()
+            .deposit(engine_user_storage_deposit())
+            .args_json(json!({}))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // Liquidity of the dex
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone()), AssetId::Nep141(ft2.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft2,
+        &deployer,
+        &deployer,
+        U128(liquidity_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "amount": U128(liquidity_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // The trader deposits ft1 with ft_transfer_call
+    engine_ft_deposit(
+        &dex_engine_contract,
+        &ft1,
+        &deployer,
+        &trader,
+        U128(trader_deposit_amount),
+    )
+    .await;
+    assert_ft_balance(&trader, ft1.clone(), U128(0))
+        .await
+        .unwrap();
+    let result = trader
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft2.id().clone())],
+            "for": AccountOrDexId::Account(trader.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = trader
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "message": "",
+            "asset_in": AssetId::Nep141(ft1.id().clone()),
+            "asset_out": AssetId::Nep141(ft2.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_eq!(
+        result.json::<(U128, U128)>().unwrap(),
+        (U128(swap_amount), U128(swap_amount))
+    );
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(trader.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(trader_deposit_amount - swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(trader.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(swap_amount)),
+    )
+    .await
+    .unwrap();
+
+    // The trader withdraws the output to their wallet
+    ft_storage_deposit(&ft2, &trader).await;
+    let result = trader
+        .call(dex_engine_contract.id(), "withdraw")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "amount": U128(swap_amount),
+            "withdraw_to": null,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(result.json::<bool>().unwrap());
+
+    assert_ft_balance(&trader, ft1.clone(), U128(0))
+        .await
+        .unwrap();
+    assert_ft_balance(&trader, ft2.clone(), U128(swap_amount))
+        .await
+        .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(trader.id().clone()),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(0)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id.clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(swap_amount)),
+    )
+    .await
+    .unwrap();
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Dex(dex_id),
+        AssetId::Nep141(ft2.id().clone()),
+        Some(U128(liquidity_amount - swap_amount)),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_swap_slippage() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1: trader,
+        ft1,
+        ft2,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    for account in [&deployer, &trader] {
+        let result = account
+            .call(dex_engine_contract.id(), "storage_deposit")
+            .max_gas()
+            .deposit(engine_user_storage_deposit())
+            .args_json(json!({}))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
     }
-    #[near(serializers=[borsh])]
-    struct RemoveLiquidityResponse;
     let result = deployer
-        .call(dex_engine_contract.id(), "dex_call")
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&get_compiled_wasms().await.sandbox_test_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone()), AssetId::Nep141(ft2.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    engine_ft_deposit(&dex_engine_contract, &ft2, &deployer, &deployer, U128(1000)).await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "amount": U128(1000),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    engine_ft_deposit(&dex_engine_contract, &ft1, &deployer, &trader, U128(500)).await;
+    let result = trader
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft2.id().clone())],
+            "for": AccountOrDexId::Account(trader.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // The dex swaps 1:1
+    let swap = async |amount: SwapRequestAmount,
+                      min_amount_out: Option<U128>,
+                      max_amount_in: Option<U128>| {
+        trader
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": "",
+                "asset_in": AssetId::Nep141(ft1.id().clone()),
+                "asset_out": AssetId::Nep141(ft2.id().clone()),
+                "amount": amount,
+                "min_amount_out": min_amount_out,
+                "max_amount_in": max_amount_in,
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let trader_balance = async |asset_id: AssetId| {
+        dex_engine_contract
+            .view("asset_balance_of")
+            .args_json(json!({
+                "of": AccountOrDexId::Account(trader.id().clone()),
+                "asset_id": asset_id,
+            }))
+            .await
+            .unwrap()
+            .json::<Option<U128>>()
+            .unwrap()
+    };
+
+    let result = swap(SwapRequestAmount::ExactIn(U128(100)), Some(U128(101)), None).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Slippage exceeded: amount out 100 is less than the minimum of 101")
+    );
+    let result = swap(SwapRequestAmount::ExactOut(U128(100)), None, Some(U128(99))).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Slippage exceeded: amount in 100 is more than the maximum of 99")
+    );
+
+    // Same bounds in swap_many and execute_operations
+    let result = trader
+        .call(dex_engine_contract.id(), "swap_many")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "swaps": [{
+                "dex_id": dex_id,
+                "request": {
+                    "message": "",
+                    "asset_in": AssetId::Nep141(ft1.id().clone()),
+                    "asset_out": AssetId::Nep141(ft2.id().clone()),
+                    "amount": SwapRequestAmount::ExactIn(U128(100)),
+                },
+                "min_amount_out": U128(101),
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let results = result.json::<Vec<Result<(U128, U128), String>>>().unwrap();
+    assert!(results[0].as_ref().is_err_and(|err| {
+        err.contains("Slippage exceeded: amount out 100 is less than the minimum of 101")
+    }));
+    let result = trader
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [Operation::SwapSimple {
+                dex_id: dex_id.clone(),
+                message: Base64VecU8(vec![]),
+                asset_in: AssetId::Nep141(ft1.id().clone()),
+                asset_out: AssetId::Nep141(ft2.id().clone()),
+                amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactOut(U128(100))),
+                min_amount_out: None,
+                max_amount_in: Some(U128(99)),
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Slippage exceeded: amount in 100 is more than the maximum of 99")
+    );
+    assert_eq!(
+        trader_balance(AssetId::Nep141(ft1.id().clone())).await,
+        Some(U128(500))
+    );
+    assert_eq!(
+        trader_balance(AssetId::Nep141(ft2.id().clone())).await,
+        Some(U128(0))
+    );
+
+    let result = swap(SwapRequestAmount::ExactIn(U128(100)), Some(U128(100)), None).await;
+    assert_success(&result).unwrap();
+    assert_eq!(
+        result.json::<(U128, U128)>().unwrap(),
+        (U128(100), U128(100))
+    );
+    let result = swap(
+        SwapRequestAmount::ExactOut(U128(100)),
+        None,
+        Some(U128(100)),
+    )
+    .await;
+    assert_success(&result).unwrap();
+    assert_eq!(
+        trader_balance(AssetId::Nep141(ft1.id().clone())).await,
+        Some(U128(300))
+    );
+    assert_eq!(
+        trader_balance(AssetId::Nep141(ft2.id().clone())).await,
+        Some(U128(200))
+    );
+
+    // The price impact is checked against the reserves the dex
+    // reported before the swap, and not checked if there are none
+    let swap_with_price_impact_cap = async |message: &[u8], max_price_impact_bps: u16| {
+        trader
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": BASE64_STANDARD.encode(message),
+                "asset_in": AssetId::Nep141(ft1.id().clone()),
+                "asset_out": AssetId::Nep141(ft2.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(100)),
+                "max_price_impact_bps": max_price_impact_bps,
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let result = swap_with_price_impact_cap(b"", 0).await;
+    assert_success(&result).unwrap();
+    assert_eq!(
+        result.json::<(U128, U128)>().unwrap(),
+        (U128(100), U128(100))
+    );
+
+    let reserves = vec![
+        (AssetId::Nep141(ft1.id().clone()), U128(1000)),
+        (AssetId::Nep141(ft2.id().clone()), U128(1000)),
+    ];
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "set_pool_reserves",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&(0u64, reserves)).unwrap()),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // 100 into a 1000/1000 pool gives 90, 10% worse than the spot
+    // price
+    let result = swap_with_price_impact_cap(b"constant_product", 999).await;
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Price impact exceeded: 1000 bps is more than the maximum of 999 bps")
+    );
+    let result = swap_with_price_impact_cap(b"constant_product", 1000).await;
+    assert_success(&result).unwrap();
+    assert_eq!(
+        result.json::<(U128, U128)>().unwrap(),
+        (U128(100), U128(90))
+    );
+    assert_eq!(
+        trader_balance(AssetId::Nep141(ft2.id().clone())).await,
+        Some(U128(390))
+    );
+}
+
+#[tokio::test]
+async fn test_swap_deadline() {
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        ft2,
+        user1,
+        ..
+    } = setup_test_environment().await;
+
+    // The deadline is checked before anything else, so even a call
+    // without the required yocto fails on it
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .args_json(json!({
+            "dex_id": DexId {
+                deployer: user1.id().clone(),
+                id: "dex".to_string(),
+            },
+            "message": "",
+            "asset_in": AssetId::Nep141(ft1.id().clone()),
+            "asset_out": AssetId::Nep141(ft2.id().clone()),
+            "amount": SwapRequestAmount::ExactIn(U128(100)),
+            "deadline_ns": 1,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Deadline exceeded: block timestamp"));
+    assert!(format!("{:?}", result.failures()).contains("is after the deadline of 1"));
+}
+
+#[tokio::test]
+async fn test_swap_is_deterministic() {
+    let TestContext {
+        sandbox,
+        dex_engine_contract,
+        deployer,
+        user1: trader,
+        ft1,
+        ft2,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    for account in [&deployer, &trader] {
+        let result = account
+            .call(dex_engine_contract.id(), "storage_deposit")
+            .max_gas()
+            .deposit(engine_user_storage_deposit())
+            .args_json(json!({}))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&get_compiled_wasms().await.sandbox_test_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft1.id().clone()), AssetId::Nep141(ft2.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    engine_ft_deposit(&dex_engine_contract, &ft2, &deployer, &deployer, U128(1000)).await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft2.id().clone()),
+            "amount": U128(1000),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    engine_ft_deposit(&dex_engine_contract, &ft1, &deployer, &trader, U128(500)).await;
+    let result = trader
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Nep141(ft2.id().clone())],
+            "for": AccountOrDexId::Account(trader.id().clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let swap = || async {
+        let result = trader
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": BASE64_STANDARD.encode(b"record_assets"),
+                "asset_in": AssetId::Nep141(ft1.id().clone()),
+                "asset_out": AssetId::Nep141(ft2.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(100)),
+                "referral": "ref",
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let outcome = result
+            .receipt_outcomes()
+            .iter()
+            .find(|outcome| &outcome.executor_id == dex_engine_contract.id())
+            .unwrap();
+        let block_height = sandbox
+            .view_block()
+            .block_hash(outcome.block_hash)
+            .await
+            .unwrap()
+            .height();
+        (result, block_height)
+    };
+    let engine_state = || async {
+        sandbox
+            .view_state(dex_engine_contract.id())
+            .await
+            .unwrap()
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    // The first swap creates all keys that a swap writes, so that
+    // reverting only has to restore values
+    swap().await.0.into_result().unwrap();
+    let state_before = engine_state().await;
+
+    let (result_1, block_height_1) = swap().await;
+    let state_1 = engine_state().await;
+    assert_ne!(state_1, state_before);
+
+    sandbox
+        .patch(dex_engine_contract.id())
+        .states(
+            state_before
+                .iter()
+                .map(|(key, value)| (key.as_slice(), value.as_slice())),
+        )
+        .transact()
+        .await
+        .unwrap();
+    assert_eq!(engine_state().await, state_before);
+
+    let (result_2, block_height_2) = swap().await;
+    let state_2 = engine_state().await;
+    assert_ne!(block_height_1, block_height_2);
+
+    // The block height of the last swap is the only input that
+    // differs between the two runs
+    let height_1 = block_height_1.to_le_bytes();
+    let height_2 = block_height_2.to_le_bytes();
+    let state_2 = state_2
+        .into_iter()
+        .map(|(key, mut value)| {
+            for i in 0..value.len().saturating_sub(height_2.len() - 1) {
+                if value[i..i + height_2.len()] == height_2 {
+                    value[i..i + height_2.len()].copy_from_slice(&height_1);
+                }
+            }
+            (key, value)
+        })
+        .collect::<BTreeMap<_, _>>();
+    for key in state_1.keys().chain(state_2.keys()) {
+        assert_eq!(
+            state_1.get(key),
+            state_2.get(key),
+            "State differs at key {}",
+            BASE64_STANDARD.encode(key)
+        );
+    }
+    assert_eq!(result_1.logs(), result_2.logs());
+    assert_eq!(swap_events(&result_1), swap_events(&result_2));
+    assert_eq!(
+        result_1.json::<(U128, U128)>().unwrap(),
+        result_2.json::<(U128, U128)>().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_swap_unknown_pool() {
+    let TestContext {
+        dex_engine_contract,
+        ft1,
+        deployer,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "dex_id": dex_id.clone(),
-            "method": "remove_liquidity",
-            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&RemoveLiquidityArgs {
-                pool_id,
-                assets_to_remove: (U128(remove_liquidity_near.as_yoctonear()), U128(remove_liquidity_ft)),
-            }).unwrap()),
-            "attached_assets": {},
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.simple_amm_dex_wasm),
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    let response = result.json::<Base64VecU8>().unwrap();
-    let _ = near_sdk::borsh::from_slice::<RemoveLiquidityResponse>(&response.0).unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Near,
-        Some(U128(
-            initial_near_deposit.as_yoctonear()
-                - pool_storage_cost.as_yoctonear()
-                - add_liquidity_near.as_yoctonear()
-                - swap_amount_in.as_yoctonear()
-                + remove_liquidity_near.as_yoctonear(),
-        )),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(
-            ft_deposit_amount - add_liquidity_ft + 90909 + remove_liquidity_ft,
-        )),
-    )
-    .await
-    .unwrap();
 
-    let result = deployer
-        .call(dex_engine_contract.id(), "withdraw")
+    // No pools were created, so any id is unknown, including the
+    // first one that will be assigned
+    for pool_id in [0u64, 1, 42] {
+        let result = user1
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&pool_id).unwrap()),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(100)),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert!(result.is_failure());
+        assert!(format!("{:?}", result.failures()).contains("Dex panicked: Pool not found"));
+    }
+}
+
+#[tokio::test]
+async fn test_near_token_amounts() {
+    let deposit = NearToken::from_millinear(5);
+    let withdrawal = NearToken::from_millinear(2);
+
+    let TestContext {
+        dex_engine_contract,
+        user1: user,
+        ..
+    } = setup_test_environment().await;
+
+    let result = user
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user
+        .call(dex_engine_contract.id(), "register_assets")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_id": AssetId::Near,
-            "amount": U128(withdraw_near_amount.as_yoctonear()),
+            "asset_ids": [AssetId::Near],
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
-    assert!(result.json::<bool>().unwrap());
-    sandbox.fast_forward(1).await.unwrap();
-    assert_near_balance(
-        &deployer,
-        initial_near_balance
-            .saturating_sub(total_near_burnt)
-            .saturating_sub(initial_near_deposit)
-            .saturating_sub(NearToken::from_yoctonear(9))
-            .saturating_add(withdraw_near_amount),
-    )
-    .await
-    .unwrap();
+
+    let near_balance_of = async || {
+        dex_engine_contract
+            .view("near_balance_of")
+            .args_json(json!({
+                "of": AccountOrDexId::Account(user.id().clone()),
+            }))
+            .await
+            .unwrap()
+            .json::<Option<NearToken>>()
+            .unwrap()
+    };
+
+    let result = user
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
     assert_inner_asset_balance(
         &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Account(user.id().clone()),
         AssetId::Near,
-        Some(U128(
-            initial_near_deposit.as_yoctonear()
-                - pool_storage_cost.as_yoctonear()
-                - add_liquidity_near.as_yoctonear()
-                - swap_amount_in.as_yoctonear()
-                + remove_liquidity_near.as_yoctonear()
-                - withdraw_near_amount.as_yoctonear(),
-        )),
+        Some(U128(5_000_000_000_000_000_000_000)),
     )
     .await
     .unwrap();
+    assert_eq!(near_balance_of().await, Some(deposit));
 
-    let result = deployer
-        .call(dex_engine_contract.id(), "withdraw")
+    let result = user
+        .call(dex_engine_contract.id(), "withdraw_near")
         .max_gas()
         .deposit(NearToken::from_yoctonear(1))
         .args_json(json!({
-            "asset_id": AssetId::Nep141(ft1.id().clone()),
-            "amount": U128(withdraw_ft_amount),
-            "withdraw_to": null,
+            "amount": withdrawal,
         }))
         .transact()
         .await
         .unwrap();
     assert_success(&result).unwrap();
-    track_tokens_burnt(&result, &mut total_near_burnt);
     assert!(result.json::<bool>().unwrap());
-    assert_ft_balance(
-        &deployer,
-        ft1.clone(),
-        U128(initial_ft_balance.0 - ft_deposit_amount + withdraw_ft_amount),
-    )
-    .await
-    .unwrap();
-    assert_inner_asset_balance(
-        &dex_engine_contract,
-        AccountOrDexId::Account(deployer.id().clone()),
-        AssetId::Nep141(ft1.id().clone()),
-        Some(U128(
-            ft_deposit_amount - add_liquidity_ft + 90909 + remove_liquidity_ft - withdraw_ft_amount,
-        )),
-    )
-    .await
-    .unwrap();
+    assert_eq!(
+        near_balance_of().await,
+        Some(deposit.saturating_sub(withdrawal))
+    );
+}
 
-    #[near(serializers=[borsh])]
-    struct GetPoolArgs {
-        pool_id: PoolId,
+#[tokio::test]
+async fn test_dex_assets() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ft2,
+        ft3,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: "dex".to_string(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let dex_assets = async || {
+        dex_engine_contract
+            .view("dex_assets")
+            .args_json(json!({
+                "dex_id": dex_id,
+            }))
+            .await
+            .unwrap()
+            .json::<Vec<AssetId>>()
+            .unwrap()
+    };
+    assert_eq!(dex_assets().await, vec![]);
+
+    let assets = [
+        AssetId::Nep141(ft2.id().clone()),
+        AssetId::Near,
+        AssetId::Nep141(ft1.id().clone()),
+    ];
+    for asset_ids in [&assets[..2], &assets[1..]] {
+        for r#for in [
+            AccountOrDexId::Dex(dex_id.clone()),
+            AccountOrDexId::Account(deployer.id().clone()),
+        ] {
+            let result = deployer
+                .call(dex_engine_contract.id(), "register_assets")
+                .max_gas()
+                .deposit(NearToken::from_yoctonear(1))
+                .args_json(json!({
+                    "asset_ids": asset_ids,
+                    "for": r#for,
+                }))
+                .transact()
+                .await
+                .unwrap();
+            assert_success(&result).unwrap();
+        }
     }
-    #[derive(PartialEq, Debug)]
-    #[near(serializers=[borsh])]
-    struct SimplePool {
-        assets: (AssetWithBalance, AssetWithBalance),
-        owner_id: AccountId,
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(100))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    for ft in [&ft1, &ft2] {
+        engine_ft_deposit(&dex_engine_contract, ft, &deployer, &deployer, U128(100)).await;
     }
-    #[derive(PartialEq, Debug)]
-    #[near(serializers=[borsh])]
-    struct AssetWithBalance {
-        asset_id: AssetId,
-        balance: U128,
+    for asset_id in &assets {
+        let result = deployer
+            .call(dex_engine_contract.id(), "transfer_asset")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "to": AccountOrDexId::Dex(dex_id.clone()),
+                "asset_id": asset_id,
+                "amount": U128(100),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
     }
-    let result = dex_engine_contract
-        .view("dex_view")
+
+    assert_eq!(
+        dex_assets().await,
+        vec![
+            AssetId::Near,
+            AssetId::Nep141(ft1.id().clone()),
+            AssetId::Nep141(ft2.id().clone()),
+        ]
+    );
+
+    let dex_balances = dex_engine_contract
+        .view("dex_balances")
         .args_json(json!({
-            "dex_id": dex_id.clone(),
-            "method": "get_pool",
-            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&GetPoolArgs {
-                pool_id,
-            }).unwrap()),
+            "dex_id": dex_id,
+            "asset_ids": [
+                AssetId::Nep141(ft3.id().clone()),
+                AssetId::Near,
+                AssetId::Nep141(ft2.id().clone()),
+            ],
         }))
         .await
+        .unwrap()
+        .json::<Vec<U128>>()
         .unwrap();
-    let pool_view_result = result.json::<Base64VecU8>().unwrap();
-    let pool: Option<SimplePool> = near_sdk::borsh::from_slice(&pool_view_result.0).unwrap();
-    assert_eq!(
-        pool,
-        Some(SimplePool {
-            assets: (
-                AssetWithBalance {
-                    asset_id: AssetId::Near,
-                    balance: U128(
-                        add_liquidity_near.as_yoctonear() + swap_amount_in.as_yoctonear()
-                            - remove_liquidity_near.as_yoctonear()
-                    ),
-                },
-                AssetWithBalance {
-                    asset_id: AssetId::Nep141(ft1.id().clone()),
-                    balance: U128(add_liquidity_ft - 90909 - remove_liquidity_ft),
-                },
-            ),
-            owner_id: deployer.id().clone(),
-        })
-    );
+    assert_eq!(dex_balances, vec![U128(0), U128(100), U128(100)]);
 }
@@ -0,0 +1,1983 @@
+mod common;
+use common::*;
+
+use intear_dex::internal_asset_operations::AccountOrDexId;
+use intear_dex::internal_operations::{DEX_FUEL_LIMIT, Operation, SwapOperationAmount};
+use intear_dex_types::{AssetId, DexId, SwapRequestAmount};
+use near_sdk::serde_json::json;
+use near_sdk::{
+    NearToken,
+    base64::{Engine, prelude::BASE64_STANDARD},
+    json_types::{Base64VecU8, U128},
+};
+use near_workspaces::{Account, Contract};
+use std::collections::HashMap;
+
+async fn deploy_sandbox_test_dex(
+    dex_engine_contract: &Contract,
+    deployer: &Account,
+    last_part_of_id: &str,
+) -> DexId {
+    let wasms = get_compiled_wasms().await;
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: last_part_of_id.to_string(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(NearToken::from_near(5))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id.id,
+            "code_base64": BASE64_STANDARD.encode(&wasms.sandbox_test_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    dex_id
+}
+
+/// Registers NEAR and `ft1` for the deployer and the dex, deposits
+/// NEAR for the deployer, and gives the dex `ft_amount` of `ft1`.
+async fn fund_sandbox_test_swaps(
+    dex_engine_contract: &Contract,
+    deployer: &Account,
+    ft1: &Contract,
+    dex_id: &DexId,
+    ft_amount: u128,
+) {
+    for r#for in [
+        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = deployer
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+                "for": r#for,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_near(20))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    engine_ft_deposit(
+        dex_engine_contract,
+        ft1,
+        deployer,
+        deployer,
+        U128(ft_amount),
+    )
+    .await;
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Nep141(ft1.id().clone()),
+            "amount": U128(ft_amount),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+}
+
+#[tokio::test]
+async fn test_fuel_remaining() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let reserve = DEX_FUEL_LIMIT - 1_000_000;
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "work_until_fuel_low",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&reserve).unwrap()),
+        }))
+        .await
+        .unwrap();
+    let response = result.json::<Base64VecU8>().unwrap();
+    let (iterations, fuel_remaining): (u64, u64) =
+        near_sdk::borsh::from_slice(&response.0).unwrap();
+    assert!(iterations > 0);
+    assert!(fuel_remaining <= reserve);
+    assert!(fuel_remaining > 0);
+}
+
+#[tokio::test]
+async fn test_out_of_fuel() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    // never stops on its own
+    let reserve = 0u64;
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "work_until_fuel_low",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&reserve).unwrap()),
+        }))
+        .await;
+    let err = result.unwrap_err();
+    assert!(format!("{err:?}").contains("Dex ran out of fuel"));
+}
+
+#[tokio::test]
+async fn test_promise_return_not_supported() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "return_via_promise",
+            "args": "",
+        }))
+        .await;
+    let err = result.unwrap_err();
+    assert!(format!("{err:?}").contains("promise_return is not supported"));
+}
+
+#[tokio::test]
+async fn test_dex_id() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_a = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "a").await;
+    let dex_b = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "b").await;
+
+    for dex_id in [dex_a, dex_b] {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "own_dex_id",
+                "args": "",
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        let own_dex_id: DexId = near_sdk::serde_json::from_slice(&response.0).unwrap();
+        assert_eq!(own_dex_id, dex_id);
+    }
+}
+
+#[tokio::test]
+async fn test_current_account_id() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "own_account_id",
+            "args": "",
+        }))
+        .await
+        .unwrap();
+    let response = result.json::<Base64VecU8>().unwrap();
+    // The engine, not the deployer of the dex
+    assert_eq!(response.0, dex_engine_contract.id().as_bytes());
+}
+
+#[tokio::test]
+async fn test_account_balance() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let near_balance = async || {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "own_near_balance",
+                "args": "",
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<u128>(&response.0).unwrap()
+    };
+    assert_eq!(near_balance().await, 0);
+
+    for account_or_dex in [
+        AccountOrDexId::Account(deployer.id().clone()),
+        AccountOrDexId::Dex(dex_id.clone()),
+    ] {
+        let result = deployer
+            .call(dex_engine_contract.id(), "register_assets")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "asset_ids": [AssetId::Near],
+                "for": account_or_dex,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    let result = deployer
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(NearToken::from_near(1))
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_asset")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "to": AccountOrDexId::Dex(dex_id.clone()),
+            "asset_id": AssetId::Near,
+            "amount": U128(1000),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    // The balance of the dex, not of the engine account
+    assert_eq!(near_balance().await, 1000);
+}
+
+#[tokio::test]
+async fn test_signer_account() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        user1_key,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "own_signer",
+            "args": "",
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let response = result.json::<Base64VecU8>().unwrap();
+    let (signer_id, signer_pk) =
+        near_sdk::borsh::from_slice::<(Vec<u8>, Vec<u8>)>(&response.0).unwrap();
+    assert_eq!(signer_id, user1.id().as_bytes());
+    assert_eq!(
+        signer_pk,
+        near_sdk::borsh::to_vec(&user1_key.public_key()).unwrap()
+    );
+
+    // Views have no signer
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "own_signer",
+            "args": "",
+        }))
+        .await;
+    assert!(
+        format!("{:?}", result.unwrap_err())
+            .contains("signer_account_id is not allowed in view functions")
+    );
+}
+
+#[tokio::test]
+async fn test_value_return_only_once() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "return_twice",
+            "args": "",
+        }))
+        .await;
+    let err = result.unwrap_err();
+    assert!(format!("{err:?}").contains("value_return can only be called once"));
+}
+
+#[tokio::test]
+async fn test_logs_are_prefixed_with_dex_id() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_a = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "a").await;
+    let dex_b = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "b").await;
+
+    let operations = [dex_a.clone(), dex_b.clone()].map(|dex_id| Operation::DexCall {
+        dex_id,
+        method: "log_hello".to_string(),
+        args: Base64VecU8(vec![]),
+        attached_assets: HashMap::new(),
+    });
+    let result = deployer
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let logs = result.logs();
+    assert!(logs.contains(&format!("[{dex_a}] hello").as_str()));
+    assert!(logs.contains(&format!("[{dex_b}] hello").as_str()));
+}
+
+#[tokio::test]
+async fn test_log_invalid_utf8_is_lossy() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "log_invalid_utf8",
+            "args": "",
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert!(
+        result
+            .logs()
+            .contains(&format!("[{dex_id}] invalid \u{FFFD} utf-8").as_str())
+    );
+}
+
+#[tokio::test]
+async fn test_log_utf16() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "log_utf16_messages",
+            "args": "",
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let logs = result.logs();
+    assert!(logs.contains(&format!("[{dex_id}] sized ✓").as_str()));
+    assert!(logs.contains(&format!("[{dex_id}] terminated ✓").as_str()));
+    assert!(logs.contains(&format!("[{dex_id}] lone \u{FFFD}").as_str()));
+    let dex_events = events(&result, "dex_event");
+    assert_eq!(dex_events.len(), 1);
+    assert_eq!(dex_events[0]["event"], json!({ "utf16": true }));
+}
+
+#[tokio::test]
+async fn test_storage_is_isolated_between_dexes() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    // ids and keys that would collide if dex id and key were
+    // naively concatenated
+    let dex_a = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "a").await;
+    let dex_ab = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "ab").await;
+
+    let write_kv = async |dex_id: &DexId, key: &[u8], value: &[u8]| {
+        let result = deployer
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "write_kv",
+                "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&(key, value)).unwrap()),
+                "attached_assets": {},
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    };
+    let read_kv = async |dex_id: &DexId, key: &[u8]| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "read_kv",
+                "args": BASE64_STANDARD.encode(key),
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0).unwrap()
+    };
+
+    write_kv(&dex_a, b"bkey", b"a").await;
+    write_kv(&dex_a, b"/ab/key", b"a").await;
+    assert_eq!(read_kv(&dex_ab, b"key").await, None);
+    assert_eq!(read_kv(&dex_ab, b"bkey").await, None);
+    assert_eq!(read_kv(&dex_ab, b"/key").await, None);
+
+    write_kv(&dex_ab, b"key", b"ab").await;
+    assert_eq!(read_kv(&dex_a, b"bkey").await, Some(b"a".to_vec()));
+    assert_eq!(read_kv(&dex_a, b"/ab/key").await, Some(b"a".to_vec()));
+    assert_eq!(read_kv(&dex_a, b"key").await, None);
+    assert_eq!(read_kv(&dex_ab, b"key").await, Some(b"ab".to_vec()));
+}
+
+#[tokio::test]
+async fn test_same_id_different_deployers() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_a = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "shared").await;
+    let dex_b = deploy_sandbox_test_dex(&dex_engine_contract, &user1, "shared").await;
+    assert_eq!(dex_a.id, dex_b.id);
+    assert_ne!(dex_a, dex_b);
+
+    let dex_call = async |account: &Account, dex_id: &DexId, method: &str, args: Vec<u8>| {
+        account
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": method,
+                "args": BASE64_STANDARD.encode(args),
+                "attached_assets": {},
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let read_kv = async |dex_id: &DexId, key: &[u8]| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "read_kv",
+                "args": BASE64_STANDARD.encode(key),
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0).unwrap()
+    };
+
+    // Storage
+    for (account, dex_id, value) in [(&deployer, &dex_a, b"a"), (&user1, &dex_b, b"b")] {
+        let args = near_sdk::borsh::to_vec(&(b"key", value)).unwrap();
+        assert_success(&dex_call(account, dex_id, "write_kv", args).await).unwrap();
+    }
+    assert_eq!(read_kv(&dex_a, b"key").await, Some(b"a".to_vec()));
+    assert_eq!(read_kv(&dex_b, b"key").await, Some(b"b".to_vec()));
+
+    // Balances
+    for (account, dex_id, liquidity) in [(&deployer, &dex_a, 1000), (&user1, &dex_b, 500)] {
+        engine_ft_deposit(
+            &dex_engine_contract,
+            &ft1,
+            &deployer,
+            account,
+            U128(liquidity),
+        )
+        .await;
+        for r#for in [
+            AccountOrDexId::Account(account.id().clone()),
+            AccountOrDexId::Dex(dex_id.clone()),
+        ] {
+            let result = account
+                .call(dex_engine_contract.id(), "register_assets")
+                .max_gas()
+                .deposit(NearToken::from_yoctonear(1))
+                .args_json(json!({
+                    "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+                    "for": r#for,
+                }))
+                .transact()
+                .await
+                .unwrap();
+            assert_success(&result).unwrap();
+        }
+        let result = account
+            .call(dex_engine_contract.id(), "transfer_asset")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "to": AccountOrDexId::Dex(dex_id.clone()),
+                "asset_id": AssetId::Nep141(ft1.id().clone()),
+                "amount": U128(liquidity),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let result = account
+            .call(dex_engine_contract.id(), "deposit_near")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(100))
+            .args_json(json!({}))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let result = account
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": "",
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(100)),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+    for (dex_id, ft_balance) in [(&dex_a, 900), (&dex_b, 400)] {
+        assert_inner_asset_balance(
+            &dex_engine_contract,
+            AccountOrDexId::Dex(dex_id.clone()),
+            AssetId::Near,
+            Some(U128(100)),
+        )
+        .await
+        .unwrap();
+        assert_inner_asset_balance(
+            &dex_engine_contract,
+            AccountOrDexId::Dex(dex_id.clone()),
+            AssetId::Nep141(ft1.id().clone()),
+            Some(U128(ft_balance)),
+        )
+        .await
+        .unwrap();
+    }
+
+    // Code: replacing the code of one doesn't affect the other
+    let result = deployer
+        .call(dex_engine_contract.id(), "upgrade_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_a,
+            "code_base64": BASE64_STANDARD.encode(&wasms.minimal_dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let args = near_sdk::borsh::to_vec(&(b"key", b"c")).unwrap();
+    assert!(
+        dex_call(&deployer, &dex_a, "write_kv", args.clone())
+            .await
+            .is_failure()
+    );
+    assert_success(&dex_call(&user1, &dex_b, "write_kv", args).await).unwrap();
+    assert_eq!(read_kv(&dex_b, b"key").await, Some(b"c".to_vec()));
+}
+
+#[tokio::test]
+async fn test_storage_write_returns_latest_value() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let write_kv_twice = async |key: &[u8], first: &[u8], second: &[u8]| {
+        let result = deployer
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "write_kv_twice",
+                "args": BASE64_STANDARD.encode(
+                    near_sdk::borsh::to_vec(&(key, first, second)).unwrap()
+                ),
+                "attached_assets": {},
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<(Option<Vec<u8>>, Option<Vec<u8>>)>(&response.0).unwrap()
+    };
+
+    // The second write sees the first one, not the value from
+    // before the call
+    assert_eq!(
+        write_kv_twice(b"key", b"1", b"2").await,
+        (None, Some(b"1".to_vec()))
+    );
+    assert_eq!(
+        write_kv_twice(b"key", b"3", b"4").await,
+        (Some(b"2".to_vec()), Some(b"3".to_vec()))
+    );
+
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "read_kv",
+            "args": BASE64_STANDARD.encode(b"key"),
+        }))
+        .await
+        .unwrap();
+    let response = result.json::<Base64VecU8>().unwrap();
+    assert_eq!(
+        near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0).unwrap(),
+        Some(b"4".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn test_dex_storage_usage() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let dex_storage_usage = async || {
+        dex_engine_contract
+            .view("dex_storage_usage")
+            .args_json(json!({
+                "dex_id": dex_id,
+            }))
+            .await
+            .unwrap()
+            .json::<u64>()
+            .unwrap()
+    };
+    let write_kv = async |key: &[u8], value: &[u8]| {
+        let result = deployer
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "write_kv",
+                "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&(key, value)).unwrap()),
+                "attached_assets": {},
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    };
+
+    // The code is charged to the dex
+    let initial = dex_storage_usage().await;
+    assert!(initial > get_compiled_wasms().await.sandbox_test_dex_wasm.len() as u64);
+
+    write_kv(b"a", &[0; 10]).await;
+    let after_a = dex_storage_usage().await;
+    let record = after_a - initial;
+    assert!(record > 10);
+
+    // Same key length and value length, same size
+    write_kv(b"b", &[0; 10]).await;
+    assert_eq!(dex_storage_usage().await, after_a + record);
+
+    // Only the value grows
+    write_kv(b"a", &[0; 30]).await;
+    assert_eq!(dex_storage_usage().await, after_a + record + 20);
+}
+
+#[tokio::test]
+async fn test_debug_kv() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let log_debug_pairs = async || {
+        let result = deployer
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "log_debug_pairs",
+                "args": "",
+                "attached_assets": {},
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+        result
+            .logs()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+
+    // Disabled by default
+    assert!(
+        !log_debug_pairs()
+            .await
+            .iter()
+            .any(|log| log.contains("\"pool\""))
+    );
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_dex_debug")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "debug": true,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let logs = log_debug_pairs().await;
+    assert!(logs.contains(&format!("[{dex_id}] {{\"pool\":\"0\"}}")));
+    assert!(logs.contains(&format!("[{dex_id}] {{\"reserve\":\"100\"}}")));
+}
+
+#[tokio::test]
+async fn test_redeploy_and_migrate() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "write_kv",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&(b"old", b"v")).unwrap()),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let read_kv = async |key: &[u8]| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "read_kv",
+                "args": BASE64_STANDARD.encode(key),
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0).unwrap()
+    };
+    let redeploy_and_migrate = async |account: &Account, dex_id: &DexId| {
+        account
+            .call(dex_engine_contract.id(), "redeploy_and_migrate")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "code_base64": BASE64_STANDARD.encode(&wasms.sandbox_test_dex_wasm),
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+
+    assert_success(&redeploy_and_migrate(&deployer, &dex_id).await).unwrap();
+    assert_eq!(read_kv(b"old").await, None);
+    assert_eq!(read_kv(b"new").await, Some(b"v".to_vec()));
+
+    // A failed migration reverts the upgrade
+    let result = redeploy_and_migrate(&deployer, &dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Nothing to migrate"));
+    assert_eq!(read_kv(b"new").await, Some(b"v".to_vec()));
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "migrate",
+            "args": "",
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(
+        format!("{:?}", result.failures())
+            .contains("Method name 'migrate' is reserved for migrations")
+    );
+
+    let not_deployed = DexId {
+        deployer: deployer.id().clone(),
+        id: "not-deployed".to_string(),
+    };
+    let result = redeploy_and_migrate(&deployer, &not_deployed).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("is not deployed yet"));
+
+    // After a transfer, only the new owner can migrate the storage
+    let result = deployer
+        .call(dex_engine_contract.id(), "transfer_dex_ownership")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "new_owner": user1.id(),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = redeploy_and_migrate(&deployer, &dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Only the owner of dex"));
+    let result = redeploy_and_migrate(&user1, &dex_id).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Nothing to migrate"));
+    assert_eq!(read_kv(b"new").await, Some(b"v".to_vec()));
+}
+
+#[tokio::test]
+async fn test_max_pools_per_dex() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = dex_engine_contract
+        .call("set_max_pools_per_dex")
+        .max_gas()
+        .args_json(json!({
+            "max_pools": 2,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let max_pools = dex_engine_contract
+        .view("max_pools_per_dex")
+        .await
+        .unwrap()
+        .json::<Option<u32>>()
+        .unwrap();
+    assert_eq!(max_pools, Some(2));
+
+    let create_pool = async || {
+        deployer
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "create_pool",
+                "args": "",
+                "attached_assets": {},
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let pool_count = async || {
+        dex_engine_contract
+            .view("dex_pool_count")
+            .args_json(json!({
+                "dex_id": dex_id,
+            }))
+            .await
+            .unwrap()
+            .json::<u32>()
+            .unwrap()
+    };
+
+    assert_eq!(pool_count().await, 0);
+    for expected_count in 1..=2 {
+        assert_success(&create_pool().await).unwrap();
+        assert_eq!(pool_count().await, expected_count);
+    }
+
+    let result = create_pool().await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("reached the limit of 2 pools"));
+    assert_eq!(pool_count().await, 2);
+
+    let result = dex_engine_contract
+        .call("set_max_pools_per_dex")
+        .max_gas()
+        .args_json(json!({
+            "max_pools": null,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    assert_success(&create_pool().await).unwrap();
+    assert_eq!(pool_count().await, 3);
+}
+
+#[tokio::test]
+async fn test_resource_limits() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let limits = json!({
+        "fuel": 1_000_000,
+        "memory_bytes": 4 << 20,
+        "register_bytes": 1024,
+        "storage_write_bytes": 512,
+        "max_recursion_depth": 64,
+    });
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_resource_limits")
+        .max_gas()
+        .args_json(json!({
+            "limits": limits,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    let result = dex_engine_contract
+        .call("set_resource_limits")
+        .max_gas()
+        .args_json(json!({
+            "limits": limits,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = dex_engine_contract
+        .view("resource_limits")
+        .await
+        .unwrap()
+        .json::<near_sdk::serde_json::Value>()
+        .unwrap();
+    assert_eq!(result, limits);
+
+    let dex_view_error = async |method: &str, args: Vec<u8>| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": method,
+                "args": BASE64_STANDARD.encode(args),
+            }))
+            .await;
+        format!("{:?}", result.unwrap_err())
+    };
+
+    let err = dex_view_error(
+        "work_until_fuel_low",
+        near_sdk::borsh::to_vec(&0u64).unwrap(),
+    )
+    .await;
+    assert!(err.contains(&format!(
+        "[{dex_id}] Dex ran out of fuel, the limit is 1000000"
+    )));
+
+    let err = dex_view_error("grow_memory", near_sdk::borsh::to_vec(&64u32).unwrap()).await;
+    assert!(err.contains(&format!(
+        "[{dex_id}] Dex exceeded the memory limit of 4194304 bytes"
+    )));
+
+    let err = dex_view_error("fill_register", near_sdk::borsh::to_vec(&2048u64).unwrap()).await;
+    assert!(err.contains(&format!(
+        "[{dex_id}] Dex exceeded the register limit of 1024 bytes"
+    )));
+
+    let err = dex_view_error("recurse", near_sdk::borsh::to_vec(&1000u32).unwrap()).await;
+    assert!(err.contains(&format!(
+        "[{dex_id}] Dex stack overflow, the recursion limit is 64"
+    )));
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "recurse",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&32u32).unwrap()),
+        }))
+        .await
+        .unwrap();
+    let response = result.json::<Base64VecU8>().unwrap();
+    assert_eq!(near_sdk::borsh::from_slice::<u32>(&response.0).unwrap(), 32);
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "write_kv",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&(b"key", vec![0u8; 600])).unwrap()),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains(&format!(
+        "[{dex_id}] Dex exceeded the storage write limit of 512 bytes"
+    )));
+
+    // Within the limits
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "write_kv",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&(b"key", vec![0u8; 100])).unwrap()),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+}
+
+#[tokio::test]
+async fn test_soft_fail_keeps_dex_storage() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let swap = async || {
+        user1
+            .call(dex_engine_contract.id(), "swap_simple")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "message": BASE64_STANDARD.encode(b"soft_fail"),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(dex_engine_contract.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(100)),
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+    let failed_attempts = async || {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "read_kv",
+                "args": BASE64_STANDARD.encode(b"failed_attempts"),
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0)
+            .unwrap()
+            .map(|value| near_sdk::borsh::from_slice::<u32>(&value).unwrap())
+    };
+
+    // Without the opt-in, a soft fail is a regular failure
+    let result = swap().await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Dex soft-failed: No liquidity"));
+    assert_eq!(failed_attempts().await, None);
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_dex_allow_soft_fail")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "allow": true,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for attempt in 1..=2 {
+        let result = swap().await;
+        assert_success(&result).unwrap();
+        assert!(
+            result
+                .logs()
+                .contains(&format!("[{dex_id}] Swap soft-failed: No liquidity").as_str())
+        );
+        assert_eq!(result.json::<(U128, U128)>().unwrap(), (U128(0), U128(0)));
+        assert_eq!(failed_attempts().await, Some(attempt));
+    }
+
+    // A batch can't keep the changes of a failed step
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": [Operation::SwapSimple {
+                dex_id: dex_id.clone(),
+                message: Base64VecU8(b"soft_fail".to_vec()),
+                asset_in: AssetId::Near,
+                asset_out: AssetId::Nep141(dex_engine_contract.id().clone()),
+                amount: SwapOperationAmount::Amount(SwapRequestAmount::ExactIn(U128(100))),
+                min_amount_out: None,
+                max_amount_in: None,
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Swap soft-failed: No liquidity"));
+    assert_eq!(failed_attempts().await, Some(2));
+}
+
+#[tokio::test]
+async fn test_fuel_reserve_allows_partial_fill() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let fill_orders = async |amount: u64| {
+        dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "fill_orders",
+                "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&amount).unwrap()),
+            }))
+            .await
+            .map(|result| {
+                let response = result.json::<Base64VecU8>().unwrap();
+                near_sdk::borsh::from_slice::<u64>(&response.0).unwrap()
+            })
+    };
+
+    assert_eq!(fill_orders(10).await.unwrap(), 10);
+    // Without a reserve, the dex has no fuel left to return
+    // anything once `fuel_remaining` reports zero
+    let err = fill_orders(u64::MAX).await.unwrap_err();
+    assert!(format!("{err:?}").contains("Dex ran out of fuel"));
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "set_dex_fuel_reserve")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id,
+            "fuel_reserve": 100_000,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let filled = fill_orders(u64::MAX).await.unwrap();
+    assert!(filled > 0);
+    assert!(filled < u64::MAX);
+    assert_eq!(fill_orders(10).await.unwrap(), 10);
+}
+
+#[tokio::test]
+async fn test_random_seed_override() {
+    let TestContext {
+        sandbox,
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+
+    // Production builds don't have the hook
+    let result = dex_engine_contract
+        .call("set_random_seed_override")
+        .max_gas()
+        .args_json(json!({
+            "seed": BASE64_STANDARD.encode([7u8; 32]),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+
+    let testing_engine = sandbox
+        .dev_deploy(&wasms.contract_testing_wasm)
+        .await
+        .unwrap();
+    let dex_id = deploy_sandbox_test_dex(&testing_engine, &deployer, "sandbox-test").await;
+    let own_random_seed = async || {
+        let result = testing_engine
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "own_random_seed",
+                "args": "",
+            }))
+            .await
+            .unwrap();
+        result.json::<Base64VecU8>().unwrap().0
+    };
+    let set_random_seed_override = async |account: &Account, seed: &[u8]| {
+        account
+            .call(testing_engine.id(), "set_random_seed_override")
+            .max_gas()
+            .args_json(json!({
+                "seed": BASE64_STANDARD.encode(seed),
+            }))
+            .transact()
+            .await
+            .unwrap()
+    };
+
+    assert_eq!(own_random_seed().await.len(), 32);
+
+    let result = set_random_seed_override(&deployer, &[7; 32]).await;
+    assert!(result.is_failure());
+    let result = set_random_seed_override(testing_engine.as_account(), &[7; 31]).await;
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Random seed must be 32 bytes, got 31"));
+
+    let result = set_random_seed_override(testing_engine.as_account(), &[7; 32]).await;
+    assert_success(&result).unwrap();
+    assert_eq!(own_random_seed().await, vec![7; 32]);
+    assert_eq!(own_random_seed().await, vec![7; 32]);
+}
+
+#[tokio::test]
+async fn test_trace_swap() {
+    let TestContext {
+        sandbox,
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let request = json!({
+        "message": BASE64_STANDARD.encode(b"record_assets"),
+        "asset_in": AssetId::Near,
+        "asset_out": AssetId::Nep141(ft1.id().clone()),
+        "amount": SwapRequestAmount::ExactIn(U128(100)),
+    });
+
+    // Production builds don't have the hook
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+    let result = dex_engine_contract
+        .view("trace_swap")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "request": request,
+        }))
+        .await;
+    assert!(result.is_err());
+
+    let testing_engine = sandbox
+        .dev_deploy(&wasms.contract_testing_wasm)
+        .await
+        .unwrap();
+    let dex_id = deploy_sandbox_test_dex(&testing_engine, &deployer, "sandbox-test").await;
+    let trace = testing_engine
+        .view("trace_swap")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "request": request,
+        }))
+        .await
+        .unwrap()
+        .json::<near_sdk::serde_json::Value>()
+        .unwrap();
+
+    let assets =
+        near_sdk::borsh::to_vec(&(AssetId::Near, AssetId::Nep141(ft1.id().clone()))).unwrap();
+    assert_eq!(
+        trace["registers"]["0"],
+        json!(BASE64_STANDARD.encode(&assets))
+    );
+    assert_eq!(
+        trace["storage_writes"],
+        json!([[
+            BASE64_STANDARD.encode(b"last_assets"),
+            BASE64_STANDARD.encode(&assets)
+        ]])
+    );
+    assert_eq!(trace["logs"], json!(["Recorded assets"]));
+    assert_eq!(trace["response"]["amount_out"], json!("100"));
+    assert_eq!(trace["error"], json!(null));
+
+    // Nothing is persisted
+    let result = testing_engine
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "read_kv",
+            "args": BASE64_STANDARD.encode(b"last_assets"),
+        }))
+        .await
+        .unwrap();
+    let response = result.json::<Base64VecU8>().unwrap();
+    assert_eq!(
+        near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0).unwrap(),
+        None
+    );
+
+    let trace = testing_engine
+        .view("trace_swap")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "request": {
+                "message": BASE64_STANDARD.encode(b"write_then_panic"),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(100)),
+            },
+        }))
+        .await
+        .unwrap()
+        .json::<near_sdk::serde_json::Value>()
+        .unwrap();
+    assert_eq!(
+        trace["storage_writes"],
+        json!([[
+            BASE64_STANDARD.encode(b"written"),
+            BASE64_STANDARD.encode(b"value")
+        ]])
+    );
+    assert_eq!(trace["response"], json!(null));
+    assert!(
+        trace["error"]
+            .as_str()
+            .unwrap()
+            .contains("Panicked after writing")
+    );
+}
+
+#[tokio::test]
+async fn test_dex_config() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_a = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "a").await;
+    let dex_b = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "b").await;
+
+    let upgrade = async |dex_id: &DexId, config: Option<&[u8]>| {
+        let result = deployer
+            .call(dex_engine_contract.id(), "upgrade_dex_code")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "code_base64": BASE64_STANDARD.encode(&wasms.sandbox_test_dex_wasm),
+                "config": config.map(|config| BASE64_STANDARD.encode(config)),
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    };
+    let own_config = async |dex_id: &DexId| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "own_config",
+                "args": "",
+            }))
+            .await
+            .unwrap();
+        result.json::<Base64VecU8>().unwrap().0
+    };
+    let dex_config = async |dex_id: &DexId| {
+        dex_engine_contract
+            .view("dex_config")
+            .args_json(json!({
+                "dex_id": dex_id,
+            }))
+            .await
+            .unwrap()
+            .json::<Base64VecU8>()
+            .unwrap()
+            .0
+    };
+
+    assert_eq!(own_config(&dex_a).await, Vec::<u8>::new());
+
+    upgrade(&dex_a, Some(b"fee=30")).await;
+    upgrade(&dex_b, Some(b"fee=5")).await;
+    assert_eq!(own_config(&dex_a).await, b"fee=30");
+    assert_eq!(own_config(&dex_b).await, b"fee=5");
+    assert_eq!(dex_config(&dex_a).await, b"fee=30");
+
+    // Upgrading the code without a config keeps the current one
+    upgrade(&dex_a, None).await;
+    assert_eq!(own_config(&dex_a).await, b"fee=30");
+
+    upgrade(&dex_a, Some(b"")).await;
+    assert_eq!(own_config(&dex_a).await, Vec::<u8>::new());
+    assert_eq!(own_config(&dex_b).await, b"fee=5");
+}
+
+#[tokio::test]
+async fn test_storage_rolled_back_on_panic() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        user1,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let request = json!({
+        "message": BASE64_STANDARD.encode(b"write_then_panic"),
+        "asset_in": AssetId::Near,
+        "asset_out": AssetId::Nep141(dex_engine_contract.id().clone()),
+        "amount": SwapRequestAmount::ExactIn(U128(100)),
+    });
+    let written = async || {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "read_kv",
+                "args": BASE64_STANDARD.encode(b"written"),
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<Option<Vec<u8>>>(&response.0).unwrap()
+    };
+
+    let mut args = request.clone();
+    args["dex_id"] = json!(dex_id);
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_simple")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(args)
+        .transact()
+        .await
+        .unwrap();
+    assert!(result.is_failure());
+    assert!(format!("{:?}", result.failures()).contains("Dex panicked: Panicked after writing"));
+    assert_eq!(written().await, None);
+
+    // swap_many doesn't fail the transaction, but the writes of
+    // the failed swap are still thrown away
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_many")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "swaps": [{
+                "dex_id": dex_id,
+                "request": request,
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let results = result.json::<Vec<Result<(U128, U128), String>>>().unwrap();
+    assert!(
+        results[0]
+            .as_ref()
+            .is_err_and(|err| err.contains("Dex panicked: Panicked after writing"))
+    );
+    assert_eq!(written().await, None);
+
+    // Checking storage_usage in the middle of a swap doesn't save
+    // the writes of the swap before it fails
+    let mut request = request;
+    request["message"] = json!(BASE64_STANDARD.encode(b"write_then_mismatch"));
+    let result = user1
+        .call(dex_engine_contract.id(), "swap_many")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "swaps": [{
+                "dex_id": dex_id,
+                "request": request,
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let results = result.json::<Vec<Result<(U128, U128), String>>>().unwrap();
+    assert!(
+        results[0]
+            .as_ref()
+            .is_err_and(|err| err.contains("Amount in does not match"))
+    );
+    assert_eq!(written().await, None);
+}
+
+#[tokio::test]
+async fn test_abort() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "abort_like_assemblyscript",
+            "args": "",
+        }))
+        .await;
+    assert!(
+        format!("{:?}", result.unwrap_err())
+            .contains("Dex aborted: Assertion failed at assembly/index.ts:3:5")
+    );
+
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "abort_with_null_message",
+            "args": "",
+        }))
+        .await;
+    assert!(format!("{:?}", result.unwrap_err()).contains("Dex aborted: null at null:0:0"));
+}
+
+#[tokio::test]
+async fn test_pool_reserves() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+
+    let set_pool_reserves = async |pool_id: u64, reserves: &[(AssetId, U128)]| {
+        let result = deployer
+            .call(dex_engine_contract.id(), "dex_call")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "set_pool_reserves",
+                "args": BASE64_STANDARD.encode(
+                    near_sdk::borsh::to_vec(&(pool_id, reserves)).unwrap()
+                ),
+                "attached_assets": {},
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    };
+    let pool_reserves = async |pool_id: u64| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "pool_reserves",
+                "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&pool_id).unwrap()),
+            }))
+            .await
+            .unwrap();
+        let response = result.json::<Base64VecU8>().unwrap();
+        near_sdk::borsh::from_slice::<Option<Vec<(AssetId, U128)>>>(&response.0).unwrap()
+    };
+    let dex_reserves = async |pool_id: u64| {
+        dex_engine_contract
+            .view("dex_reserves")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "pool_id": pool_id,
+            }))
+            .await
+            .unwrap()
+            .json::<Option<Vec<(AssetId, U128)>>>()
+            .unwrap()
+    };
+
+    assert_eq!(pool_reserves(0).await, None);
+    assert_eq!(dex_reserves(0).await, None);
+
+    let reserves = vec![
+        (AssetId::Near, U128(1_000)),
+        (AssetId::Nep141("wrap.near".parse().unwrap()), U128(2_000)),
+    ];
+    set_pool_reserves(0, &reserves).await;
+    set_pool_reserves(1, &[(AssetId::Near, U128(5))]).await;
+    assert_eq!(pool_reserves(0).await, Some(reserves.clone()));
+    assert_eq!(dex_reserves(0).await, Some(reserves));
+    assert_eq!(pool_reserves(1).await, Some(vec![(AssetId::Near, U128(5))]));
+
+    set_pool_reserves(0, &[]).await;
+    assert_eq!(pool_reserves(0).await, Some(vec![]));
+    assert_eq!(dex_reserves(2).await, None);
+}
+
+#[tokio::test]
+async fn test_ed25519_verify() {
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+    let verify = async |signature: &[u8], message: &[u8], public_key: &[u8]| {
+        let result = dex_engine_contract
+            .view("dex_view")
+            .args_json(json!({
+                "dex_id": dex_id,
+                "method": "verify_ed25519",
+                "args": BASE64_STANDARD.encode(
+                    near_sdk::borsh::to_vec(&(signature, message, public_key)).unwrap()
+                ),
+            }))
+            .await
+            .unwrap();
+        near_sdk::borsh::from_slice::<u64>(&result.json::<Base64VecU8>().unwrap().0).unwrap()
+    };
+
+    let secret_key = near_crypto::SecretKey::from_seed(near_crypto::KeyType::ED25519, "signer");
+    let public_key = secret_key.public_key();
+    let message = b"swap 100 near for usdc";
+    let near_crypto::Signature::ED25519(signature) = secret_key.sign(message) else {
+        unreachable!()
+    };
+    let signature = signature.to_bytes();
+    assert_eq!(verify(&signature, message, public_key.key_data()).await, 1);
+
+    assert_eq!(
+        verify(&signature, b"swap 100 near for usdt", public_key.key_data()).await,
+        0
+    );
+    let mut tampered = signature;
+    tampered[0] ^= 1;
+    assert_eq!(verify(&tampered, message, public_key.key_data()).await, 0);
+    let other_key = near_crypto::SecretKey::from_seed(near_crypto::KeyType::ED25519, "other");
+    assert_eq!(
+        verify(&signature, message, other_key.public_key().key_data()).await,
+        0
+    );
+    // Wrong lengths are a failed verification, not an error
+    assert_eq!(
+        verify(&signature[..63], message, public_key.key_data()).await,
+        0
+    );
+    assert_eq!(
+        verify(&signature, message, &public_key.key_data()[..31]).await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_swap_many_resource_limits() {
+    let transfer_amount = 1000u128;
+    let swap_amount = 100u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+    fund_sandbox_test_swaps(
+        &dex_engine_contract,
+        &deployer,
+        &ft1,
+        &dex_id,
+        transfer_amount,
+    )
+    .await;
+
+    let result = dex_engine_contract
+        .call("set_resource_limits")
+        .max_gas()
+        .args_json(json!({
+            "limits": {
+                "fuel": DEX_FUEL_LIMIT,
+                "memory_bytes": 32 << 20,
+                "register_bytes": 1024,
+                "storage_write_bytes": 512,
+                "max_recursion_depth": 256,
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let result = dex_engine_contract
+        .call("set_max_pools_per_dex")
+        .max_gas()
+        .args_json(json!({
+            "max_pools": 1,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let swap = |message: &[u8]| {
+        json!({
+            "dex_id": dex_id,
+            "request": {
+                "message": BASE64_STANDARD.encode(message),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+            },
+        })
+    };
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_many")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "swaps": [
+                swap(b""),
+                swap(b"fill_register"),
+                swap(b"write_too_much"),
+                swap(b"create_pool"),
+                // One pool too many
+                swap(b"create_pool"),
+                swap(b""),
+            ],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    let results = result.json::<Vec<Result<(U128, U128), String>>>().unwrap();
+    assert_eq!(results.len(), 6);
+    assert_eq!(results[0], Ok((U128(swap_amount), U128(swap_amount))));
+    assert!(results[1].as_ref().is_err_and(|err| err.contains(&format!(
+        "[{dex_id}] Dex exceeded the register limit of 1024 bytes"
+    ))));
+    assert!(results[2].as_ref().is_err_and(|err| err.contains(&format!(
+        "[{dex_id}] Dex exceeded the storage write limit of 512 bytes"
+    ))));
+    assert_eq!(results[3], Ok((U128(swap_amount), U128(swap_amount))));
+    assert!(results[4].as_ref().is_err_and(|err| err.contains(&format!(
+        "[{dex_id}] Dex reached the limit of 1 pools"
+    ))));
+    assert_eq!(results[5], Ok((U128(swap_amount), U128(swap_amount))));
+
+    assert_inner_asset_balance(
+        &dex_engine_contract,
+        AccountOrDexId::Account(deployer.id().clone()),
+        AssetId::Nep141(ft1.id().clone()),
+        Some(U128(3 * swap_amount)),
+    )
+    .await
+    .unwrap();
+    let pool_count = dex_engine_contract
+        .view("dex_pool_count")
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .await
+        .unwrap()
+        .json::<u32>()
+        .unwrap();
+    assert_eq!(pool_count, 1);
+}
+
+#[tokio::test]
+async fn test_dex_events_of_failed_swaps() {
+    let swap_amount = 100u128;
+
+    let TestContext {
+        dex_engine_contract,
+        deployer,
+        ft1,
+        ..
+    } = setup_test_environment().await;
+    let dex_id = deploy_sandbox_test_dex(&dex_engine_contract, &deployer, "sandbox-test").await;
+    fund_sandbox_test_swaps(&dex_engine_contract, &deployer, &ft1, &dex_id, 1000).await;
+
+    let swap = |message: &[u8]| {
+        json!({
+            "dex_id": dex_id,
+            "request": {
+                "message": BASE64_STANDARD.encode(message),
+                "asset_in": AssetId::Near,
+                "asset_out": AssetId::Nep141(ft1.id().clone()),
+                "amount": SwapRequestAmount::ExactIn(U128(swap_amount)),
+            },
+        })
+    };
+    let result = deployer
+        .call(dex_engine_contract.id(), "swap_many")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "swaps": [swap(b"event_then_panic"), swap(b"event")],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+    // The event of the failed swap is not emitted, and its sequence
+    // number is given to the event of the next swap
+    let dex_events = events(&result, "dex_event");
+    assert_eq!(dex_events.len(), 1);
+    assert_eq!(dex_events[0]["event"], json!({ "pool": 0 }));
+    assert_eq!(dex_events[0]["sequence"], json!(0));
+    assert_eq!(swap_event_sequences(&result), [1]);
+    let results = result.json::<Vec<Result<(U128, U128), String>>>().unwrap();
+    assert!(results[0].is_err());
+    assert_eq!(results[1], Ok((U128(swap_amount), U128(swap_amount))));
+}
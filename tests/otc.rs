@@ -38,7 +38,7 @@ struct OtcTradeIntent {
     validity: OtcValidity,
 }
 
-#[derive(Default, PartialEq)]
+#[derive(Default, PartialEq, Clone)]
 #[near(serializers=[borsh, json])]
 struct OtcValidity {
     expiry: Option<OtcExpiryCondition>,
@@ -115,6 +115,26 @@ async fn assert_otc_inner_asset_balance(
     Ok(())
 }
 
+/// Check whether a nonce of an account is marked as used by the OTC dex.
+async fn is_otc_nonce_used(
+    dex_engine_contract: &Contract,
+    dex_id: &DexId,
+    account_id: &AccountId,
+    nonce: U128,
+) -> bool {
+    let result = dex_engine_contract
+        .view("dex_view")
+        .args_json(json!({
+            "dex_id": dex_id,
+            "method": "is_nonce_used",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&(nonce, account_id)).unwrap()),
+        }))
+        .await
+        .unwrap();
+
+    near_sdk::borsh::from_slice::<bool>(&result.json::<Base64VecU8>().unwrap().0).unwrap()
+}
+
 #[tokio::test]
 async fn test_otc_regular_flow() {
     let initial_near_deposit = NearToken::from_near(20);
@@ -2612,3 +2632,421 @@ async fn test_otc_nonces() {
         .unwrap();
     assert_success(&result).unwrap();
 }
+
+#[tokio::test]
+async fn test_otc_nonce_cleanup_within_fuel_limit() {
+    let initial_near_deposit = NearToken::from_near(20);
+    let storage_deposit_for_otc = NearToken::from_millinear(250);
+    let assets_deposit_to_otc_near_user1 = NearToken::from_near(10);
+    let assets_deposit_to_otc_ft_user2 = 500_000_000u128;
+    let trade_amount_near = NearToken::from_millinear(100);
+    let trade_amount_ft = 100_000_000u128;
+    let ft_initial_deposit = 1_000_000_000u128;
+
+    let TestContext {
+        sandbox,
+        dex_engine_contract,
+        ft1,
+        deployer,
+        user1,
+        user1_key,
+        user2,
+        user2_key,
+        ..
+    } = setup_test_environment().await;
+    let wasms = get_compiled_wasms().await;
+    let dex_wasm = &wasms.otc_dex_wasm;
+
+    let dex_id_string = "dex".to_string();
+    let dex_id = DexId {
+        deployer: deployer.id().clone(),
+        id: dex_id_string.clone(),
+    };
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "dex_storage_deposit")
+        .max_gas()
+        .deposit(engine_dex_storage_deposit())
+        .args_json(json!({
+            "dex_id": dex_id,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "deploy_dex_code")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "last_part_of_id": dex_id_string,
+            "code_base64": BASE64_STANDARD.encode(dex_wasm),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = deployer
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+            "for": AccountOrDexId::Dex(dex_id.clone()),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user2
+        .call(dex_engine_contract.id(), "storage_deposit")
+        .max_gas()
+        .deposit(engine_user_storage_deposit())
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user2
+        .call(dex_engine_contract.id(), "register_assets")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "asset_ids": [AssetId::Near, AssetId::Nep141(ft1.id().clone())],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(initial_near_deposit)
+        .args_json(json!({}))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    ft_storage_deposit(&ft1, &user2).await;
+
+    ft_storage_deposit_for(&ft1, &deployer, dex_engine_contract.id()).await;
+
+    let result = deployer
+        .call(ft1.id(), "ft_transfer")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": user2.id(),
+            "amount": U128(ft_initial_deposit),
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user2
+        .call(ft1.id(), "ft_transfer_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "receiver_id": dex_engine_contract.id(),
+            "amount": U128(ft_initial_deposit),
+            "msg": "",
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "storage_deposit",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
+            "attached_assets": {
+                "near": U128(storage_deposit_for_otc.as_yoctonear()),
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "deposit_assets",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&OtcDepositAssetsArgs).unwrap()),
+            "attached_assets": {
+                "near": U128(assets_deposit_to_otc_near_user1.as_yoctonear()),
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user1
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "set_authorized_key",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&OtcSetAuthorizedKeyArgs {
+                key: user1_key.public_key().to_string().parse().unwrap(),
+            }).unwrap()),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user2
+        .call(dex_engine_contract.id(), "deposit_near")
+        .max_gas()
+        .deposit(storage_deposit_for_otc)
+        .args_json(json!({
+            "operations": [Operation::DexCall {
+                dex_id: dex_id.clone(),
+                method: "storage_deposit".to_string(),
+                args: Base64VecU8(near_sdk::borsh::to_vec(&OtcStorageDepositArgs).unwrap()),
+                attached_assets: HashMap::from_iter([(AssetId::Near, U128(storage_deposit_for_otc.as_yoctonear()))]),
+            }],
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user2
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "deposit_assets",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&OtcDepositAssetsArgs).unwrap()),
+            "attached_assets": {
+                format!("nep141:{}", ft1.id()): U128(assets_deposit_to_otc_ft_user2),
+            },
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let result = user2
+        .call(dex_engine_contract.id(), "dex_call")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "dex_id": dex_id.clone(),
+            "method": "set_authorized_key",
+            "args": BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&OtcSetAuthorizedKeyArgs {
+                key: user2_key.public_key().to_string().parse().unwrap(),
+            }).unwrap()),
+            "attached_assets": {},
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    let sign = |key: &SecretKey, trade_intent: OtcTradeIntent| {
+        let serialized = near_sdk::borsh::to_vec(&trade_intent).unwrap();
+        let signature: Base64VecU8 =
+            Base64VecU8(match key.sign(&near_sdk::env::sha256_array(serialized)) {
+                Signature::ED25519(sig) => sig.to_bytes().to_vec(),
+                Signature::SECP256K1(sig) => <[u8; 65]>::from(sig).to_vec(),
+            });
+        OtcAuthorizedTradeIntent {
+            trade_intent,
+            authorization_method: OtcAuthorizationMethod::Signature(signature),
+        }
+    };
+    let near_to_ft = |user_id: &AccountId, validity: OtcValidity| OtcTradeIntent {
+        user_id: user_id.clone(),
+        asset_in: AssetId::Near,
+        asset_out: AssetId::Nep141(ft1.id().clone()),
+        amount_in: U128(trade_amount_near.as_yoctonear()),
+        amount_out: U128(trade_amount_ft),
+        validity,
+    };
+    let ft_to_near = |user_id: &AccountId, validity: OtcValidity| OtcTradeIntent {
+        user_id: user_id.clone(),
+        asset_in: AssetId::Nep141(ft1.id().clone()),
+        asset_out: AssetId::Near,
+        amount_in: U128(trade_amount_ft),
+        amount_out: U128(trade_amount_near.as_yoctonear()),
+        validity,
+    };
+
+    // Fill both expirable nonce maps of both users with as many nonces as
+    // a single intent cleans up
+    const NONCES_TO_REMOVE_AT_ONCE: u128 = 10;
+    let block = sandbox.view_block().await.unwrap();
+    let expiry_block_height = block.height() + 200;
+    let expiry_timestamp_millis = block.timestamp() / 1_000_000 + 60_000;
+    for i in 0..NONCES_TO_REMOVE_AT_ONCE {
+        let block_height_validity = OtcValidity {
+            expiry: Some(OtcExpiryCondition::BlockHeight(expiry_block_height)),
+            nonce: Some(U128(i)),
+            only_for_whitelisted_parties: None,
+        };
+        let timestamp_validity = OtcValidity {
+            expiry: Some(OtcExpiryCondition::Timestamp {
+                milliseconds: U64(expiry_timestamp_millis),
+            }),
+            nonce: Some(U128(NONCES_TO_REMOVE_AT_ONCE + i)),
+            only_for_whitelisted_parties: None,
+        };
+        let operations = vec![Operation::DexCall {
+            dex_id: dex_id.clone(),
+            method: "match".to_string(),
+            args: Base64VecU8(
+                near_sdk::borsh::to_vec(&OtcMatchArgs {
+                    authorized_trade_intents: vec![
+                        sign(
+                            &user1_key,
+                            near_to_ft(user1.id(), block_height_validity.clone()),
+                        ),
+                        sign(
+                            &user2_key,
+                            ft_to_near(user2.id(), timestamp_validity.clone()),
+                        ),
+                        sign(&user1_key, ft_to_near(user1.id(), timestamp_validity)),
+                        sign(&user2_key, near_to_ft(user2.id(), block_height_validity)),
+                    ],
+                    output_destination: OtcOutputDestination::InternalOtcBalance,
+                })
+                .unwrap(),
+            ),
+            attached_assets: HashMap::new(),
+        }];
+
+        let result = user1
+            .call(dex_engine_contract.id(), "execute_operations")
+            .max_gas()
+            .deposit(NearToken::from_yoctonear(1))
+            .args_json(json!({
+                "operations": operations,
+            }))
+            .transact()
+            .await
+            .unwrap();
+        assert_success(&result).unwrap();
+    }
+
+    sandbox.fast_forward(1000).await.unwrap();
+    let block = sandbox.view_block().await.unwrap();
+    assert!(block.height() > expiry_block_height);
+    assert!(block.timestamp() / 1_000_000 > expiry_timestamp_millis);
+
+    // Each intent removes the maximum number of expired nonces from both maps
+    let operations = vec![Operation::DexCall {
+        dex_id: dex_id.clone(),
+        method: "match".to_string(),
+        args: Base64VecU8(
+            near_sdk::borsh::to_vec(&OtcMatchArgs {
+                authorized_trade_intents: vec![
+                    sign(
+                        &user1_key,
+                        near_to_ft(
+                            user1.id(),
+                            OtcValidity {
+                                nonce: Some(U128(2 * NONCES_TO_REMOVE_AT_ONCE)),
+                                ..Default::default()
+                            },
+                        ),
+                    ),
+                    sign(
+                        &user2_key,
+                        ft_to_near(
+                            user2.id(),
+                            OtcValidity {
+                                nonce: Some(U128(2 * NONCES_TO_REMOVE_AT_ONCE)),
+                                ..Default::default()
+                            },
+                        ),
+                    ),
+                ],
+                output_destination: OtcOutputDestination::InternalOtcBalance,
+            })
+            .unwrap(),
+        ),
+        attached_assets: HashMap::new(),
+    }];
+
+    let result = user1
+        .call(dex_engine_contract.id(), "execute_operations")
+        .max_gas()
+        .deposit(NearToken::from_yoctonear(1))
+        .args_json(json!({
+            "operations": operations,
+        }))
+        .transact()
+        .await
+        .unwrap();
+    assert_success(&result).unwrap();
+
+    for user_id in [user1.id(), user2.id()] {
+        for nonce in 0..2 * NONCES_TO_REMOVE_AT_ONCE {
+            assert!(!is_otc_nonce_used(&dex_engine_contract, &dex_id, user_id, U128(nonce)).await);
+        }
+        assert!(
+            is_otc_nonce_used(
+                &dex_engine_contract,
+                &dex_id,
+                user_id,
+                U128(2 * NONCES_TO_REMOVE_AT_ONCE)
+            )
+            .await
+        );
+    }
+}
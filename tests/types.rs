@@ -0,0 +1,320 @@
+use intear_dex_types::{
+    AssetId, BPS_DENOMINATOR, FeeSplit, SwapRequest, SwapRequestAmount, SwapResponse,
+    price_impact_bps, split_fees,
+};
+use near_sdk::borsh;
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde_json::{self, json};
+
+#[test]
+fn test_swap_types_borsh_roundtrip() {
+    for amount in [
+        SwapRequestAmount::ExactIn(U128(123)),
+        SwapRequestAmount::ExactOut(U128(u128::MAX)),
+    ] {
+        let bytes = borsh::to_vec(&amount).unwrap();
+        let decoded: SwapRequestAmount = borsh::from_slice(&bytes).unwrap();
+        assert_eq!(decoded, amount);
+    }
+
+    let request = SwapRequest {
+        message: Base64VecU8(vec![1, 2, 3]),
+        asset_in: AssetId::Near,
+        asset_out: AssetId::Nep141("ft.near".parse().unwrap()),
+        amount: SwapRequestAmount::ExactIn(U128(1000)),
+    };
+    let bytes = borsh::to_vec(&request).unwrap();
+    let decoded: SwapRequest = borsh::from_slice(&bytes).unwrap();
+    assert_eq!(decoded.message.0, request.message.0);
+    assert_eq!(decoded.asset_in, request.asset_in);
+    assert_eq!(decoded.asset_out, request.asset_out);
+    assert_eq!(decoded.amount, request.amount);
+
+    let response = SwapResponse {
+        amount_in: U128(1000),
+        amount_out: U128(999),
+        refunds: vec![(AssetId::Near, U128(1))],
+    };
+    let bytes = borsh::to_vec(&response).unwrap();
+    let decoded: SwapResponse = borsh::from_slice(&bytes).unwrap();
+    assert_eq!(decoded.amount_in, response.amount_in);
+    assert_eq!(decoded.amount_out, response.amount_out);
+    assert_eq!(decoded.refunds, response.refunds);
+}
+
+#[test]
+fn test_swap_response_from_dex_bytes() {
+    // Dexes built before refunds were added return two u128s
+    let legacy = [1000u128.to_le_bytes(), 999u128.to_le_bytes()].concat();
+    let decoded = SwapResponse::from_dex_bytes(&legacy).unwrap();
+    assert_eq!(decoded.amount_in, U128(1000));
+    assert_eq!(decoded.amount_out, U128(999));
+    assert!(decoded.refunds.is_empty());
+
+    for refunds in [vec![], vec![(AssetId::Near, U128(1))]] {
+        let response = SwapResponse {
+            amount_in: U128(1000),
+            amount_out: U128(999),
+            refunds,
+        };
+        let bytes = borsh::to_vec(&response).unwrap();
+        let decoded = SwapResponse::from_dex_bytes(&bytes).unwrap();
+        assert_eq!(decoded.amount_in, response.amount_in);
+        assert_eq!(decoded.amount_out, response.amount_out);
+        assert_eq!(decoded.refunds, response.refunds);
+    }
+
+    assert!(SwapResponse::from_dex_bytes(&legacy[..31]).is_err());
+}
+
+#[test]
+fn test_swap_types_json() {
+    let request = SwapRequest {
+        message: Base64VecU8(vec![1, 2, 3]),
+        asset_in: AssetId::Near,
+        asset_out: AssetId::Nep141("ft.near".parse().unwrap()),
+        amount: SwapRequestAmount::ExactOut(U128(1000)),
+    };
+    assert_eq!(
+        serde_json::to_value(&request).unwrap(),
+        json!({
+            "message": "AQID",
+            "asset_in": "near",
+            "asset_out": "nep141:ft.near",
+            "amount": { "ExactOut": "1000" },
+        })
+    );
+
+    let response = SwapResponse {
+        amount_in: U128(1000),
+        amount_out: U128(999),
+        refunds: vec![(AssetId::Near, U128(1))],
+    };
+    let value = serde_json::to_value(&response).unwrap();
+    assert_eq!(
+        value,
+        json!({
+            "amount_in": "1000",
+            "amount_out": "999",
+            "refunds": [["near", "1"]],
+        })
+    );
+    let decoded: SwapResponse = serde_json::from_value(value).unwrap();
+    assert_eq!(decoded.amount_in, response.amount_in);
+    assert_eq!(decoded.amount_out, response.amount_out);
+    assert_eq!(decoded.refunds, response.refunds);
+}
+
+#[test]
+fn test_swap_request_validate() {
+    let valid = SwapRequest {
+        message: Base64VecU8(vec![]),
+        asset_in: AssetId::Near,
+        asset_out: AssetId::Nep141("ft.near".parse().unwrap()),
+        amount: SwapRequestAmount::ExactIn(U128(1)),
+    };
+    assert_eq!(valid.validate(), Ok(()));
+
+    let zero_in = SwapRequest {
+        amount: SwapRequestAmount::ExactIn(U128(0)),
+        ..valid.clone()
+    };
+    assert_eq!(
+        zero_in.validate(),
+        Err("Amount must be greater than 0".to_string())
+    );
+
+    let zero_out = SwapRequest {
+        amount: SwapRequestAmount::ExactOut(U128(0)),
+        ..valid.clone()
+    };
+    assert_eq!(
+        zero_out.validate(),
+        Err("Amount must be greater than 0".to_string())
+    );
+
+    let same_asset = SwapRequest {
+        asset_out: AssetId::Near,
+        ..valid
+    };
+    assert_eq!(
+        same_asset.validate(),
+        Err("Asset in and asset out must be different, got near for both".to_string())
+    );
+}
+
+#[test]
+fn test_asset_id_encoding_and_order() {
+    let assets = vec![
+        AssetId::Near,
+        AssetId::Nep141("a.near".parse().unwrap()),
+        AssetId::Nep141("bb.near".parse().unwrap()),
+        AssetId::Nep245("mt.near".parse().unwrap(), "1".to_string()),
+        AssetId::Nep171("nft.near".parse().unwrap(), "1".to_string()),
+    ];
+    let mut sorted = assets.clone();
+    sorted.reverse();
+    sorted.sort();
+    assert_eq!(sorted, assets);
+
+    for (variant, asset) in [0u8, 1, 1, 2, 3].into_iter().zip(&assets) {
+        let bytes = borsh::to_vec(asset).unwrap();
+        assert_eq!(bytes[0], variant);
+        let decoded: AssetId = borsh::from_slice(&bytes).unwrap();
+        assert_eq!(&decoded, asset);
+    }
+
+    assert_eq!(
+        borsh::to_vec(&AssetId::Nep141("a.near".parse().unwrap())).unwrap(),
+        [&[1, 6, 0, 0, 0][..], b"a.near"].concat()
+    );
+
+    // Strings are length-prefixed, so byte order differs from `Ord`
+    let longer = AssetId::Nep141("aa.near".parse().unwrap());
+    let shorter = AssetId::Nep141("b.near".parse().unwrap());
+    assert!(longer < shorter);
+    assert!(borsh::to_vec(&longer).unwrap() > borsh::to_vec(&shorter).unwrap());
+}
+
+#[test]
+fn test_asset_id_display() {
+    for (asset, string) in [
+        (AssetId::Near, "near"),
+        (
+            AssetId::Nep141("wrap.near".parse().unwrap()),
+            "nep141:wrap.near",
+        ),
+        (
+            AssetId::Nep245("mt.near".parse().unwrap(), "token:1".to_string()),
+            "nep245:mt.near:token:1",
+        ),
+        (
+            AssetId::Nep171("nft.near".parse().unwrap(), "1".to_string()),
+            "nep171:nft.near:1",
+        ),
+    ] {
+        assert_eq!(asset.to_string(), string);
+        assert_eq!(string.parse::<AssetId>().unwrap(), asset);
+        assert_eq!(serde_json::to_value(&asset).unwrap(), json!(string));
+    }
+
+    assert_eq!(AssetId::Near.token_contract(), None);
+    for asset in [
+        AssetId::Nep141("a.near".parse().unwrap()),
+        AssetId::Nep245("a.near".parse().unwrap(), "1".to_string()),
+        AssetId::Nep171("a.near".parse().unwrap(), "1".to_string()),
+    ] {
+        assert_eq!(asset.token_contract().map(|id| id.as_str()), Some("a.near"));
+    }
+}
+
+#[test]
+fn test_asset_id_from_str_errors() {
+    for (string, error) in [
+        ("", "Invalid asset id: "),
+        ("NEAR", "Invalid asset id: NEAR"),
+        ("nep999:a.near", "Invalid asset id: nep999:a.near"),
+        ("wrap.near", "Invalid asset id: wrap.near"),
+        ("nep245:mt.near", "Invalid asset id: nep245:mt.near"),
+        (
+            "nep171:nft.near:",
+            "Invalid asset id: nep171:nft.near:, token id is empty",
+        ),
+    ] {
+        assert_eq!(string.parse::<AssetId>(), Err(error.to_string()));
+    }
+    assert!(
+        "nep141:Invalid Account"
+            .parse::<AssetId>()
+            .is_err_and(|err| err.starts_with("Invalid account id Invalid Account: "))
+    );
+}
+
+#[test]
+fn test_split_fees() {
+    assert_eq!(
+        split_fees(U128(1_000_000), &[30, 5, 10]),
+        Ok(FeeSplit {
+            net: U128(995_500),
+            fees: vec![U128(3_000), U128(500), U128(1_000)],
+        })
+    );
+
+    // Rounded down, the dust stays in net
+    assert_eq!(
+        split_fees(U128(9_999), &[30, 5, 10]),
+        Ok(FeeSplit {
+            net: U128(9_957),
+            fees: vec![U128(29), U128(4), U128(9)],
+        })
+    );
+
+    // Amounts where `amount * bps` overflows u128
+    for gross in [u128::MAX, u128::MAX - 1, u128::MAX / 3, 1 << 120] {
+        for fees_bps in [
+            &[30u16, 5, 10][..],
+            &[BPS_DENOMINATOR],
+            &[3_333, 3_333, 3_334],
+            &[9_999, 1],
+            &[1; 7],
+            &[],
+        ] {
+            let split = split_fees(U128(gross), fees_bps).unwrap();
+            assert_eq!(split.fees.len(), fees_bps.len());
+            let total = split
+                .fees
+                .iter()
+                .try_fold(split.net.0, |total, fee| total.checked_add(fee.0))
+                .unwrap();
+            assert_eq!(total, gross);
+            for (fee, bps) in split.fees.iter().zip(fees_bps) {
+                let exact = gross as f64 * f64::from(*bps) / f64::from(BPS_DENOMINATOR);
+                assert!(fee.0 as f64 <= exact * (1.0 + f64::EPSILON));
+            }
+        }
+    }
+    assert_eq!(
+        split_fees(U128(u128::MAX), &[BPS_DENOMINATOR]).unwrap(),
+        FeeSplit {
+            net: U128(0),
+            fees: vec![U128(u128::MAX)],
+        }
+    );
+
+    assert_eq!(
+        split_fees(U128(1_000), &[9_000, 1_001]),
+        Err("Fees add up to 10001 bps, more than 10000".to_string())
+    );
+    assert!(split_fees(U128(1_000), &[u16::MAX, u16::MAX]).is_err());
+}
+
+#[test]
+fn test_price_impact_bps() {
+    let impact = |reserve_in, reserve_out, amount_in, amount_out| {
+        price_impact_bps(
+            U128(reserve_in),
+            U128(reserve_out),
+            U128(amount_in),
+            U128(amount_out),
+        )
+    };
+
+    // 100 into a 1000/1000 constant product pool gives 90
+    assert_eq!(impact(1000, 1000, 100, 90), Ok(1000));
+    assert_eq!(impact(1000, 2000, 100, 200), Ok(0));
+    assert_eq!(impact(1000, 2000, 100, 250), Ok(0));
+    assert_eq!(impact(1000, 1000, 100, 0), Ok(BPS_DENOMINATOR));
+    // Rounded up
+    assert_eq!(impact(3, 1, 1, 0), Ok(BPS_DENOMINATOR));
+    assert_eq!(impact(1_000_000, 1_000_000, 1_000_000, 999_999), Ok(1));
+    assert_eq!(
+        impact(u128::MAX, u128::MAX, u128::MAX, u128::MAX / 2 + 1),
+        Ok(5000)
+    );
+    assert_eq!(impact(u128::MAX, 3, u128::MAX / 3, 1), Ok(0));
+    assert_eq!(impact(u128::MAX, 1, u128::MAX, 0), Ok(BPS_DENOMINATOR));
+
+    assert!(impact(0, 1000, 100, 90).is_err());
+    assert!(impact(1000, 0, 100, 90).is_err());
+    assert!(impact(1000, 1000, 0, 90).is_err());
+}